@@ -0,0 +1,442 @@
+//! The `bar` command: continuously updated status for waybar custom modules,
+//! i3bar and polybar, read from the running instance or the devices directly.
+
+use log::warn;
+use serde_json::json;
+use std::error::Error;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::thread;
+
+use crate::config::{BarConfig, Config};
+use crate::controller::battery_percentage;
+use crate::history::unix_timestamp;
+use crate::ipc::{self, DeviceStatus, Request, Response};
+use crate::manager::{DeviceBackend, DeviceManager};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarOutput {
+    /// One JSON object per line, for `return-type: json` custom modules.
+    Waybar,
+    /// The i3bar protocol, an endless JSON array of status lines.
+    I3bar,
+    /// Plain text lines, for `tail = true` script modules.
+    Polybar,
+}
+
+impl FromStr for BarOutput {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "waybar" => Ok(BarOutput::Waybar),
+            "i3bar" | "i3status" => Ok(BarOutput::I3bar),
+            "polybar" => Ok(BarOutput::Polybar),
+            _ => Err(format!(
+                "Invalid output: {}, expected waybar, i3bar or polybar",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BarOptions {
+    pub output: BarOutput,
+    /// Only show this product id.
+    pub device: Option<u32>,
+    /// Replaces the `format` from the config.
+    pub format: Option<String>,
+}
+
+/// Prints a status line whenever it changes, until the bar closes stdout.
+pub fn run(options: &BarOptions) -> Result<(), Box<dyn Error>> {
+    let config = Config::load();
+    let format = options.format.as_deref().unwrap_or(&config.bar.format);
+    let mut manager = None;
+    let mut last_line = None;
+
+    let mut stdout = io::stdout().lock();
+    if options.output == BarOutput::I3bar {
+        writeln!(stdout, "{{\"version\":1}}\n[")?;
+    }
+
+    loop {
+        let mut devices = match ipc::send(&Request::Status) {
+            Ok(Some(Response::Status { devices })) => devices,
+            Ok(Some(Response::Error { message })) => return Err(message.into()),
+            Ok(Some(response)) => return Err(format!("Unexpected response: {:?}", response).into()),
+            // Nobody else holds the devices, so read them here
+            Ok(None) => read_devices(manager.get_or_insert_with(DeviceManager::new), &config),
+            // The instance still holds the devices, e.g. while it is busy or
+            // exiting, so keep the last line until it answers again
+            Err(err) => {
+                warn!(
+                    "Failed to get the status from the running instance: {}",
+                    err
+                );
+                thread::sleep(config.bar.interval);
+                continue;
+            }
+        };
+        if let Some(pid) = options.device {
+            devices.retain(|device| device.pid == pid);
+        }
+
+        let line = render(options.output, &devices, format, &config.bar);
+        if print_changed(&mut stdout, options.output, &mut last_line, line).is_err() {
+            return Ok(());
+        }
+
+        thread::sleep(config.bar.interval);
+    }
+}
+
+/// Prints the line unless it is the same as the last one.
+fn print_changed(
+    out: &mut impl Write,
+    output: BarOutput,
+    last_line: &mut Option<String>,
+    line: String,
+) -> io::Result<()> {
+    if last_line.as_ref() == Some(&line) {
+        return Ok(());
+    }
+
+    // The i3bar array needs a comma before every line but the first
+    let separator = match (output, &last_line) {
+        (BarOutput::I3bar, Some(_)) => ",",
+        _ => "",
+    };
+    writeln!(out, "{}{}", separator, line)?;
+    out.flush()?;
+    *last_line = Some(line);
+    Ok(())
+}
+
+/// Reads every connected device without recording history.
+fn read_devices(manager: &mut DeviceManager, config: &Config) -> Vec<DeviceStatus> {
+    manager.fetch_devices();
+    let mut ids: Vec<u32> = manager
+        .device_controllers
+        .lock()
+        .iter()
+        .map(|controller| controller.pid as u32)
+        .collect();
+    ids.sort_unstable();
+
+    let mut devices: Vec<DeviceStatus> = ids
+        .into_iter()
+        .map(|id| {
+            let name = manager
+                .get_device_name(id)
+                .map(|model| config.device_name(id, &model))
+                .unwrap_or_else(|| format!("0x{:04X}", id));
+            let level = manager.get_device_battery_raw(id).map(battery_percentage);
+
+            DeviceStatus {
+                pid: id,
+                name,
+                level,
+                is_charging: manager.is_device_charging(id).unwrap_or(false),
+                is_available: level.is_some(),
+                connection: manager.get_device_connection(id),
+                last_update: level.map(|_| unix_timestamp()),
+                time_to_empty: None,
+                time_to_full: None,
+            }
+        })
+        .collect();
+    devices.sort_by(|a, b| a.name.cmp(&b.name).then(a.pid.cmp(&b.pid)));
+    devices
+}
+
+fn render(output: BarOutput, devices: &[DeviceStatus], format: &str, config: &BarConfig) -> String {
+    let text = join(devices, format, &config.separator);
+    let classes = classes(devices, config);
+
+    match output {
+        BarOutput::Waybar => {
+            let tooltip = join(devices, &config.tooltip_format, "\n");
+            let percentage = lowest(devices).and_then(|device| device.level);
+            json!({
+                "text": text,
+                "tooltip": tooltip,
+                "class": classes,
+                "alt": classes.first().copied().unwrap_or("normal"),
+                "percentage": percentage.unwrap_or(0),
+            })
+            .to_string()
+        }
+        BarOutput::I3bar => {
+            let mut block = json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "full_text": text,
+            });
+            if classes.contains(&"critical") {
+                block["color"] = json!("#FF0000");
+                block["urgent"] = json!(!classes.contains(&"charging"));
+            } else if classes.contains(&"low") {
+                block["color"] = json!("#FFFF00");
+            }
+            json!([block]).to_string()
+        }
+        BarOutput::Polybar => text,
+    }
+}
+
+fn join(devices: &[DeviceStatus], format: &str, separator: &str) -> String {
+    devices
+        .iter()
+        .map(|device| expand(format, device))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Fills in the `{name}`, `{pid}`, `{level}`, `{charging}`, `{state}`,
+/// `{estimate}` and `{connection}` placeholders.
+fn expand(format: &str, device: &DeviceStatus) -> String {
+    let level = match device.level {
+        Some(level) if device.is_available => level.to_string(),
+        _ => "?".to_owned(),
+    };
+    let state = match device.level {
+        Some(_) if !device.is_available => "asleep",
+        Some(_) if device.is_charging => "charging",
+        Some(_) => "discharging",
+        None => "unknown",
+    };
+
+    format
+        .replace("{name}", &device.name)
+        .replace("{pid}", &format!("0x{:04X}", device.pid))
+        .replace("{level}", &level)
+        .replace("{charging}", if device.is_charging { "⚡" } else { "" })
+        .replace("{state}", state)
+        .replace(
+            "{estimate}",
+            &device
+                .estimate()
+                .map_or_else(String::new, |estimate| estimate.to_string()),
+        )
+        .replace(
+            "{connection}",
+            device
+                .connection
+                .map_or("", |connection| connection.as_str()),
+        )
+        .trim()
+        .to_owned()
+}
+
+/// The available device with the lowest battery, like the tray icon.
+fn lowest(devices: &[DeviceStatus]) -> Option<&DeviceStatus> {
+    devices
+        .iter()
+        .filter(|device| device.is_available && device.level.is_some())
+        .min_by_key(|device| device.level)
+}
+
+/// `critical` or `low` by the lowest level, plus `charging`, or
+/// `disconnected` without a reading.
+fn classes(devices: &[DeviceStatus], config: &BarConfig) -> Vec<&'static str> {
+    let Some(device) = lowest(devices) else {
+        return vec!["disconnected"];
+    };
+
+    let mut classes = Vec::new();
+    match device.level {
+        Some(level) if level <= config.critical => classes.push("critical"),
+        Some(level) if level <= config.low => classes.push("low"),
+        _ => (),
+    }
+    if device.is_charging {
+        classes.push("charging");
+    }
+    classes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::ConnectionType;
+    use serde_json::Value;
+
+    fn device(name: &str, level: Option<i32>, is_charging: bool) -> DeviceStatus {
+        DeviceStatus {
+            pid: 0x00B7,
+            name: name.to_owned(),
+            level,
+            is_charging,
+            is_available: level.is_some(),
+            connection: Some(ConnectionType::Wireless),
+            last_update: level.map(|_| 1_750_000_000),
+            time_to_empty: None,
+            time_to_full: None,
+        }
+    }
+
+    fn asleep(name: &str, level: i32) -> DeviceStatus {
+        DeviceStatus {
+            is_available: false,
+            ..device(name, Some(level), false)
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let mut mouse = device("Mouse", Some(42), true);
+        mouse.time_to_full = Some(90 * 60);
+        let format = "{name} {pid} {level}%{charging} {state} {estimate} {connection}";
+
+        let cases = [
+            (
+                mouse,
+                "Mouse 0x00B7 42%⚡ charging about 2 h to full wireless",
+            ),
+            (
+                device("Mouse", Some(42), false),
+                "Mouse 0x00B7 42% discharging  wireless",
+            ),
+            (asleep("Mouse", 42), "Mouse 0x00B7 ?% asleep  wireless"),
+            (
+                DeviceStatus {
+                    connection: None,
+                    ..device("Mouse", None, false)
+                },
+                "Mouse 0x00B7 ?% unknown",
+            ),
+        ];
+
+        for (device, expected) in cases {
+            assert_eq!(expand(format, &device), expected);
+        }
+        assert_eq!(
+            expand("{unknown} {level}", &device("Mouse", Some(5), false)),
+            "{unknown} 5"
+        );
+    }
+
+    #[test]
+    fn the_lowest_available_device_counts() {
+        let devices = [
+            device("Mouse", Some(60), false),
+            asleep("Keyboard", 10),
+            device("Headset", None, false),
+            device("Dock", Some(30), true),
+        ];
+
+        assert_eq!(
+            lowest(&devices).map(|device| device.name.as_str()),
+            Some("Dock")
+        );
+        assert!(lowest(&devices[1..3]).is_none());
+        assert!(lowest(&[]).is_none());
+    }
+
+    #[test]
+    fn classes_follow_the_lowest_level() {
+        let config = BarConfig::default();
+        let cases: [(&[DeviceStatus], &[&str]); 8] = [
+            (&[], &["disconnected"]),
+            (&[asleep("Mouse", 5)], &["disconnected"]),
+            (&[device("Mouse", Some(80), false)], &[]),
+            (&[device("Mouse", Some(41), false)], &[]),
+            (&[device("Mouse", Some(40), false)], &["low"]),
+            (&[device("Mouse", Some(15), false)], &["critical"]),
+            (
+                &[device("Mouse", Some(10), true)],
+                &["critical", "charging"],
+            ),
+            (
+                &[
+                    device("Mouse", Some(80), true),
+                    device("Keyboard", Some(30), false),
+                ],
+                &["low"],
+            ),
+        ];
+
+        for (devices, expected) in cases {
+            assert_eq!(classes(devices, &config), expected, "{:?}", devices);
+        }
+    }
+
+    #[test]
+    fn waybar_gets_a_json_object() {
+        let config = BarConfig::default();
+        let devices = [
+            device("Mouse", Some(12), true),
+            device("Keyboard", Some(70), false),
+        ];
+
+        let line = render(BarOutput::Waybar, &devices, &config.format, &config);
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "text": "12%⚡ 70%",
+                "tooltip": "Mouse: 12%, charging\nKeyboard: 70%, discharging",
+                "class": ["critical", "charging"],
+                "alt": "critical",
+                "percentage": 12,
+            })
+        );
+
+        let line = render(BarOutput::Waybar, &[], &config.format, &config);
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["alt"], "disconnected");
+        assert_eq!(value["percentage"], 0);
+    }
+
+    #[test]
+    fn i3bar_gets_colored_blocks() {
+        let config = BarConfig::default();
+        let block = |devices: &[DeviceStatus]| -> Value {
+            let line = render(BarOutput::I3bar, devices, "{level}", &config);
+            serde_json::from_str::<Value>(&line).unwrap()[0].clone()
+        };
+
+        let critical = block(&[device("Mouse", Some(10), false)]);
+        assert_eq!(critical["full_text"], "10");
+        assert_eq!(critical["color"], "#FF0000");
+        assert_eq!(critical["urgent"], true);
+        // Charging already takes care of it
+        assert_eq!(block(&[device("Mouse", Some(10), true)])["urgent"], false);
+
+        let low = block(&[device("Mouse", Some(30), false)]);
+        assert_eq!(low["color"], "#FFFF00");
+        assert!(low.get("urgent").is_none());
+
+        let normal = block(&[device("Mouse", Some(80), false)]);
+        assert!(normal.get("color").is_none());
+        assert_eq!(normal["name"], env!("CARGO_PKG_NAME"));
+    }
+
+    #[test]
+    fn polybar_gets_plain_text() {
+        let config = BarConfig::default();
+        let devices = [device("Mouse", Some(80), false), asleep("Keyboard", 50)];
+
+        assert_eq!(
+            render(BarOutput::Polybar, &devices, "{name} {level}", &config),
+            "Mouse 80 Keyboard ?"
+        );
+    }
+
+    #[test]
+    fn only_changed_lines_are_printed() {
+        let print = |output, lines: &[&str]| {
+            let mut out = Vec::new();
+            let mut last_line = None;
+            for line in lines {
+                print_changed(&mut out, output, &mut last_line, line.to_string()).unwrap();
+            }
+            String::from_utf8(out).unwrap()
+        };
+
+        let lines = ["[1]", "[1]", "[2]", "[1]"];
+        assert_eq!(print(BarOutput::I3bar, &lines), "[1]\n,[2]\n,[1]\n");
+        assert_eq!(print(BarOutput::Waybar, &lines), "[1]\n[2]\n[1]\n");
+    }
+}
@@ -0,0 +1,190 @@
+//! Typed events of the polling core. History, notifications and exporters
+//! subscribe when the monitor is created and handle every event in the
+//! polling thread, in the order they subscribed. Everything else, like the
+//! tray icons, gets its own bounded receiver.
+
+use log::warn;
+use parking_lot::Mutex;
+#[cfg(any(test, feature = "tray", target_os = "linux"))]
+use std::sync::mpsc::{self, Receiver};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::Arc;
+
+use crate::devices::ConnectionType;
+use crate::monitor::BatteryMonitor;
+use crate::notify::NotificationKind;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonitorEvent {
+    DeviceConnected {
+        pid: u32,
+        name: String,
+    },
+    DeviceRemoved {
+        pid: u32,
+        name: String,
+    },
+    /// A successful battery read.
+    ReadingUpdated {
+        pid: u32,
+        name: String,
+        timestamp: u64,
+        level: i32,
+        raw: u8,
+        is_charging: bool,
+        connection: ConnectionType,
+    },
+    /// Follows the reading that changed it.
+    ChargingChanged {
+        pid: u32,
+        name: String,
+        level: i32,
+        is_charging: bool,
+    },
+    /// A notification rule fired, e.g. `low:15`.
+    ThresholdCrossed {
+        pid: u32,
+        name: String,
+        level: i32,
+        is_charging: bool,
+        rule: String,
+        notification: NotificationKind,
+    },
+    /// A battery read failed. `was_available` is set if the previous one
+    /// succeeded, i.e. the device just fell asleep.
+    Error {
+        pid: u32,
+        name: String,
+        was_available: bool,
+    },
+    /// The monitor shut down, nothing follows.
+    Stopped,
+}
+
+impl MonitorEvent {
+    /// Whether the device state changed, as opposed to events that only
+    /// interpret a reading already sent.
+    pub fn changes_state(&self) -> bool {
+        !matches!(
+            self,
+            MonitorEvent::ChargingChanged { .. }
+                | MonitorEvent::ThresholdCrossed { .. }
+                | MonitorEvent::Stopped
+        )
+    }
+}
+
+/// How many events a receiver may fall behind before it's dropped.
+pub const RECEIVER_CAPACITY: usize = 256;
+
+/// Sends without blocking the polling thread. Returns `false` once the
+/// receiver is gone or fell too far behind, and the sender should be dropped.
+pub fn try_deliver<T>(sender: &SyncSender<T>, event: T) -> bool {
+    match sender.try_send(event) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!(
+                "Dropping an event receiver {} events behind",
+                RECEIVER_CAPACITY
+            );
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+pub trait Subscriber: Send + Sync {
+    /// Called in the polling thread, without any monitor lock held. The next
+    /// device read waits for every subscriber, so slow work like network I/O
+    /// belongs on the subscriber's own thread, as MQTT and hooks do it.
+    fn handle(&self, monitor: &BatteryMonitor, event: &MonitorEvent);
+}
+
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Arc<dyn Subscriber>>>,
+    receivers: Mutex<Vec<SyncSender<MonitorEvent>>>,
+}
+
+impl EventBus {
+    pub fn add(&self, subscriber: Arc<dyn Subscriber>) {
+        self.subscribers.lock().push(subscriber);
+    }
+
+    /// Receives every event published from now on, until the receiver is
+    /// dropped or falls `RECEIVER_CAPACITY` events behind.
+    #[cfg(any(test, feature = "tray", target_os = "linux"))]
+    pub fn subscribe(&self) -> Receiver<MonitorEvent> {
+        let (sender, receiver) = mpsc::sync_channel(RECEIVER_CAPACITY);
+        self.receivers.lock().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, monitor: &BatteryMonitor, event: MonitorEvent) {
+        // Cloned so a slow subscriber doesn't block adding another
+        let subscribers = self.subscribers.lock().clone();
+        for subscriber in &subscribers {
+            subscriber.handle(monitor, &event);
+        }
+
+        self.receivers
+            .lock()
+            .retain(|receiver| try_deliver(receiver, event.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::monitor::testing;
+
+    fn removed(pid: u32) -> MonitorEvent {
+        MonitorEvent::DeviceRemoved {
+            pid,
+            name: "DeathAdder".to_owned(),
+        }
+    }
+
+    #[test]
+    fn receivers_get_events_in_order() {
+        let test = testing::monitor(Config::default());
+        let bus = EventBus::default();
+        let receiver = bus.subscribe();
+
+        bus.publish(&test.monitor, removed(1));
+        bus.publish(&test.monitor, MonitorEvent::Stopped);
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [removed(1), MonitorEvent::Stopped]
+        );
+    }
+
+    #[test]
+    fn lagging_receivers_are_dropped() {
+        let test = testing::monitor(Config::default());
+        let bus = EventBus::default();
+        let lagging = bus.subscribe();
+        let reading = bus.subscribe();
+
+        for pid in 0..=RECEIVER_CAPACITY as u32 {
+            bus.publish(&test.monitor, removed(pid));
+            assert_eq!(reading.try_recv().unwrap(), removed(pid));
+        }
+
+        assert_eq!(bus.receivers.lock().len(), 1);
+        // What was queued before is still there
+        assert_eq!(lagging.try_iter().count(), RECEIVER_CAPACITY);
+    }
+
+    #[test]
+    fn dropped_receivers_are_removed() {
+        let test = testing::monitor(Config::default());
+        let bus = EventBus::default();
+        drop(bus.subscribe());
+
+        bus.publish(&test.monitor, MonitorEvent::Stopped);
+        assert!(bus.receivers.lock().is_empty());
+    }
+}
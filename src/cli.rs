@@ -0,0 +1,409 @@
+use chrono::{Local, TimeZone};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use log::warn;
+
+use crate::bar::{self, BarOptions, BarOutput};
+use crate::config::{parse_duration, parse_pid, Config};
+use crate::controller::battery_percentage;
+use crate::daemon;
+use crate::estimate::{estimate_device, Estimate};
+use crate::history::{unix_timestamp, History, HistoryFilter, HistoryRecord};
+use crate::ipc::{self, Request, Response};
+use crate::manager::{DeviceBackend, DeviceManager};
+use crate::monitor::BatteryMonitor;
+use crate::stats;
+
+pub const USAGE: &str = "\
+Usage: razer-battery-report [COMMAND]
+
+Commands:
+  (none)     Run the tray indicator
+  daemon     Monitor devices without a tray icon (e.g. as a systemd service)
+  status     Read the battery of every connected device
+  refresh    Make the running instance re-read every device now
+  reload     Make the running instance re-read the config file
+  history    Show recorded battery readings
+             --device <PID>      Only show readings for this product id (e.g. 0x00B7)
+             --since <DURATION>  Only show readings newer than this (e.g. 30m, 12h, 7d)
+             --limit <N>         Only show the N most recent readings
+  stats      Show battery usage and health statistics from the history
+             --device <PID>      Only show statistics for this product id
+             --since <DURATION>  Only use readings newer than this
+             --json              Print statistics as JSON
+  watch      Print device events as JSON lines until interrupted
+  bar        Print a continuously updated status line for a status bar
+             --output <OUTPUT>   waybar (default), i3bar or polybar
+             --format <FORMAT>   Text per device, e.g. '{name} {level}%'
+             --device <PID>      Only show this product id
+  help       Show this message";
+
+pub enum Command {
+    Tray,
+    Daemon,
+    Status,
+    Refresh,
+    Reload,
+    History(HistoryFilter),
+    Stats { filter: HistoryFilter, json: bool },
+    Watch,
+    Bar(BarOptions),
+    Help,
+}
+
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let command = match args.next() {
+        None => return Ok(Command::Tray),
+        Some(command) => command,
+    };
+
+    match command.as_str() {
+        "daemon" => Ok(Command::Daemon),
+        "status" => Ok(Command::Status),
+        "refresh" => Ok(Command::Refresh),
+        "reload" => Ok(Command::Reload),
+        "history" => parse_history(args).map(Command::History),
+        "stats" => parse_stats(args).map(|(filter, json)| Command::Stats { filter, json }),
+        "watch" => Ok(Command::Watch),
+        "bar" => parse_bar(args).map(Command::Bar),
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        _ => Err(format!("Unknown command: {}", command)),
+    }
+}
+
+fn parse_history<I: Iterator<Item = String>>(mut args: I) -> Result<HistoryFilter, String> {
+    let mut filter = HistoryFilter::default();
+
+    while let Some(arg) = args.next() {
+        if arg == "--limit" {
+            let limit = next_value(&arg, &mut args)?;
+            filter.limit = Some(
+                limit
+                    .parse()
+                    .map_err(|_| format!("Invalid limit: {}", limit))?,
+            );
+        } else if !parse_filter_arg(&arg, &mut args, &mut filter)? {
+            return Err(format!("Unknown argument: {}", arg));
+        }
+    }
+
+    Ok(filter)
+}
+
+fn parse_stats<I: Iterator<Item = String>>(mut args: I) -> Result<(HistoryFilter, bool), String> {
+    let mut filter = HistoryFilter::default();
+    let mut json = false;
+
+    while let Some(arg) = args.next() {
+        if arg == "--json" {
+            json = true;
+        } else if !parse_filter_arg(&arg, &mut args, &mut filter)? {
+            return Err(format!("Unknown argument: {}", arg));
+        }
+    }
+
+    Ok((filter, json))
+}
+
+fn parse_bar<I: Iterator<Item = String>>(mut args: I) -> Result<BarOptions, String> {
+    let mut options = BarOptions {
+        output: BarOutput::Waybar,
+        device: None,
+        format: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => options.output = next_value(&arg, &mut args)?.parse()?,
+            "--format" => options.format = Some(next_value(&arg, &mut args)?),
+            "--device" => options.device = Some(parse_pid(&next_value(&arg, &mut args)?)?),
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Handles the `--device` and `--since` options shared by history queries.
+fn parse_filter_arg<I: Iterator<Item = String>>(
+    arg: &str,
+    args: &mut I,
+    filter: &mut HistoryFilter,
+) -> Result<bool, String> {
+    match arg {
+        "--device" => filter.pid = Some(parse_pid(&next_value(arg, args)?)?),
+        "--since" => {
+            let since = parse_duration(&next_value(arg, args)?)?;
+            filter.since = Some(unix_timestamp().saturating_sub(since.as_secs()));
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+fn next_value<I: Iterator<Item = String>>(arg: &str, args: &mut I) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}", arg))
+}
+
+pub fn format_timestamp(timestamp: u64) -> String {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map_or_else(
+            || timestamp.to_string(),
+            |time| time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        )
+}
+
+pub fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Tray => unreachable!("tray mode is handled by main"),
+        Command::Daemon => daemon::run(),
+        Command::Status => run_status(),
+        Command::Refresh => run_refresh(),
+        Command::Reload => run_reload(),
+        Command::History(filter) => run_history(&filter),
+        Command::Stats { filter, json } => run_stats(&filter, json),
+        Command::Watch => run_watch(),
+        Command::Bar(options) => bar::run(&options),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
+}
+
+fn run_status() -> Result<(), Box<dyn std::error::Error>> {
+    let Some(response) = request_instance(&Request::Status)? else {
+        return read_status();
+    };
+    let Response::Status { devices } = response else {
+        return Err(unexpected_response(response));
+    };
+
+    if devices.is_empty() {
+        println!("No supported devices connected");
+    }
+
+    for device in devices {
+        match device.level {
+            Some(level) if device.is_available => println!(
+                "{}",
+                format_status(&device.name, level, device.is_charging, device.estimate())
+            ),
+            _ => println!("{}: battery level unavailable", device.name),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the devices directly when no running instance holds them.
+fn read_status() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load();
+    let mut manager = DeviceManager::new();
+    let (_, mut device_ids) = manager.fetch_devices();
+    device_ids.sort_unstable();
+
+    if device_ids.is_empty() {
+        println!("No supported devices connected");
+        return Ok(());
+    }
+
+    let mut history = match History::open_default() {
+        Ok(history) => Some(history),
+        Err(err) => {
+            warn!("Failed to open battery history: {}", err);
+            None
+        }
+    };
+
+    for id in device_ids {
+        let name = manager
+            .get_device_name(id)
+            .map(|model| config.device_name(id, &model))
+            .unwrap_or_else(|| format!("0x{:04X}", id));
+
+        let (Some(raw), Some(is_charging), Some(connection)) = (
+            manager.get_device_battery_raw(id),
+            manager.is_device_charging(id),
+            manager.get_device_connection(id),
+        ) else {
+            println!("{}: battery level unavailable", name);
+            continue;
+        };
+        let level = battery_percentage(raw);
+
+        let mut estimate = None;
+        if let Some(history) = history.as_mut() {
+            let record = HistoryRecord {
+                timestamp: unix_timestamp(),
+                pid: id,
+                name: name.clone(),
+                level,
+                raw,
+                is_charging,
+                connection,
+            };
+            history.append(&record)?;
+            estimate = estimate_device(history, id)?;
+        }
+
+        println!("{}", format_status(&name, level, is_charging, estimate));
+    }
+
+    Ok(())
+}
+
+fn format_status(name: &str, level: i32, is_charging: bool, estimate: Option<Estimate>) -> String {
+    let mut line = format!("{}: {}%", name, level);
+    if is_charging {
+        line.push_str(" (charging)");
+    }
+    if let Some(estimate) = estimate {
+        line.push_str(&format!(" — {}", estimate));
+    }
+    line
+}
+
+fn run_refresh() -> Result<(), Box<dyn std::error::Error>> {
+    match request_instance(&Request::Refresh)? {
+        Some(Response::Ok) => {
+            println!("Refresh requested");
+            Ok(())
+        }
+        Some(response) => Err(unexpected_response(response)),
+        // Without a running instance nobody holds the devices, so read them here
+        None => read_status(),
+    }
+}
+
+fn run_reload() -> Result<(), Box<dyn std::error::Error>> {
+    match request_instance(&Request::ReloadConfig)? {
+        Some(Response::Ok) => {
+            println!("Config reloaded");
+            Ok(())
+        }
+        Some(response) => Err(unexpected_response(response)),
+        None => Err("No running instance to reload".into()),
+    }
+}
+
+fn run_history(filter: &HistoryFilter) -> Result<(), Box<dyn std::error::Error>> {
+    let records = query_history(filter)?;
+
+    if records.is_empty() {
+        println!("No battery readings recorded");
+        return Ok(());
+    }
+
+    for record in &records {
+        println!("{}", format_record(record));
+    }
+
+    Ok(())
+}
+
+fn run_stats(filter: &HistoryFilter, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let stats = stats::compute(&query_history(filter)?);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    if stats.is_empty() {
+        println!("No battery readings recorded");
+        return Ok(());
+    }
+
+    for device in &stats {
+        print!("{}", stats::format_text(device));
+    }
+
+    Ok(())
+}
+
+/// Prints the running instance's events, or monitors the devices like the
+/// daemon if there is none.
+fn run_watch() -> Result<(), Box<dyn std::error::Error>> {
+    let mut stdout = io::stdout().lock();
+
+    if let Some(lines) = ipc::send_streaming(&Request::Watch)? {
+        for line in lines {
+            let line = line?;
+            if let Ok(Response::Error { message }) = serde_json::from_str(&line) {
+                return Err(message.into());
+            }
+            if writeln!(stdout, "{}", line).is_err() {
+                return Ok(());
+            }
+        }
+        return Err("The running instance exited".into());
+    }
+
+    #[cfg(unix)]
+    let signals = crate::signals::SignalSet::block_termination()?;
+
+    let listener = ipc::Listener::bind()?.ok_or("Another instance just started, try again")?;
+    let monitor = Arc::new(BatteryMonitor::new());
+    let events = monitor.events().subscribe();
+    daemon::start(&monitor, listener);
+    #[cfg(unix)]
+    signals.spawn_handler(&monitor);
+
+    // Ends when a signal shuts the monitor down
+    for event in events {
+        if writeln!(stdout, "{}", serde_json::to_string(&event)?).is_err() {
+            break;
+        }
+    }
+    monitor.shutdown();
+    Ok(())
+}
+
+/// Asks the running instance for the history, which is writing to it, and
+/// reads the file only if there is none.
+fn query_history(filter: &HistoryFilter) -> Result<Vec<HistoryRecord>, Box<dyn std::error::Error>> {
+    let request = Request::History {
+        filter: filter.clone(),
+    };
+
+    match request_instance(&request)? {
+        Some(Response::History { records }) => Ok(records),
+        Some(response) => Err(unexpected_response(response)),
+        None => History::open_default()?.query(filter),
+    }
+}
+
+/// Sends a request to the running instance, `None` if there is none.
+fn request_instance(request: &Request) -> Result<Option<Response>, Box<dyn std::error::Error>> {
+    match ipc::send(request)? {
+        Some(Response::Error { message }) => Err(message.into()),
+        response => Ok(response),
+    }
+}
+
+fn unexpected_response(response: Response) -> Box<dyn std::error::Error> {
+    format!(
+        "Unexpected response from the running instance: {:?}",
+        response
+    )
+    .into()
+}
+
+fn format_record(record: &HistoryRecord) -> String {
+    format!(
+        "{}  {} (0x{:04X}, {})  {:>3}% (raw {:>3}){}",
+        format_timestamp(record.timestamp),
+        record.name,
+        record.pid,
+        record.connection,
+        record.level,
+        record.raw,
+        if record.is_charging { "  charging" } else { "" }
+    )
+}
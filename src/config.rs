@@ -0,0 +1,558 @@
+use log::{info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::events::EventKind;
+use crate::icon::IconConfig;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Directory where the user's configuration lives.
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub notifications: NotificationConfig,
+    /// Per-device settings keyed by product id (e.g. `"0x00B7"`).
+    pub devices: HashMap<String, DeviceConfig>,
+    pub hooks: Vec<HookConfig>,
+    pub icon: IconConfig,
+    pub http: HttpConfig,
+    pub metrics: MetricsConfig,
+    pub mqtt: MqttConfig,
+    pub dbus: DbusConfig,
+    pub bar: BarConfig,
+    pub polling: PollingConfig,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(CONFIG_FILE_NAME))
+    }
+
+    /// Loads the configuration file, falling back to defaults if it is missing
+    /// or invalid.
+    pub fn load() -> Self {
+        Self::try_load().unwrap_or_else(|err| {
+            warn!("{}, using defaults", err);
+            Self::default()
+        })
+    }
+
+    /// Loads the configuration file, or the defaults if there is none.
+    pub fn try_load() -> Result<Self, String> {
+        let path = Self::path().ok_or("Failed to determine config directory")?;
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+        };
+
+        let config = toml::from_str(&content)
+            .map_err(|err| format!("Invalid config {}: {}", path.display(), err))?;
+        info!("Loaded config from {}", path.display());
+        Ok(config)
+    }
+
+    pub fn device(&self, pid: u32) -> Option<&DeviceConfig> {
+        self.devices
+            .iter()
+            .find(|(key, _)| parse_pid(key) == Ok(pid))
+            .map(|(_, device)| device)
+    }
+
+    /// The configured name of a device, or its model name.
+    pub fn device_name(&self, pid: u32, model: &str) -> String {
+        self.device(pid)
+            .and_then(|device| device.name.clone())
+            .unwrap_or_else(|| model.to_owned())
+    }
+
+    /// Sets the `name` of a device in the config file, keeping the rest of
+    /// the file as it is.
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    pub fn set_device_name(pid: u32, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Self::path().ok_or("Failed to determine config directory")?;
+        write_device_name(&path, pid, name)
+    }
+
+    /// Rules for a device: its own list if configured, the global list otherwise.
+    pub fn rules(&self, pid: u32) -> &[Rule] {
+        self.device(pid)
+            .and_then(|device| device.rules.as_deref())
+            .unwrap_or(&self.notifications.rules)
+    }
+}
+
+/// The configuration of the running app, replaced when it is reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// The current configuration. Stays unchanged by a later reload.
+    pub fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.0.read())
+    }
+
+    pub fn set(&self, config: Config) {
+        *self.0.write() = Arc::new(config);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    pub rules: Vec<Rule>,
+    /// How long "Snooze Notifications" silences them.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub snooze: Duration,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                Rule::new(RuleKind::Critical, 5),
+                Rule::new(RuleKind::Low, 15),
+                Rule::new(RuleKind::Charged, 100),
+            ],
+            snooze: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Shown instead of the model name.
+    pub name: Option<String>,
+    pub rules: Option<Vec<Rule>>,
+    /// Replaces `polling.interval` for this device.
+    #[serde(default, deserialize_with = "deserialize_optional_interval")]
+    pub interval: Option<Duration>,
+}
+
+/// A command and/or URL to notify about device events.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// Events that trigger the hook, all but `reading_updated` if empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Run through the system shell.
+    pub command: Option<String>,
+    /// Receives the event as a JSON POST request.
+    pub url: Option<String>,
+}
+
+/// The local HTTP API. Changes apply after a restart.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    /// Address to listen on, only reachable from this machine by default.
+    /// Other addresses are refused without a `token`.
+    pub address: String,
+    /// Required as a bearer token or `token` query parameter if set.
+    pub token: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:7878".to_owned(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Written after every battery read, for node_exporter's textfile
+    /// collector. Must end in `.prom`.
+    pub textfile: Option<PathBuf>,
+}
+
+/// Publishing to an MQTT broker. Changes apply after a restart.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// `host:port` of the broker, publishing is off without it.
+    pub broker: Option<String>,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Prefix of the state and availability topics.
+    pub topic_prefix: String,
+    /// Publish Home Assistant discovery config.
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            broker: None,
+            client_id: env!("CARGO_PKG_NAME").to_owned(),
+            username: None,
+            password: None,
+            topic_prefix: env!("CARGO_PKG_NAME").to_owned(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_owned(),
+        }
+    }
+}
+
+/// The session bus service on Linux. Changes apply after a restart.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbusConfig {
+    pub enabled: bool,
+}
+
+/// Output of the `bar` command, read when it starts.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BarConfig {
+    /// Text per device, with placeholders like `{level}`.
+    pub format: String,
+    pub tooltip_format: String,
+    /// Between the texts of several devices.
+    pub separator: String,
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub interval: Duration,
+    /// Levels up to which the `low` and `critical` classes apply.
+    pub low: i32,
+    pub critical: i32,
+}
+
+impl Default for BarConfig {
+    fn default() -> Self {
+        Self {
+            format: "{level}%{charging}".to_owned(),
+            tooltip_format: "{name}: {level}%, {state} {estimate}".to_owned(),
+            separator: " ".to_owned(),
+            interval: Duration::from_secs(30),
+            low: 40,
+            critical: 15,
+        }
+    }
+}
+
+/// How often batteries are read.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollingConfig {
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub interval: Duration,
+    /// Used instead while charging, if shorter.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub charging_interval: Duration,
+    /// Used instead near or below a `low` or `critical` rule, if shorter.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub low_interval: Duration,
+    /// Used instead at 100%, if longer.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub full_interval: Duration,
+    /// Longest wait between retries of a device that doesn't answer.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub max_backoff: Duration,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5 * 60),
+            charging_interval: Duration::from_secs(60),
+            low_interval: Duration::from_secs(60),
+            full_interval: Duration::from_secs(15 * 60),
+            max_backoff: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    /// Level dropped to or below the threshold while discharging.
+    Low,
+    /// Same as `Low`, shown as an urgent notification.
+    Critical,
+    /// Level reached the threshold while charging.
+    Charged,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub kind: RuleKind,
+    pub level: i32,
+    /// How far the level has to move back past the threshold before the rule
+    /// can fire again.
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: i32,
+    /// Repeat the notification while the condition holds.
+    #[serde(default, deserialize_with = "deserialize_optional_interval")]
+    pub repeat: Option<Duration>,
+}
+
+impl Rule {
+    pub const fn new(kind: RuleKind, level: i32) -> Self {
+        Self {
+            kind,
+            level,
+            hysteresis: 2,
+            repeat: None,
+        }
+    }
+
+    /// Identifies the rule in persisted notification state.
+    pub fn key(&self) -> String {
+        let kind = match self.kind {
+            RuleKind::Low => "low",
+            RuleKind::Critical => "critical",
+            RuleKind::Charged => "charged",
+        };
+        format!("{}:{}", kind, self.level)
+    }
+}
+
+fn default_hysteresis() -> i32 {
+    2
+}
+
+#[cfg(any(feature = "tray", target_os = "linux"))]
+fn write_device_name(
+    path: &std::path::Path,
+    pid: u32,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    // Leaves a file alone that wouldn't load anyway
+    toml::from_str::<Config>(&content)?;
+
+    let mut document: toml_edit::DocumentMut = content.parse()?;
+    let devices = document
+        .entry("devices")
+        .or_insert_with(|| {
+            let mut devices = toml_edit::Table::new();
+            devices.set_implicit(true);
+            toml_edit::Item::Table(devices)
+        })
+        .as_table_like_mut()
+        .ok_or("`devices` is not a table")?;
+    let key = devices
+        .iter()
+        .map(|(key, _)| key)
+        .find(|key| parse_pid(key) == Ok(pid))
+        .map_or_else(|| format!("0x{:04X}", pid), str::to_owned);
+    let device = devices
+        .entry(&key)
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| format!("`devices.{}` is not a table", key))?;
+    device.insert("name", toml_edit::value(name));
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, document.to_string())?;
+    info!(
+        "Named device 0x{:04X} {:?} in {}",
+        pid,
+        name,
+        path.display()
+    );
+    Ok(())
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+/// A duration that something waits for repeatedly, so it can't be zero.
+fn deserialize_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let interval = deserialize_duration(deserializer)?;
+    if interval.is_zero() {
+        return Err(serde::de::Error::custom(
+            "Interval must be longer than zero",
+        ));
+    }
+    Ok(interval)
+}
+
+fn deserialize_optional_interval<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    deserialize_interval(deserializer).map(Some)
+}
+
+pub fn parse_pid(value: &str) -> Result<u32, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid product id: {}", value))
+}
+
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {}", value);
+
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" | "" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes < 60 {
+        format!("{} min", minutes.max(1))
+    } else {
+        format!("{} h", (minutes as f64 / 60.0).round())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_parse_with_units() {
+        let cases = [
+            ("30s", Some(30)),
+            ("5m", Some(5 * 60)),
+            ("5", Some(5 * 60)),
+            ("2h", Some(2 * 60 * 60)),
+            ("7d", Some(7 * 24 * 60 * 60)),
+            ("0s", Some(0)),
+            ("", None),
+            ("m", None),
+            ("5w", None),
+            ("-5m", None),
+            ("1.5h", None),
+            ("18446744073709551615s", Some(u64::MAX)),
+            ("18446744073709551615m", None),
+            ("999999999999999999d", None),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(
+                parse_duration(value).ok(),
+                expected.map(Duration::from_secs),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    #[test]
+    fn device_names_are_written_to_the_file() {
+        let dir = crate::history::testing::TempDir::new();
+        let path = dir.path().join("settings").join(CONFIG_FILE_NAME);
+        let name = |path, pid| -> String {
+            let config: Config = toml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+            config.device_name(pid, "Model")
+        };
+
+        // A new file
+        write_device_name(&path, 0x00B7, "Left \"mouse\"").unwrap();
+        assert_eq!(name(&path, 0x00B7), "Left \"mouse\"");
+
+        // A second device, then renaming the first one
+        write_device_name(&path, 0x00B8, "Keyboard").unwrap();
+        write_device_name(&path, 0x00B7, "Mouse").unwrap();
+        assert_eq!(name(&path, 0x00B7), "Mouse");
+        assert_eq!(name(&path, 0x00B8), "Keyboard");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().matches("name").count(),
+            2
+        );
+
+        let existing = [
+            "# Comments stay\n[devices.\"0x00b7\"]\ninterval = \"2m\"\n",
+            "[devices.\"183\"]\nname = \"Old\"\n",
+            "devices = { 0x00B7 = { interval = \"2m\" } }\n",
+        ];
+        for content in existing {
+            fs::write(&path, content).unwrap();
+            write_device_name(&path, 0x00B7, "Mouse").unwrap();
+
+            let written = fs::read_to_string(&path).unwrap();
+            assert_eq!(name(&path, 0x00B7), "Mouse", "{}", written);
+            assert_eq!(
+                written.matches("0x00").count() + written.matches("183").count(),
+                1
+            );
+            assert!(!written.contains("Old"));
+            if content.starts_with('#') {
+                assert!(written.starts_with("# Comments stay\n"), "{}", written);
+                assert!(written.contains("interval = \"2m\""), "{}", written);
+            }
+        }
+
+        // Invalid files aren't touched
+        fs::write(&path, "[polling]\ninterval = 5").unwrap();
+        assert!(write_device_name(&path, 0x00B7, "Mouse").is_err());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[polling]\ninterval = 5"
+        );
+    }
+
+    #[test]
+    fn intervals_cant_be_zero() {
+        let cases = [
+            "[polling]\ninterval = \"0s\"",
+            "[polling]\ncharging_interval = \"0m\"",
+            "[polling]\nmax_backoff = \"0h\"",
+            "[devices.\"0x00B7\"]\ninterval = \"0\"",
+            "[bar]\ninterval = \"0s\"",
+        ];
+
+        for config in cases {
+            let err = toml::from_str::<Config>(config).unwrap_err();
+            assert!(err.to_string().contains("longer than zero"), "{}", config);
+        }
+        let config: Config = toml::from_str("[polling]\ninterval = \"1s\"").unwrap();
+        assert_eq!(config.polling.interval, Duration::from_secs(1));
+    }
+}
@@ -1,58 +1,155 @@
-use parking_lot::Mutex;
-use std::{ffi::OsStr, os::windows::ffi::OsStrExt, sync::Arc};
-use winapi::um::{consoleapi, wincon, winuser};
+//! The log window of the tray app and message boxes. Only Windows has them,
+//! elsewhere the log goes to the terminal the app was started from.
 
-pub struct DebugConsole {
-    hwnd: *mut winapi::shared::windef::HWND__,
-    visible: Arc<Mutex<bool>>,
-}
+pub use platform::*;
+
+#[cfg(windows)]
+mod platform {
+    use winapi::um::wincon;
+    #[cfg(feature = "tray")]
+    use {
+        parking_lot::Mutex,
+        std::{ffi::OsStr, os::windows::ffi::OsStrExt, sync::Arc},
+        winapi::um::{consoleapi, winuser},
+    };
 
-impl DebugConsole {
-    pub fn new(title: &str) -> Self {
+    /// Attaches to the console of the process that launched us, so command line
+    /// output is visible despite the `windows` subsystem.
+    pub fn attach_parent_console() {
         unsafe {
-            consoleapi::AllocConsole();
+            wincon::AttachConsole(wincon::ATTACH_PARENT_PROCESS);
+        }
+    }
 
-            let title: Vec<u16> = OsStr::new(title)
+    /// Shows a message box. Blocks until it is closed.
+    #[cfg(feature = "tray")]
+    pub fn show_message(title: &str, text: &str) {
+        let encode = |value: &str| -> Vec<u16> {
+            OsStr::new(value)
                 .encode_wide()
                 .chain(std::iter::once(0))
-                .collect();
-            wincon::SetConsoleTitleW(title.as_ptr());
+                .collect()
+        };
+        let (title, text) = (encode(title), encode(text));
+
+        unsafe {
+            winuser::MessageBoxW(
+                std::ptr::null_mut(),
+                text.as_ptr(),
+                title.as_ptr(),
+                winuser::MB_OK | winuser::MB_ICONINFORMATION,
+            );
+        }
+    }
+
+    #[cfg(feature = "tray")]
+    pub struct DebugConsole {
+        hwnd: *mut winapi::shared::windef::HWND__,
+        visible: Arc<Mutex<bool>>,
+    }
 
-            let hwnd = wincon::GetConsoleWindow();
+    #[cfg(feature = "tray")]
+    impl DebugConsole {
+        pub fn new(title: &str) -> Self {
+            unsafe {
+                consoleapi::AllocConsole();
+
+                let title: Vec<u16> = OsStr::new(title)
+                    .encode_wide()
+                    .chain(std::iter::once(0))
+                    .collect();
+                wincon::SetConsoleTitleW(title.as_ptr());
+
+                let hwnd = wincon::GetConsoleWindow();
+
+                if !hwnd.is_null() {
+                    let hmenu = winuser::GetSystemMenu(hwnd, 0);
+                    if !hmenu.is_null() {
+                        winuser::DeleteMenu(hmenu, winuser::SC_CLOSE as u32, winuser::MF_BYCOMMAND);
+                    }
+                    winuser::ShowWindow(hwnd, winuser::SW_HIDE);
+                }
 
-            if !hwnd.is_null() {
-                let hmenu = winuser::GetSystemMenu(hwnd, 0);
-                if !hmenu.is_null() {
-                    winuser::DeleteMenu(hmenu, winuser::SC_CLOSE as u32, winuser::MF_BYCOMMAND);
+                Self {
+                    hwnd,
+                    visible: Arc::new(Mutex::new(false)),
                 }
-                winuser::ShowWindow(hwnd, winuser::SW_HIDE);
             }
+        }
 
-            Self {
-                hwnd,
-                visible: Arc::new(Mutex::new(false)),
+        pub fn toggle_visibility(&self) {
+            if !self.hwnd.is_null() {
+                let mut visible = self.visible.lock();
+                *visible = !*visible;
+                unsafe {
+                    winuser::ShowWindow(
+                        self.hwnd,
+                        if *visible {
+                            winuser::SW_SHOW
+                        } else {
+                            winuser::SW_HIDE
+                        },
+                    );
+                }
+            }
+        }
+
+        /// Shows the window and brings it to the front.
+        pub fn show(&self) {
+            if !self.hwnd.is_null() {
+                *self.visible.lock() = true;
+                unsafe {
+                    winuser::ShowWindow(self.hwnd, winuser::SW_SHOW);
+                    winuser::SetForegroundWindow(self.hwnd);
+                }
             }
         }
+
+        pub fn is_visible(&self) -> bool {
+            *self.visible.lock()
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    #[cfg(feature = "tray")]
+    use parking_lot::Mutex;
+
+    /// Output already goes to the terminal we were started from.
+    pub fn attach_parent_console() {}
+
+    /// Without message boxes the message only appears in the log, which
+    /// callers write to anyway.
+    #[cfg(feature = "tray")]
+    pub fn show_message(_title: &str, _text: &str) {}
+
+    /// Tracks whether the log "window" is shown so the tray menu stays the same
+    /// on every platform.
+    #[cfg(feature = "tray")]
+    pub struct DebugConsole {
+        visible: Mutex<bool>,
     }
 
-    pub fn toggle_visibility(&self) {
-        if !self.hwnd.is_null() {
+    #[cfg(feature = "tray")]
+    impl DebugConsole {
+        pub fn new(_title: &str) -> Self {
+            Self {
+                visible: Mutex::new(false),
+            }
+        }
+
+        pub fn toggle_visibility(&self) {
             let mut visible = self.visible.lock();
             *visible = !*visible;
-            unsafe {
-                winuser::ShowWindow(
-                    self.hwnd,
-                    if *visible {
-                        winuser::SW_SHOW
-                    } else {
-                        winuser::SW_HIDE
-                    },
-                );
-            }
         }
-    }
 
-    pub fn is_visible(&self) -> bool {
-        *self.visible.lock()
+        pub fn show(&self) {
+            *self.visible.lock() = true;
+        }
+
+        pub fn is_visible(&self) -> bool {
+            *self.visible.lock()
+        }
     }
 }
@@ -2,13 +2,19 @@ use hidapi::{HidApi, HidDevice};
 use log::{info, warn};
 use std::ffi::CString;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::devices::RAZER_DEVICE_LIST;
+use crate::devices::{ConnectionType, RAZER_DEVICE_LIST};
+use crate::metrics;
 
 const MAX_TRIES_SEND: u8 = 10;
 const TIME_BETWEEN_SEND: Duration = Duration::from_millis(500);
 
+/// Converts the raw battery byte reported by the device (0-255) to a percentage.
+pub fn battery_percentage(raw: u8) -> i32 {
+    ((raw as f32 / 255.0) * 100.0).round() as i32
+}
+
 pub struct RazerReport {
     pub status: u8,
     pub transaction_id: u8,
@@ -97,6 +103,7 @@ pub struct DeviceController {
     pub handle: HidDevice,
     pub name: String,
     pub pid: u16,
+    pub connection: ConnectionType,
     pub report_id: u8,
     pub transaction_id: u8,
 }
@@ -108,25 +115,24 @@ impl DeviceController {
         let c_path = CString::new(path)?;
         let handle = api.open_path(c_path.as_ref())?;
 
-        let transaction_id = RAZER_DEVICE_LIST
-            .iter()
-            .find(|device| device.pid == pid)
-            .map_or(0x3F, |device| device.transaction_id());
+        let info = RAZER_DEVICE_LIST.iter().find(|device| device.pid == pid);
+        let transaction_id = info.map_or(0x3F, |device| device.transaction_id());
+        let connection = info.map_or(ConnectionType::Wireless, |device| device.connection);
 
         Ok(DeviceController {
             handle,
             name,
             pid,
+            connection,
             report_id: 0x00,
             transaction_id,
         })
     }
 
-    pub fn get_battery_level(&self) -> Result<i32, Box<dyn std::error::Error>> {
+    pub fn get_battery_raw(&self) -> Result<u8, Box<dyn std::error::Error>> {
         let request = self.create_command(0x07, 0x80, 0x02);
         let response = self.send_payload(request)?;
-        let battery_level = (response.arguments[1] as f32 / 255.0) * 100.0;
-        Ok(battery_level.round() as i32)
+        Ok(response.arguments[1])
     }
 
     pub fn get_charging_status(&self) -> Result<bool, Box<dyn std::error::Error>> {
@@ -136,7 +142,30 @@ impl DeviceController {
         Ok(charging_status)
     }
 
+    /// The serial number, e.g. `PM2312H12345678`. Wireless devices may report
+    /// the serial of their receiver.
+    pub fn get_serial(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let request = self.create_command(0x00, 0x82, 0x16);
+        let response = self.send_payload(request)?;
+        let serial: String = response.arguments[..0x16]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect();
+        Ok(serial.trim().to_owned())
+    }
+
     pub fn send_payload(
+        &self,
+        request: RazerReport,
+    ) -> Result<RazerReport, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        let result = self.send_payload_with_retries(request);
+        metrics::record_command(self.pid, start.elapsed(), result.is_ok());
+        result
+    }
+
+    fn send_payload_with_retries(
         &self,
         mut request: RazerReport,
     ) -> Result<RazerReport, Box<dyn std::error::Error>> {
@@ -157,13 +186,17 @@ impl DeviceController {
                 RazerReport::STATUS_SUCCESSFUL => return Ok(response),
                 RazerReport::STATUS_BUSY => info!("Device is busy"),
                 RazerReport::STATUS_NO_RESPONSE => info!("Command timed out"),
-                RazerReport::STATUS_NOT_SUPPORTED => return Err("Command not supported".into()),
+                RazerReport::STATUS_NOT_SUPPORTED => {
+                    metrics::record_not_supported(self.pid);
+                    return Err("Command not supported".into());
+                }
                 RazerReport::STATUS_FAILURE => return Err("Command failed".into()),
                 _ => return Err("Error unknown report status".into()),
             }
 
             thread::sleep(TIME_BETWEEN_SEND);
             warn!("Trying to resend command");
+            metrics::record_retry(self.pid);
         }
 
         Err(format!("Abort command (tries: {})", MAX_TRIES_SEND).into())
@@ -198,6 +231,7 @@ impl DeviceController {
 
         let report = RazerReport::from_bytes(&buf[1..])?;
         if !report.is_valid() {
+            metrics::record_crc_failure(self.pid);
             return Err("Get report has no valid crc".into());
         }
 
@@ -0,0 +1,208 @@
+//! Headless mode without a tray icon, e.g. for running as a systemd user
+//! service. Logs go to stderr, the IPC endpoint works like in tray mode.
+//!
+//! On Linux it reports readiness and watchdog pings to systemd when started
+//! with `Type=notify`, reloads the config on SIGHUP and exits on SIGTERM.
+
+use log::info;
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+
+use crate::http;
+use crate::ipc::{self, Request, Response};
+use crate::monitor::BatteryMonitor;
+use crate::scheduler::DEVICE_FETCH_INTERVAL;
+#[cfg(unix)]
+use crate::signals::SignalSet;
+
+pub fn run() -> Result<(), Box<dyn Error>> {
+    // Signals have to be blocked before any thread starts, so they all
+    // inherit the mask and only the main thread receives them
+    #[cfg(unix)]
+    let signals = SignalSet::block(&[libc::SIGTERM, libc::SIGINT, libc::SIGHUP])?;
+
+    let listener = ipc::Listener::bind()?.ok_or("Another instance is already running")?;
+    let monitor = Arc::new(BatteryMonitor::new());
+    start(&monitor, listener);
+
+    systemd::notify("READY=1");
+    info!("Monitoring devices");
+
+    #[cfg(unix)]
+    loop {
+        match signals.wait()? {
+            libc::SIGHUP => {
+                systemd::notify("RELOADING=1");
+                if let Err(err) = monitor.reload_config() {
+                    log::error!("Failed to reload config: {}", err);
+                }
+                systemd::notify("READY=1");
+            }
+            signal => {
+                info!("Received signal {}, exiting", signal);
+                systemd::notify("STOPPING=1");
+                monitor.shutdown();
+                return Ok(());
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    loop {
+        thread::park();
+    }
+}
+
+/// Answers IPC requests and polls the devices in the background.
+pub fn start(monitor: &Arc<BatteryMonitor>, listener: ipc::Listener) {
+    spawn_ipc_server(listener, Arc::clone(monitor));
+    monitor.start_polling();
+    spawn_watchdog_thread(Arc::clone(monitor));
+    http::start(monitor);
+}
+
+fn spawn_ipc_server(listener: ipc::Listener, monitor: Arc<BatteryMonitor>) {
+    listener.serve(move |request| match request {
+        Request::Status => Response::Status {
+            devices: monitor.status(),
+        },
+        Request::Refresh => {
+            monitor.request_poll();
+            Response::Ok
+        }
+        Request::History { filter } => match monitor.history(&filter) {
+            Ok(records) => Response::History { records },
+            Err(err) => Response::error(err),
+        },
+        Request::ReloadConfig => match monitor.reload_config() {
+            Ok(()) => Response::Ok,
+            Err(err) => Response::error(err),
+        },
+        Request::Activate => Response::error("Running as a daemon without a tray icon"),
+        Request::Watch => Response::Events(monitor.events().subscribe()),
+    });
+}
+
+fn spawn_watchdog_thread(monitor: Arc<BatteryMonitor>) {
+    let Some(timeout) = systemd::watchdog_timeout() else {
+        return;
+    };
+
+    thread::spawn(move || loop {
+        // A hung device read stops enumeration and with it the pings, so
+        // systemd restarts us
+        if monitor.since_fetch() <= 2 * DEVICE_FETCH_INTERVAL {
+            systemd::notify("WATCHDOG=1");
+        }
+
+        // Twice per timeout, as systemd recommends
+        thread::sleep(timeout / 2);
+    });
+}
+
+/// The `sd_notify` protocol: state changes sent as datagrams to the socket in
+/// `$NOTIFY_SOCKET`.
+#[cfg(unix)]
+mod systemd {
+    use log::{trace, warn};
+    use std::env;
+    use std::ffi::OsStr;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    pub fn notify(state: &str) {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+
+        trace!("Notifying systemd: {}", state);
+        if let Err(err) = send(&path, state) {
+            warn!("Failed to notify systemd: {}", err);
+        }
+    }
+
+    fn send(path: &OsStr, state: &str) -> io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+
+        match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                use std::os::unix::net::SocketAddr;
+
+                let address = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &address)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract sockets are only supported on Linux",
+                ));
+            }
+            None => {
+                socket.send_to(state.as_bytes(), path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How often systemd expects `WATCHDOG=1` pings from this process, if
+    /// at all.
+    pub fn watchdog_timeout() -> Option<Duration> {
+        parse_watchdog(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+        )
+    }
+
+    fn parse_watchdog(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+        let for_us = pid.is_none_or(|pid| pid.parse() == Ok(std::process::id()));
+        let usec = usec?.parse::<u64>().ok().filter(|&usec| usec > 0)?;
+        for_us.then(|| Duration::from_micros(usec))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn watchdog_timeouts_come_from_the_environment() {
+            let pid = std::process::id().to_string();
+            let other = (std::process::id() + 1).to_string();
+            let cases = [
+                (None, None, None),
+                (Some("30000000"), None, Some(30)),
+                (Some("30000000"), Some(pid.as_str()), Some(30)),
+                (Some("30000000"), Some(other.as_str()), None),
+                (Some("0"), None, None),
+                (Some("soon"), None, None),
+                (None, Some(pid.as_str()), None),
+            ];
+
+            for (usec, pid, expected) in cases {
+                assert_eq!(
+                    parse_watchdog(usec, pid),
+                    expected.map(Duration::from_secs),
+                    "{:?} {:?}",
+                    usec,
+                    pid
+                );
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod systemd {
+    use std::time::Duration;
+
+    pub fn notify(_state: &str) {}
+
+    pub fn watchdog_timeout() -> Option<Duration> {
+        None
+    }
+}
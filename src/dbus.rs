@@ -0,0 +1,520 @@
+//! Session bus service with an object per device, shaped like UPower's
+//! `org.freedesktop.UPower.Device` so widgets that read UPower batteries can
+//! read ours by pointing them at a different bus name.
+
+use log::{info, warn};
+use zbus::blocking::connection::Builder;
+use zbus::blocking::Connection;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath};
+use zbus::{block_on, interface};
+
+use crate::monitor::{MemoryDevice, StatePublisher};
+
+const BUS_NAME: &str = "io.github.xzeldon.RazerBatteryReport";
+const ROOT_PATH: &str = "/io/github/xzeldon/RazerBatteryReport";
+
+/// UPower device type.
+const TYPE_MOUSE: u32 = 5;
+
+/// UPower device states.
+const STATE_UNKNOWN: u32 = 0;
+const STATE_CHARGING: u32 = 1;
+const STATE_DISCHARGING: u32 = 2;
+const STATE_FULLY_CHARGED: u32 = 4;
+
+pub struct DbusService {
+    connection: Connection,
+}
+
+impl DbusService {
+    pub fn start() -> zbus::Result<Self> {
+        Self::serve(Builder::session()?)
+    }
+
+    fn serve(builder: Builder) -> zbus::Result<Self> {
+        let connection = builder
+            .name(BUS_NAME)?
+            .serve_at(ROOT_PATH, Root::default())?
+            .build()?;
+        info!("Serving devices on the session bus as {}", BUS_NAME);
+        Ok(Self { connection })
+    }
+
+    fn try_update(&self, device: &MemoryDevice) -> zbus::Result<()> {
+        let path = device_path(device.pid);
+        let server = self.connection.object_server();
+        let new = Device::from(device);
+
+        let iface = match server.interface::<_, Device>(&path) {
+            Ok(iface) => iface,
+            Err(zbus::Error::InterfaceNotFound) => {
+                server.at(&path, new)?;
+                let root = server.interface::<_, Root>(ROOT_PATH)?;
+                root.get_mut().devices.push(path.clone());
+                return block_on(Root::device_added(root.signal_emitter(), path.as_ref()));
+            }
+            Err(err) => return Err(err),
+        };
+
+        let old = std::mem::replace(&mut *iface.get_mut(), new.clone());
+        let emitter = iface.signal_emitter();
+        block_on(async {
+            if old.percentage != new.percentage {
+                new.percentage_changed(emitter).await?;
+            }
+            if old.is_charging != new.is_charging {
+                new.is_charging_changed(emitter).await?;
+            }
+            if old.icon_name() != new.icon_name() {
+                new.icon_name_changed(emitter).await?;
+            }
+            if old.state() != new.state() {
+                new.state_changed(emitter).await?;
+            }
+            if old.is_present != new.is_present {
+                new.is_present_changed(emitter).await?;
+            }
+            if old.model != new.model {
+                new.model_changed(emitter).await?;
+            }
+            if old.serial != new.serial {
+                new.serial_changed(emitter).await?;
+            }
+            if old.update_time != new.update_time {
+                new.update_time_changed(emitter).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn try_remove(&self, pid: u32) -> zbus::Result<()> {
+        let path = device_path(pid);
+        let server = self.connection.object_server();
+        match server.remove::<Device, _>(&path) {
+            Ok(true) => (),
+            // Never published, or removed already
+            Ok(false) | Err(zbus::Error::InterfaceNotFound) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+
+        let root = server.interface::<_, Root>(ROOT_PATH)?;
+        root.get_mut().devices.retain(|device| *device != path);
+        block_on(Root::device_removed(root.signal_emitter(), path.as_ref()))
+    }
+}
+
+impl StatePublisher for DbusService {
+    fn update(&self, device: &MemoryDevice) {
+        if let Err(err) = self.try_update(device) {
+            warn!("Failed to update D-Bus device: {}", err);
+        }
+    }
+
+    fn removed(&self, pid: u32) {
+        if let Err(err) = self.try_remove(pid) {
+            warn!("Failed to remove D-Bus device: {}", err);
+        }
+    }
+}
+
+fn device_path(pid: u32) -> OwnedObjectPath {
+    let path = format!("{}/devices/razer_{:04x}", ROOT_PATH, pid);
+    ObjectPath::try_from(path)
+        .expect("valid object path")
+        .into()
+}
+
+/// Lists the devices, like `org.freedesktop.UPower`.
+#[derive(Default)]
+struct Root {
+    devices: Vec<OwnedObjectPath>,
+}
+
+#[interface(name = "org.freedesktop.UPower")]
+impl Root {
+    fn enumerate_devices(&self) -> Vec<OwnedObjectPath> {
+        self.devices.clone()
+    }
+
+    #[zbus(signal)]
+    async fn device_added(emitter: &SignalEmitter<'_>, device: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn device_removed(
+        emitter: &SignalEmitter<'_>,
+        device: ObjectPath<'_>,
+    ) -> zbus::Result<()>;
+}
+
+#[derive(Clone)]
+struct Device {
+    native_path: String,
+    model: String,
+    serial: String,
+    percentage: f64,
+    is_charging: bool,
+    /// Whether the last battery read succeeded.
+    is_present: bool,
+    has_reading: bool,
+    update_time: u64,
+}
+
+impl From<&MemoryDevice> for Device {
+    fn from(device: &MemoryDevice) -> Self {
+        Self {
+            native_path: format!("razer-{:04x}", device.pid),
+            model: device.model.clone(),
+            serial: device.serial.clone().unwrap_or_default(),
+            percentage: device.battery_level.map_or(0.0, f64::from),
+            is_charging: device.is_charging,
+            is_present: device.is_available,
+            has_reading: device.battery_level.is_some(),
+            update_time: device.last_update.unwrap_or(0),
+        }
+    }
+}
+
+#[interface(name = "org.freedesktop.UPower.Device")]
+impl Device {
+    #[zbus(property)]
+    fn native_path(&self) -> &str {
+        &self.native_path
+    }
+
+    #[zbus(property)]
+    fn vendor(&self) -> &str {
+        "Razer"
+    }
+
+    #[zbus(property)]
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    #[zbus(property)]
+    fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    #[zbus(property)]
+    fn update_time(&self) -> u64 {
+        self.update_time
+    }
+
+    #[zbus(property, name = "Type")]
+    fn kind(&self) -> u32 {
+        TYPE_MOUSE
+    }
+
+    #[zbus(property)]
+    fn power_supply(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn is_present(&self) -> bool {
+        self.is_present
+    }
+
+    #[zbus(property)]
+    fn is_rechargeable(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn percentage(&self) -> f64 {
+        self.percentage
+    }
+
+    /// Not part of UPower, which only has `State`.
+    #[zbus(property)]
+    fn is_charging(&self) -> bool {
+        self.is_charging
+    }
+
+    #[zbus(property)]
+    fn state(&self) -> u32 {
+        match (self.has_reading, self.is_charging) {
+            (false, _) => STATE_UNKNOWN,
+            (true, true) if self.percentage >= 100.0 => STATE_FULLY_CHARGED,
+            (true, true) => STATE_CHARGING,
+            (true, false) => STATE_DISCHARGING,
+        }
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> String {
+        if !self.has_reading {
+            return "battery-missing-symbolic".to_owned();
+        }
+
+        let level = match self.percentage {
+            level if level >= 80.0 => "full",
+            level if level >= 40.0 => "good",
+            level if level >= 15.0 => "low",
+            _ => "caution",
+        };
+        let charging = if self.is_charging { "-charging" } else { "" };
+        format!("battery-{}{}-symbolic", level, charging)
+    }
+}
+
+/// A private bus for tests that talk D-Bus.
+#[cfg(test)]
+pub mod testing {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use zbus::blocking::connection::Builder;
+    use zbus::blocking::Connection;
+
+    /// A `dbus-daemon` of its own, killed when dropped.
+    pub struct TestBus {
+        daemon: Child,
+        pub address: String,
+    }
+
+    impl TestBus {
+        /// `None` if `dbus-daemon` isn't installed, so the test can be skipped.
+        pub fn start() -> Option<Self> {
+            let mut daemon = match Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(daemon) => daemon,
+                Err(err) => {
+                    eprintln!("Skipped, no dbus-daemon: {}", err);
+                    return None;
+                }
+            };
+
+            let mut address = String::new();
+            let stdout = daemon.stdout.take().expect("piped stdout");
+            BufReader::new(stdout).read_line(&mut address).unwrap();
+            Some(Self {
+                daemon,
+                address: address.trim().to_owned(),
+            })
+        }
+
+        pub fn builder(&self) -> Builder<'static> {
+            Builder::address(self.address.as_str()).unwrap()
+        }
+
+        pub fn connect(&self) -> Connection {
+            self.builder().build().unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::TestBus;
+    use super::*;
+    use crate::config::Config;
+    use crate::monitor::testing::{self, TestMonitor};
+    use std::collections::HashMap;
+    use zbus::blocking::MessageIterator;
+    use zbus::message::Type;
+    use zbus::zvariant::OwnedValue;
+    use zbus::MatchRule;
+
+    const PID: u32 = 0x00B7;
+    const DEVICE_INTERFACE: &str = "org.freedesktop.UPower.Device";
+
+    struct Client {
+        connection: Connection,
+        signals: MessageIterator,
+    }
+
+    impl Client {
+        fn new(bus: &TestBus) -> Self {
+            let connection = bus.connect();
+            let rule = MatchRule::builder()
+                .msg_type(Type::Signal)
+                .sender(BUS_NAME)
+                .unwrap()
+                .build();
+            let signals = MessageIterator::for_match_rule(rule, &connection, None).unwrap();
+            Self {
+                connection,
+                signals,
+            }
+        }
+
+        fn properties(&self, path: &OwnedObjectPath) -> HashMap<String, OwnedValue> {
+            self.connection
+                .call_method(
+                    Some(BUS_NAME),
+                    path,
+                    Some("org.freedesktop.DBus.Properties"),
+                    "GetAll",
+                    &DEVICE_INTERFACE,
+                )
+                .unwrap()
+                .body()
+                .deserialize()
+                .unwrap()
+        }
+
+        fn devices(&self) -> Vec<OwnedObjectPath> {
+            self.connection
+                .call_method(
+                    Some(BUS_NAME),
+                    ROOT_PATH,
+                    Some("org.freedesktop.UPower"),
+                    "EnumerateDevices",
+                    &(),
+                )
+                .unwrap()
+                .body()
+                .deserialize()
+                .unwrap()
+        }
+
+        /// The next signal's name and the object it came from.
+        fn signal(&mut self) -> (String, zbus::message::Message) {
+            let message = self.signals.next().unwrap().unwrap();
+            let member = message.header().member().unwrap().to_string();
+            (member, message)
+        }
+
+        /// Names of the properties in the next `PropertiesChanged` signal.
+        fn changed(&mut self) -> Vec<String> {
+            let (member, message) = self.signal();
+            assert_eq!(member, "PropertiesChanged");
+            assert_eq!(
+                message.header().path().unwrap().as_str(),
+                device_path(PID).as_str()
+            );
+
+            let (interface, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) =
+                message.body().deserialize().unwrap();
+            assert_eq!(interface, DEVICE_INTERFACE);
+            let mut names: Vec<String> = changed.into_keys().collect();
+            names.sort();
+            names
+        }
+    }
+
+    fn value<T>(properties: &HashMap<String, OwnedValue>, name: &str) -> T
+    where
+        T: TryFrom<OwnedValue>,
+        T::Error: std::fmt::Debug,
+    {
+        T::try_from(properties[name].try_clone().unwrap()).unwrap()
+    }
+
+    fn start() -> Option<(TestBus, DbusService, Client, TestMonitor)> {
+        let bus = TestBus::start()?;
+        let service = DbusService::serve(bus.builder()).unwrap();
+        let client = Client::new(&bus);
+
+        let test = testing::monitor(Config::default());
+        test.backend.connect(PID, "Razer DeathAdder V3 Pro");
+        test.monitor.fetch_devices();
+        Some((bus, service, client, test))
+    }
+
+    fn read(test: &TestMonitor, service: &DbusService, level: i32, is_charging: bool) {
+        test.backend.set_level(PID, level, is_charging);
+        test.monitor.update(&[PID]);
+        service.try_update(&test.monitor.devices()[&PID]).unwrap();
+    }
+
+    #[test]
+    fn devices_are_added_with_upower_properties() {
+        let Some((_bus, service, mut client, test)) = start() else {
+            return;
+        };
+        assert!(client.devices().is_empty());
+
+        read(&test, &service, 42, true);
+        let (member, message) = client.signal();
+        assert_eq!(member, "DeviceAdded");
+        let path: OwnedObjectPath = message.body().deserialize().unwrap();
+        assert_eq!(path, device_path(PID));
+        assert_eq!(client.devices(), [device_path(PID)]);
+
+        let properties = client.properties(&path);
+        assert_eq!(value::<String>(&properties, "NativePath"), "razer-00b7");
+        assert_eq!(value::<String>(&properties, "Vendor"), "Razer");
+        assert_eq!(
+            value::<String>(&properties, "Model"),
+            "Razer DeathAdder V3 Pro"
+        );
+        assert_eq!(value::<String>(&properties, "Serial"), "PM00B7");
+        assert_eq!(value::<u32>(&properties, "Type"), TYPE_MOUSE);
+        assert_eq!(value::<f64>(&properties, "Percentage"), 42.0);
+        assert!(value::<bool>(&properties, "IsCharging"));
+        assert!(value::<bool>(&properties, "IsPresent"));
+        assert_eq!(value::<u32>(&properties, "State"), STATE_CHARGING);
+        assert_eq!(
+            value::<String>(&properties, "IconName"),
+            "battery-good-charging-symbolic"
+        );
+        assert_eq!(value::<u64>(&properties, "UpdateTime"), 1_000_000);
+    }
+
+    #[test]
+    fn only_changed_properties_are_signalled() {
+        let Some((_bus, service, mut client, test)) = start() else {
+            return;
+        };
+        read(&test, &service, 42, false);
+        assert_eq!(client.signal().0, "DeviceAdded");
+
+        // Same reading
+        read(&test, &service, 42, false);
+        read(&test, &service, 41, false);
+        assert_eq!(client.changed(), ["Percentage"]);
+
+        test.clock.advance(60);
+        read(&test, &service, 41, true);
+        assert_eq!(client.changed(), ["IsCharging"]);
+        assert_eq!(client.changed(), ["IconName"]);
+        assert_eq!(client.changed(), ["State"]);
+        assert_eq!(client.changed(), ["UpdateTime"]);
+
+        test.backend.set_asleep(PID);
+        test.monitor.update(&[PID]);
+        service.try_update(&test.monitor.devices()[&PID]).unwrap();
+        assert_eq!(client.changed(), ["IsPresent"]);
+
+        let properties = client.properties(&device_path(PID));
+        assert_eq!(value::<u32>(&properties, "State"), STATE_CHARGING);
+        assert!(!value::<bool>(&properties, "IsPresent"));
+    }
+
+    #[test]
+    fn removed_devices_are_signalled() {
+        let Some((_bus, service, mut client, test)) = start() else {
+            return;
+        };
+        read(&test, &service, 42, false);
+        assert_eq!(client.signal().0, "DeviceAdded");
+
+        service.try_remove(PID).unwrap();
+        // Not there anymore, so no second signal
+        service.try_remove(PID).unwrap();
+        assert!(client.devices().is_empty());
+        read(&test, &service, 42, false);
+
+        let (member, message) = client.signal();
+        assert_eq!(member, "DeviceRemoved");
+        assert_eq!(
+            message.body().deserialize::<OwnedObjectPath>().unwrap(),
+            device_path(PID)
+        );
+        assert_eq!(client.signal().0, "DeviceAdded");
+    }
+}
@@ -1,9 +1,48 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionType {
+    Wired,
+    Wireless,
+}
+
+impl ConnectionType {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionType::Wired => "wired",
+            ConnectionType::Wireless => "wireless",
+        }
+    }
+}
+
+impl fmt::Display for ConnectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ConnectionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wired" => Ok(ConnectionType::Wired),
+            "wireless" => Ok(ConnectionType::Wireless),
+            _ => Err(format!("Unknown connection type: {}", s)),
+        }
+    }
+}
+
 pub struct DeviceInfo {
     pub name: &'static str,
     pub pid: u16,
     pub interface: u8,
     pub usage_page: u16,
     pub usage: u16,
+    pub connection: ConnectionType,
     pub vid: u16,
 }
 
@@ -14,6 +53,7 @@ impl DeviceInfo {
         interface: u8,
         usage_page: u16,
         usage: u16,
+        connection: ConnectionType,
     ) -> Self {
         DeviceInfo {
             name,
@@ -21,6 +61,7 @@ impl DeviceInfo {
             interface,
             usage_page,
             usage,
+            connection,
             vid: 0x1532,
         }
     }
@@ -37,10 +78,22 @@ impl DeviceInfo {
     }
 }
 
-pub const RAZER_DEATHADDER_V3_PRO_WIRED: DeviceInfo =
-    DeviceInfo::new("Razer DeathAdder V3 Pro", 0x00B6, 0, 1, 2);
-pub const RAZER_DEATHADDER_V3_PRO_WIRELESS: DeviceInfo =
-    DeviceInfo::new("Razer DeathAdder V3 Pro", 0x00B7, 0, 1, 2);
+pub const RAZER_DEATHADDER_V3_PRO_WIRED: DeviceInfo = DeviceInfo::new(
+    "Razer DeathAdder V3 Pro",
+    0x00B6,
+    0,
+    1,
+    2,
+    ConnectionType::Wired,
+);
+pub const RAZER_DEATHADDER_V3_PRO_WIRELESS: DeviceInfo = DeviceInfo::new(
+    "Razer DeathAdder V3 Pro",
+    0x00B7,
+    0,
+    1,
+    2,
+    ConnectionType::Wireless,
+);
 
 pub const RAZER_DEVICE_LIST: [DeviceInfo; 2] = [
     RAZER_DEATHADDER_V3_PRO_WIRED,
@@ -0,0 +1,438 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+use crate::config::format_duration;
+use crate::history::{unix_timestamp, History, HistoryFilter, HistoryRecord};
+
+/// Readings further apart than this belong to different sessions (app closed,
+/// device unplugged or asleep).
+const SESSION_GAP: Duration = Duration::from_secs(30 * 60);
+/// How far back the per-device model looks.
+const MODEL_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Minimum number of raw level changes before a session's rate is trusted.
+const MIN_SESSION_STEPS: usize = 3;
+const RAW_MAX: f64 = 255.0;
+
+/// Consecutive readings of one device with the same charging state.
+pub struct Session<'a> {
+    pub is_charging: bool,
+    pub records: &'a [HistoryRecord],
+}
+
+impl Session<'_> {
+    pub fn duration(&self) -> Duration {
+        match (self.records.first(), self.records.last()) {
+            (Some(first), Some(last)) => {
+                Duration::from_secs(last.timestamp.saturating_sub(first.timestamp))
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Raw units per hour, positive while charging and negative while discharging.
+    ///
+    /// The device only reports 256 raw steps, so a reading can sit on the same
+    /// value for a long time. Measuring between the first and the last reading
+    /// where the value changed avoids counting those flat ends as zero slope.
+    pub fn rate(&self) -> Option<f64> {
+        let steps: Vec<&HistoryRecord> = self
+            .records
+            .windows(2)
+            .filter(|pair| pair[0].raw != pair[1].raw)
+            .map(|pair| &pair[1])
+            .collect();
+
+        if steps.len() < MIN_SESSION_STEPS {
+            return None;
+        }
+
+        let (first, last) = (steps[0], steps[steps.len() - 1]);
+        let hours = last.timestamp.saturating_sub(first.timestamp) as f64 / 3600.0;
+        if hours <= 0.0 {
+            return None;
+        }
+
+        Some((last.raw as f64 - first.raw as f64) / hours)
+    }
+}
+
+/// Splits chronological readings of a single device into sessions.
+pub fn split_sessions(records: &[HistoryRecord]) -> Vec<Session<'_>> {
+    let mut sessions = Vec::new();
+    let mut start = 0;
+
+    for i in 1..=records.len() {
+        let split = i == records.len()
+            || records[i].is_charging != records[i - 1].is_charging
+            || records[i]
+                .timestamp
+                .saturating_sub(records[i - 1].timestamp)
+                > SESSION_GAP.as_secs();
+
+        if split {
+            sessions.push(Session {
+                is_charging: records[start].is_charging,
+                records: &records[start..i],
+            });
+            start = i;
+        }
+    }
+
+    sessions
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Estimate {
+    TimeToEmpty(Duration),
+    TimeToFull(Duration),
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Estimate::TimeToEmpty(duration) => {
+                write!(f, "about {} left", format_duration(*duration))
+            }
+            Estimate::TimeToFull(duration) => {
+                write!(f, "about {} to full", format_duration(*duration))
+            }
+        }
+    }
+}
+
+/// Average charge and discharge rates of one device, weighted by session length.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DischargeModel {
+    pub discharge_rate: Option<f64>,
+    pub charge_rate: Option<f64>,
+}
+
+impl DischargeModel {
+    pub fn from_records(records: &[HistoryRecord]) -> Self {
+        let mut discharge = (0.0, 0.0);
+        let mut charge = (0.0, 0.0);
+
+        for session in split_sessions(records) {
+            let Some(rate) = session.rate() else {
+                continue;
+            };
+            let weight = session.duration().as_secs_f64();
+
+            if session.is_charging && rate > 0.0 {
+                charge.0 += rate * weight;
+                charge.1 += weight;
+            } else if !session.is_charging && rate < 0.0 {
+                discharge.0 += -rate * weight;
+                discharge.1 += weight;
+            }
+        }
+
+        let average = |(sum, weight): (f64, f64)| (weight > 0.0).then(|| sum / weight);
+        DischargeModel {
+            discharge_rate: average(discharge),
+            charge_rate: average(charge),
+        }
+    }
+
+    /// Estimates from the latest reading, preferring the rate of the ongoing
+    /// session over the long-term model once it has enough data.
+    pub fn estimate(&self, records: &[HistoryRecord]) -> Option<Estimate> {
+        let sessions = split_sessions(records);
+        let current = sessions.last()?;
+        let latest = current.records.last()?;
+        let current_rate = current.rate();
+
+        if latest.is_charging {
+            if latest.raw == u8::MAX {
+                return None;
+            }
+            let rate = current_rate
+                .filter(|rate| *rate > 0.0)
+                .or(self.charge_rate)?;
+            let hours = (RAW_MAX - latest.raw as f64) / rate;
+            Some(Estimate::TimeToFull(Duration::from_secs_f64(
+                hours * 3600.0,
+            )))
+        } else {
+            let rate = current_rate
+                .filter(|rate| *rate < 0.0)
+                .map(|rate| -rate)
+                .or(self.discharge_rate)?;
+            let hours = latest.raw as f64 / rate;
+            Some(Estimate::TimeToEmpty(Duration::from_secs_f64(
+                hours * 3600.0,
+            )))
+        }
+    }
+}
+
+/// Builds the device's model from recent history and estimates from its latest reading.
+pub fn estimate_device(
+    history: &History,
+    pid: u32,
+) -> Result<Option<Estimate>, Box<dyn std::error::Error>> {
+    let records = recent_records(history, pid, unix_timestamp())?;
+    Ok(DischargeModel::from_records(&records).estimate(&records))
+}
+
+fn recent_records(
+    history: &History,
+    pid: u32,
+    now: u64,
+) -> Result<Vec<HistoryRecord>, Box<dyn std::error::Error>> {
+    history.query(&HistoryFilter {
+        pid: Some(pid),
+        since: Some(now.saturating_sub(MODEL_WINDOW.as_secs())),
+        limit: None,
+    })
+}
+
+/// Recent readings per device, kept in memory so estimating doesn't read the
+/// whole history file with every reading.
+#[derive(Debug, Default)]
+pub struct Estimator {
+    records: HashMap<u32, Vec<HistoryRecord>>,
+}
+
+impl Estimator {
+    /// Adds a reading not yet in `history` and estimates from it. The device's
+    /// recent history is only read with its first reading.
+    pub fn add(
+        &mut self,
+        history: &History,
+        record: HistoryRecord,
+    ) -> Result<Option<Estimate>, Box<dyn std::error::Error>> {
+        let records = match self.records.entry(record.pid) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(recent_records(history, record.pid, record.timestamp)?)
+            }
+        };
+
+        let since = record.timestamp.saturating_sub(MODEL_WINDOW.as_secs());
+        let expired = records.partition_point(|record| record.timestamp < since);
+        records.drain(..expired);
+        records.push(record);
+
+        Ok(DischargeModel::from_records(records).estimate(records))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::ConnectionType;
+    use crate::history::testing::TempDir;
+
+    const HOUR: u64 = 3600;
+    /// Readings closer than `SESSION_GAP`, so they stay in one session.
+    const STEP: u64 = 600;
+    const START: u64 = 1_750_000_000;
+
+    /// Description, readings and the expected sessions' lengths and states.
+    type SessionCase = (&'static str, Vec<HistoryRecord>, &'static [(usize, bool)]);
+
+    fn record(timestamp: u64, raw: u8, is_charging: bool) -> HistoryRecord {
+        HistoryRecord {
+            timestamp,
+            pid: 0x00B7,
+            name: "DeathAdder".to_owned(),
+            level: (f64::from(raw) / RAW_MAX * 100.0).round() as i32,
+            raw,
+            is_charging,
+            connection: ConnectionType::Wireless,
+        }
+    }
+
+    /// One reading per `STEP` from `start`.
+    fn readings(start: u64, raws: &[u8], is_charging: bool) -> Vec<HistoryRecord> {
+        raws.iter()
+            .enumerate()
+            .map(|(i, &raw)| record(start + i as u64 * STEP, raw, is_charging))
+            .collect()
+    }
+
+    fn hours(estimate: Option<Estimate>) -> Option<f64> {
+        match estimate? {
+            Estimate::TimeToEmpty(duration) | Estimate::TimeToFull(duration) => {
+                Some(duration.as_secs_f64() / HOUR as f64)
+            }
+        }
+    }
+
+    fn assert_close(actual: Option<f64>, expected: Option<f64>, name: &str) {
+        match (actual, expected) {
+            (Some(actual), Some(expected)) => {
+                assert!((actual - expected).abs() < 1e-6, "{}: {}", name, actual)
+            }
+            _ => assert_eq!(actual, expected, "{}", name),
+        }
+    }
+
+    #[test]
+    fn sessions_split_on_gaps_and_charging_changes() {
+        let minutes = |offsets: &[u64], charging: &[bool]| -> Vec<HistoryRecord> {
+            offsets
+                .iter()
+                .zip(charging)
+                .map(|(&offset, &is_charging)| record(START + offset * 60, 100, is_charging))
+                .collect()
+        };
+
+        let cases: [SessionCase; 5] = [
+            ("empty", Vec::new(), &[]),
+            (
+                "continuous",
+                minutes(&[0, 10, 20, 30], &[false; 4]),
+                &[(4, false)],
+            ),
+            (
+                "gap of exactly 30 minutes",
+                minutes(&[0, 30, 60], &[false; 3]),
+                &[(3, false)],
+            ),
+            (
+                "longer gap",
+                minutes(&[0, 10, 41, 50], &[false; 4]),
+                &[(2, false), (2, false)],
+            ),
+            (
+                "charging starts and stops",
+                minutes(&[0, 5, 10, 15, 20], &[false, true, true, false, false]),
+                &[(1, false), (2, true), (2, false)],
+            ),
+        ];
+
+        for (name, records, expected) in cases {
+            let sessions: Vec<(usize, bool)> = split_sessions(&records)
+                .iter()
+                .map(|session| (session.records.len(), session.is_charging))
+                .collect();
+            assert_eq!(sessions, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rates_come_from_raw_steps() {
+        // Raw units per hour, with a reading every 10 minutes
+        let cases: [(&str, &[u8], bool, Option<f64>); 6] = [
+            ("steady discharge", &[200, 199, 198, 197], false, Some(-6.0)),
+            (
+                "flat ends are ignored",
+                &[200, 200, 200, 190, 180, 170, 170, 170],
+                false,
+                Some(-60.0),
+            ),
+            ("charging", &[100, 110, 120, 130], true, Some(60.0)),
+            ("too few steps", &[200, 199, 198, 198, 198], false, None),
+            ("no steps", &[200; 6], false, None),
+            ("single reading", &[200], false, None),
+        ];
+
+        for (name, raws, is_charging, expected) in cases {
+            let records = readings(START, raws, is_charging);
+            assert_close(split_sessions(&records)[0].rate(), expected, name);
+        }
+    }
+
+    #[test]
+    fn models_average_sessions_by_duration() {
+        // 60 raw per hour for 30 minutes, then 24 per hour for an hour, with a
+        // charge in between that is too short to count
+        let mut records = readings(START, &[200, 190, 180, 170], false);
+        records.extend(readings(START + 4 * STEP, &[170, 180], true));
+        records.extend(readings(
+            START + 6 * STEP,
+            &[170, 166, 162, 158, 154, 150, 146],
+            false,
+        ));
+
+        let model = DischargeModel::from_records(&records);
+        let expected = (60.0 * 1800.0 + 24.0 * 3600.0) / 5400.0;
+        assert_close(model.discharge_rate, Some(expected), "discharge");
+        assert_eq!(model.charge_rate, None);
+    }
+
+    #[test]
+    fn estimates_prefer_the_current_session() {
+        let model = DischargeModel {
+            discharge_rate: Some(5.0),
+            charge_rate: Some(50.0),
+        };
+
+        let cases: [(&str, Vec<HistoryRecord>, Option<f64>); 6] = [
+            (
+                "enough steps in the current session",
+                readings(START, &[130, 120, 110, 100], false),
+                Some(100.0 / 60.0),
+            ),
+            (
+                "falls back to the model",
+                readings(START, &[101, 100], false),
+                Some(20.0),
+            ),
+            (
+                "a rising level doesn't count as discharging",
+                readings(START, &[97, 98, 99, 100], false),
+                Some(20.0),
+            ),
+            ("charging", readings(START, &[5, 5], true), Some(5.0)),
+            ("full", readings(START, &[254, 255], true), None),
+            ("no readings", Vec::new(), None),
+        ];
+
+        for (name, records, expected) in cases {
+            assert_close(hours(model.estimate(&records)), expected, name);
+        }
+    }
+
+    #[test]
+    fn no_estimate_without_any_rate() {
+        let records = readings(START, &[101, 100], false);
+        assert_eq!(DischargeModel::default().estimate(&records), None);
+        assert!(DischargeModel::from_records(&records)
+            .estimate(&records)
+            .is_none());
+    }
+
+    #[test]
+    fn estimators_read_the_history_once() {
+        let dir = TempDir::new();
+        let path = dir.path().join("history.tsv");
+        let mut history = History::open(path.clone()).unwrap();
+        // A past session at 60 raw per hour
+        for record in readings(START, &[200, 190, 180, 170], false) {
+            history.append(&record).unwrap();
+        }
+
+        let mut estimator = Estimator::default();
+        let first = estimator
+            .add(&history, record(START + 10 * HOUR, 150, false))
+            .unwrap();
+        assert_close(hours(first), Some(2.5), "first");
+
+        // Later readings only use what is in memory
+        std::fs::remove_file(&path).unwrap();
+        let second = estimator
+            .add(&history, record(START + 10 * HOUR + STEP, 149, false))
+            .unwrap();
+        assert_close(hours(second), Some(149.0 / 60.0), "second");
+    }
+
+    #[test]
+    fn estimators_forget_old_readings() {
+        let dir = TempDir::new();
+        let history = History::open(dir.path().join("history.tsv")).unwrap();
+        let mut estimator = Estimator::default();
+
+        for record in readings(START, &[200, 190, 180, 170], false) {
+            estimator.add(&history, record).unwrap();
+        }
+        let later = START + MODEL_WINDOW.as_secs() + 10 * HOUR;
+        let estimate = estimator.add(&history, record(later, 150, false)).unwrap();
+
+        assert_eq!(estimate, None);
+        assert_eq!(estimator.records[&0x00B7].len(), 1);
+    }
+}
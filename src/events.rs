@@ -0,0 +1,332 @@
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+use crate::bus::{try_deliver, MonitorEvent, Subscriber, RECEIVER_CAPACITY};
+use crate::config::SharedConfig;
+use crate::history::unix_timestamp;
+use crate::hooks::HookRunner;
+use crate::monitor::BatteryMonitor;
+use crate::notify::{default_notifier, Notification, NotificationKind, Notifier};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Connected,
+    Disconnected,
+    ChargingStarted,
+    ChargingStopped,
+    ThresholdCrossed,
+    /// Every successful battery read. Hooks only get it when they list it.
+    ReadingUpdated,
+    /// A device that answered before stopped answering.
+    Asleep,
+}
+
+impl EventKind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Connected => "connected",
+            EventKind::Disconnected => "disconnected",
+            EventKind::ChargingStarted => "charging_started",
+            EventKind::ChargingStopped => "charging_stopped",
+            EventKind::ThresholdCrossed => "threshold_crossed",
+            EventKind::ReadingUpdated => "reading_updated",
+            EventKind::Asleep => "asleep",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceEvent {
+    pub event: EventKind,
+    pub timestamp: u64,
+    pub pid: u32,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_charging: Option<bool>,
+    /// The notification rule that fired, e.g. `low:15`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    #[serde(skip)]
+    pub notification: Option<NotificationKind>,
+}
+
+impl DeviceEvent {
+    pub fn new(event: EventKind, pid: u32, name: &str) -> Self {
+        Self {
+            event,
+            timestamp: unix_timestamp(),
+            pid,
+            name: name.to_owned(),
+            level: None,
+            is_charging: None,
+            rule: None,
+            notification: None,
+        }
+    }
+
+    fn with_reading(mut self, level: i32, is_charging: bool) -> Self {
+        self.level = Some(level);
+        self.is_charging = Some(is_charging);
+        self
+    }
+}
+
+/// Turns monitor events into device events and hands them to notifications,
+/// hooks and subscribers.
+pub struct EventDispatcher {
+    notifier: Box<dyn Notifier>,
+    hooks: HookRunner,
+    subscribers: Mutex<Vec<SyncSender<DeviceEvent>>>,
+}
+
+impl EventDispatcher {
+    pub fn new(config: SharedConfig) -> Self {
+        Self::with_notifier(config, default_notifier())
+    }
+
+    pub fn with_notifier(config: SharedConfig, notifier: Box<dyn Notifier>) -> Self {
+        Self {
+            notifier,
+            hooks: HookRunner::new(config),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Receives every event emitted from now on, until the receiver is dropped
+    /// or falls `RECEIVER_CAPACITY` events behind, like a stalled client.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = mpsc::sync_channel(RECEIVER_CAPACITY);
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    fn emit(&self, event: DeviceEvent, snoozed: bool) {
+        // Readings are logged as they are read
+        if event.event != EventKind::ReadingUpdated {
+            info!("Event {}: {}", event.event.as_str(), event.name);
+        }
+
+        if let Some(kind) = event.notification {
+            if snoozed {
+                info!("Notification suppressed (snoozed)");
+            } else {
+                let notification = Notification::new(kind, &event.name, event.level);
+                if let Err(err) = self.notifier.notify(&notification) {
+                    warn!("Failed to show notification: {}", err);
+                }
+            }
+        }
+
+        self.hooks.dispatch(&event);
+        self.subscribers
+            .lock()
+            .retain(|subscriber| try_deliver(subscriber, event.clone()));
+    }
+}
+
+impl Subscriber for EventDispatcher {
+    fn handle(&self, monitor: &BatteryMonitor, event: &MonitorEvent) {
+        let event = match *event {
+            MonitorEvent::DeviceConnected { pid, ref name } => {
+                let mut event = DeviceEvent::new(EventKind::Connected, pid, name);
+                event.notification = Some(NotificationKind::Connected);
+                event
+            }
+            MonitorEvent::DeviceRemoved { pid, ref name } => {
+                let mut event = DeviceEvent::new(EventKind::Disconnected, pid, name);
+                event.notification = Some(NotificationKind::Disconnected);
+                event
+            }
+            MonitorEvent::ReadingUpdated {
+                pid,
+                ref name,
+                timestamp,
+                level,
+                is_charging,
+                ..
+            } => {
+                let mut event = DeviceEvent::new(EventKind::ReadingUpdated, pid, name)
+                    .with_reading(level, is_charging);
+                event.timestamp = timestamp;
+                event
+            }
+            MonitorEvent::ChargingChanged {
+                pid,
+                ref name,
+                level,
+                is_charging,
+            } => {
+                let kind = if is_charging {
+                    EventKind::ChargingStarted
+                } else {
+                    EventKind::ChargingStopped
+                };
+                DeviceEvent::new(kind, pid, name).with_reading(level, is_charging)
+            }
+            MonitorEvent::ThresholdCrossed {
+                pid,
+                ref name,
+                level,
+                is_charging,
+                ref rule,
+                notification,
+            } => {
+                let mut event = DeviceEvent::new(EventKind::ThresholdCrossed, pid, name)
+                    .with_reading(level, is_charging);
+                event.rule = Some(rule.clone());
+                event.notification = Some(notification);
+                event
+            }
+            MonitorEvent::Error {
+                pid,
+                ref name,
+                was_available: true,
+            } => DeviceEvent::new(EventKind::Asleep, pid, name),
+            MonitorEvent::Error { .. } => return,
+            // Ends the streams of `watch` and `/events`
+            MonitorEvent::Stopped => {
+                self.subscribers.lock().clear();
+                return;
+            }
+        };
+
+        let snoozed = event.notification.is_some() && monitor.is_snoozed();
+        self.emit(event, snoozed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::RecordingNotifier;
+
+    fn dispatcher() -> (EventDispatcher, RecordingNotifier) {
+        let notifier = RecordingNotifier::default();
+        let dispatcher =
+            EventDispatcher::with_notifier(SharedConfig::default(), Box::new(notifier.clone()));
+        (dispatcher, notifier)
+    }
+
+    fn event(kind: EventKind, notification: NotificationKind, level: Option<i32>) -> DeviceEvent {
+        let mut event = DeviceEvent::new(kind, 0x00B7, "DeathAdder");
+        event.level = level;
+        event.notification = Some(notification);
+        event
+    }
+
+    #[test]
+    fn notifications_reach_the_notifier() {
+        let (dispatcher, notifier) = dispatcher();
+        let events = [
+            (EventKind::ThresholdCrossed, NotificationKind::Low, Some(15)),
+            (
+                EventKind::ThresholdCrossed,
+                NotificationKind::Critical,
+                Some(5),
+            ),
+            (
+                EventKind::ThresholdCrossed,
+                NotificationKind::Full,
+                Some(100),
+            ),
+            (EventKind::Connected, NotificationKind::Connected, None),
+            (
+                EventKind::Disconnected,
+                NotificationKind::Disconnected,
+                None,
+            ),
+        ];
+        for (kind, notification, level) in events {
+            dispatcher.emit(event(kind, notification, level), false);
+        }
+
+        let sent: Vec<_> = notifier
+            .take()
+            .iter()
+            .map(|notification| (notification.kind, notification.title(), notification.body()))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                (
+                    NotificationKind::Low,
+                    "Battery low",
+                    "DeathAdder: 15%".to_owned()
+                ),
+                (
+                    NotificationKind::Critical,
+                    "Battery critically low",
+                    "DeathAdder: 5%".to_owned()
+                ),
+                (
+                    NotificationKind::Full,
+                    "Battery fully charged",
+                    "DeathAdder: 100%".to_owned()
+                ),
+                (
+                    NotificationKind::Connected,
+                    "Device connected",
+                    "DeathAdder".to_owned()
+                ),
+                (
+                    NotificationKind::Disconnected,
+                    "Device disconnected",
+                    "DeathAdder".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn snoozed_notifications_are_dropped() {
+        let (dispatcher, notifier) = dispatcher();
+        let subscriber = dispatcher.subscribe();
+
+        dispatcher.emit(
+            event(EventKind::ThresholdCrossed, NotificationKind::Low, Some(15)),
+            true,
+        );
+
+        assert!(notifier.take().is_empty());
+        // Only the notification is skipped, not the event
+        assert_eq!(
+            subscriber.try_recv().unwrap().event,
+            EventKind::ThresholdCrossed
+        );
+    }
+
+    #[test]
+    fn readings_do_not_notify() {
+        let (dispatcher, notifier) = dispatcher();
+
+        dispatcher.emit(
+            DeviceEvent::new(EventKind::ReadingUpdated, 0x00B7, "DeathAdder")
+                .with_reading(80, false),
+            false,
+        );
+
+        assert!(notifier.take().is_empty());
+    }
+
+    #[test]
+    fn stalled_subscribers_are_dropped() {
+        let (dispatcher, _) = dispatcher();
+        let stalled = dispatcher.subscribe();
+
+        for _ in 0..=RECEIVER_CAPACITY {
+            dispatcher.emit(
+                DeviceEvent::new(EventKind::Connected, 0x00B7, "DeathAdder"),
+                false,
+            );
+        }
+
+        assert!(dispatcher.subscribers.lock().is_empty());
+        // Ends the stream once the queued events are read
+        assert_eq!(stalled.iter().count(), RECEIVER_CAPACITY);
+    }
+}
@@ -0,0 +1,462 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::devices::ConnectionType;
+
+const HISTORY_FILE_NAME: &str = "history.tsv";
+const MAX_RECORD_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60); // 90 days
+const MAX_RECORDS: usize = 100_000;
+const APPENDS_BETWEEN_PRUNES: usize = 1_000;
+
+/// Directory where the application keeps its persistent data.
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")))
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub timestamp: u64,
+    pub pid: u32,
+    pub name: String,
+    pub level: i32,
+    pub raw: u8,
+    pub is_charging: bool,
+    pub connection: ConnectionType,
+}
+
+impl HistoryRecord {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{:04X}\t{}\t{}\t{}\t{}\t{}",
+            self.timestamp,
+            self.pid,
+            self.connection,
+            self.level,
+            self.raw,
+            self.is_charging as u8,
+            self.name
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(7, '\t');
+
+        Some(HistoryRecord {
+            timestamp: fields.next()?.parse().ok()?,
+            pid: u32::from_str_radix(fields.next()?, 16).ok()?,
+            connection: fields.next()?.parse().ok()?,
+            level: fields.next()?.parse().ok()?,
+            raw: fields.next()?.parse().ok()?,
+            is_charging: fields.next()? == "1",
+            name: fields.next()?.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryFilter {
+    pub pid: Option<u32>,
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, record: &HistoryRecord) -> bool {
+        self.pid.is_none_or(|pid| record.pid == pid)
+            && self.since.is_none_or(|since| record.timestamp >= since)
+    }
+}
+
+/// Append-only store of battery readings, one tab-separated record per line.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    appends_since_prune: usize,
+}
+
+impl History {
+    pub fn open_default() -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = data_dir().ok_or("Failed to determine user data directory")?;
+        fs::create_dir_all(&dir)?;
+        Self::open(dir.join(HISTORY_FILE_NAME))
+    }
+
+    pub fn open(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut history = History {
+            path,
+            appends_since_prune: 0,
+        };
+        history.prune()?;
+        Ok(history)
+    }
+
+    pub fn append(&mut self, record: &HistoryRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", record.to_line())?;
+
+        self.appends_since_prune += 1;
+        if self.appends_since_prune >= APPENDS_BETWEEN_PRUNES {
+            self.prune()?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure every record appended so far is on disk.
+    pub fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match OpenOptions::new().append(true).open(&self.path) {
+            Ok(file) => Ok(file.sync_all()?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns matching records in chronological order. With a limit, only the
+    /// most recent records are kept.
+    pub fn query(
+        &self,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<HistoryRecord>, Box<dyn std::error::Error>> {
+        let mut records: Vec<HistoryRecord> = self
+            .read_all()?
+            .into_iter()
+            .filter(|record| filter.matches(record))
+            .collect();
+
+        if let Some(limit) = filter.limit {
+            let skip = records.len().saturating_sub(limit);
+            records.drain(..skip);
+        }
+
+        Ok(records)
+    }
+
+    /// Drops records older than the retention period and caps the total count.
+    pub fn prune(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let min_timestamp = unix_timestamp().saturating_sub(MAX_RECORD_AGE.as_secs());
+        self.prune_to(min_timestamp, MAX_RECORDS)
+    }
+
+    fn prune_to(
+        &mut self,
+        min_timestamp: u64,
+        max_records: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.appends_since_prune = 0;
+
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let lines: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()?;
+
+        let mut kept: Vec<&String> = lines
+            .iter()
+            .filter(|line| {
+                HistoryRecord::from_line(line)
+                    .is_some_and(|record| record.timestamp >= min_timestamp)
+            })
+            .collect();
+
+        let skip = kept.len().saturating_sub(max_records);
+        kept.drain(..skip);
+
+        if kept.len() == lines.len() {
+            return Ok(());
+        }
+
+        let tmp_path = self.path.with_extension("tsv.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for line in &kept {
+                writeln!(writer, "{}", line)?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        info!(
+            "Pruned {} history records from {}",
+            lines.len() - kept.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    fn read_all(&self) -> Result<Vec<HistoryRecord>, Box<dyn std::error::Error>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match HistoryRecord::from_line(&line) {
+                Some(record) => records.push(record),
+                None => warn!("Skipping malformed history record: {:?}", line),
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+/// Scratch directories for tests that write files.
+#[cfg(test)]
+pub mod testing {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An empty directory of its own, removed when dropped.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "{}-test-{}-{}",
+                env!("CARGO_PKG_NAME"),
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::TempDir;
+    use super::*;
+
+    const NOW: u64 = 1_750_000_000;
+
+    fn record(timestamp: u64, pid: u32) -> HistoryRecord {
+        HistoryRecord {
+            timestamp,
+            pid,
+            name: "Razer DeathAdder V3 Pro".to_owned(),
+            level: 50,
+            raw: 128,
+            is_charging: pid.is_multiple_of(2),
+            connection: ConnectionType::Wireless,
+        }
+    }
+
+    fn timestamps(records: &[HistoryRecord]) -> Vec<u64> {
+        records.iter().map(|record| record.timestamp).collect()
+    }
+
+    fn history(dir: &TempDir, records: &[HistoryRecord]) -> History {
+        let mut history = History::open(dir.path().join(HISTORY_FILE_NAME)).unwrap();
+        for record in records {
+            history.append(record).unwrap();
+        }
+        history
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let dir = TempDir::new();
+        let history = history(&dir, &[record(NOW, 0x00B7), record(NOW + 1, 0x00B8)]);
+
+        let records = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(records.len(), 2);
+        let (first, second) = (&records[0], &records[1]);
+        assert_eq!(first.to_line(), record(NOW, 0x00B7).to_line());
+        assert_eq!(second.to_line(), record(NOW + 1, 0x00B8).to_line());
+        assert!(!first.is_charging && second.is_charging);
+        assert_eq!(first.name, "Razer DeathAdder V3 Pro");
+    }
+
+    #[test]
+    fn queries_filter_by_device_time_and_count() {
+        let dir = TempDir::new();
+        let records: Vec<HistoryRecord> = (0..6)
+            .map(|i| record(NOW + i, if i.is_multiple_of(2) { 0x00B7 } else { 0x00B8 }))
+            .collect();
+        let history = history(&dir, &records);
+
+        let cases = [
+            (
+                "everything",
+                HistoryFilter::default(),
+                vec![0, 1, 2, 3, 4, 5],
+            ),
+            (
+                "device",
+                HistoryFilter {
+                    pid: Some(0x00B7),
+                    ..Default::default()
+                },
+                vec![0, 2, 4],
+            ),
+            (
+                "since",
+                HistoryFilter {
+                    since: Some(NOW + 4),
+                    ..Default::default()
+                },
+                vec![4, 5],
+            ),
+            (
+                "limit keeps the latest",
+                HistoryFilter {
+                    limit: Some(2),
+                    ..Default::default()
+                },
+                vec![4, 5],
+            ),
+            (
+                "all together",
+                HistoryFilter {
+                    pid: Some(0x00B8),
+                    since: Some(NOW + 1),
+                    limit: Some(2),
+                },
+                vec![3, 5],
+            ),
+            (
+                "unknown device",
+                HistoryFilter {
+                    pid: Some(0x0001),
+                    ..Default::default()
+                },
+                vec![],
+            ),
+        ];
+
+        for (name, filter, expected) in cases {
+            let expected: Vec<u64> = expected.into_iter().map(|i| NOW + i).collect();
+            assert_eq!(
+                timestamps(&history.query(&filter).unwrap()),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn missing_files_are_empty() {
+        let dir = TempDir::new();
+        let history = History::open(dir.path().join(HISTORY_FILE_NAME)).unwrap();
+
+        assert!(history.query(&HistoryFilter::default()).unwrap().is_empty());
+        history.flush().unwrap();
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let dir = TempDir::new();
+        let mut history = history(&dir, &[record(NOW, 0x00B7)]);
+        let mut file = OpenOptions::new().append(true).open(&history.path).unwrap();
+        writeln!(file, "not a record").unwrap();
+        writeln!(file, "{}\tXYZ\tWireless\t50\t128\t0\tMouse", NOW + 1).unwrap();
+        history.append(&record(NOW + 2, 0x00B7)).unwrap();
+        history.flush().unwrap();
+
+        let records = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(timestamps(&records), [NOW, NOW + 2]);
+
+        // Pruning drops them from the file too
+        history.prune_to(0, MAX_RECORDS).unwrap();
+        let contents = fs::read_to_string(&history.path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+    }
+
+    #[test]
+    fn pruning_drops_old_records() {
+        let dir = TempDir::new();
+        let mut history = history(
+            &dir,
+            &[NOW - 2, NOW - 1, NOW, NOW + 1].map(|ts| record(ts, 1)),
+        );
+
+        history.prune_to(NOW, MAX_RECORDS).unwrap();
+        let records = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(timestamps(&records), [NOW, NOW + 1]);
+    }
+
+    #[test]
+    fn pruning_caps_the_record_count() {
+        let dir = TempDir::new();
+        let records: Vec<HistoryRecord> = (0..5).map(|i| record(NOW + i, 1)).collect();
+        let mut history = history(&dir, &records);
+
+        history.prune_to(0, 3).unwrap();
+        let records = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(timestamps(&records), [NOW + 2, NOW + 3, NOW + 4]);
+        assert!(!history.path.with_extension("tsv.tmp").exists());
+    }
+
+    #[test]
+    fn opening_prunes_records_past_the_retention_period() {
+        let dir = TempDir::new();
+        let now = unix_timestamp();
+        let old = now - MAX_RECORD_AGE.as_secs() - 60;
+        drop(history(&dir, &[record(old, 1), record(now, 1)]));
+
+        let history = History::open(dir.path().join(HISTORY_FILE_NAME)).unwrap();
+        let records = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(timestamps(&records), [now]);
+    }
+
+    #[test]
+    fn appends_prune_periodically() {
+        let dir = TempDir::new();
+        let mut history = history(&dir, &[]);
+        let old = unix_timestamp() - MAX_RECORD_AGE.as_secs() - 60;
+
+        for _ in 0..APPENDS_BETWEEN_PRUNES - 1 {
+            history.append(&record(old, 1)).unwrap();
+        }
+        assert_eq!(history.appends_since_prune, APPENDS_BETWEEN_PRUNES - 1);
+        history.append(&record(unix_timestamp(), 1)).unwrap();
+
+        assert_eq!(history.appends_since_prune, 0);
+        assert_eq!(history.query(&HistoryFilter::default()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn flushing_keeps_records() {
+        let dir = TempDir::new();
+        let history = history(&dir, &[record(NOW, 1)]);
+
+        history.flush().unwrap();
+        let contents = fs::read_to_string(&history.path).unwrap();
+        assert_eq!(contents, format!("{}\n", record(NOW, 1).to_line()));
+    }
+}
@@ -0,0 +1,293 @@
+use log::{info, warn};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{HookConfig, SharedConfig};
+use crate::events::{DeviceEvent, EventKind};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Commands still running after this are killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the user's commands and webhooks for device events.
+pub struct HookRunner {
+    config: SharedConfig,
+}
+
+impl HookRunner {
+    pub fn new(config: SharedConfig) -> Self {
+        Self { config }
+    }
+
+    /// Starts every hook subscribed to the event on its own thread, so a slow
+    /// command or server never delays polling.
+    pub fn dispatch(&self, event: &DeviceEvent) {
+        for hook in &self.config.get().hooks {
+            if !is_subscribed(hook, event.event) {
+                continue;
+            }
+
+            let hook = hook.clone();
+            let event = event.clone();
+            thread::spawn(move || run_hook(&hook, &event, COMMAND_TIMEOUT));
+        }
+    }
+}
+
+fn is_subscribed(hook: &HookConfig, kind: EventKind) -> bool {
+    // Readings are too frequent to send to every hook by default
+    if hook.events.is_empty() {
+        kind != EventKind::ReadingUpdated
+    } else {
+        hook.events.contains(&kind)
+    }
+}
+
+/// Runs the command and posts the webhook, each regardless of the other.
+fn run_hook(hook: &HookConfig, event: &DeviceEvent, command_timeout: Duration) {
+    let kind = event.event.as_str();
+    let payload = match serde_json::to_string(event) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("Failed to serialize {} event: {}", kind, err);
+            return;
+        }
+    };
+
+    if let Some(command) = &hook.command {
+        if let Err(err) = run_command(command, event, &payload, command_timeout) {
+            warn!("Hook command for {} event failed: {}", kind, err);
+        }
+    }
+
+    if let Some(url) = &hook.url {
+        if let Err(err) = post_webhook(url, &payload) {
+            warn!("Webhook for {} event failed: {}", kind, err);
+        }
+    }
+}
+
+/// Runs the command through the system shell with the event in environment
+/// variables and as JSON on stdin.
+fn run_command(
+    command: &str,
+    event: &DeviceEvent,
+    payload: &str,
+    timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut process = shell_command(command);
+    process
+        .env("RBR_EVENT", event.event.as_str())
+        .env("RBR_TIMESTAMP", event.timestamp.to_string())
+        .env("RBR_DEVICE_PID", format!("0x{:04X}", event.pid))
+        .env("RBR_DEVICE_NAME", &event.name)
+        .stdin(Stdio::piped());
+
+    if let Some(level) = event.level {
+        process.env("RBR_LEVEL", level.to_string());
+    }
+    if let Some(is_charging) = event.is_charging {
+        process.env("RBR_CHARGING", if is_charging { "1" } else { "0" });
+    }
+    if let Some(rule) = &event.rule {
+        process.env("RBR_RULE", rule);
+    }
+
+    let mut child = process.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may exit without reading stdin
+        let _ = stdin.write_all(payload.as_bytes());
+    }
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("`{}` killed after {} s", command, timeout.as_secs()).into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    if !status.success() {
+        return Err(format!("`{}` exited with {}", command, status).into());
+    }
+
+    info!("Hook command `{}` finished", command);
+    Ok(())
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    let mut process = Command::new("cmd");
+    process
+        .arg("/C")
+        .arg(command)
+        .creation_flags(CREATE_NO_WINDOW);
+    process
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut process = Command::new("sh");
+    process.arg("-c").arg(command);
+    process
+}
+
+fn post_webhook(url: &str, payload: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let response = ureq::post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(payload)?;
+
+    info!("Webhook {} answered {}", url, response.status());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn event() -> DeviceEvent {
+        let mut event = DeviceEvent::new(EventKind::ThresholdCrossed, 0x00B7, "DeathAdder");
+        event.level = Some(15);
+        event.is_charging = Some(false);
+        event.rule = Some("low:15".to_owned());
+        event
+    }
+
+    fn hook(command: Option<String>, url: Option<String>) -> HookConfig {
+        HookConfig {
+            events: Vec::new(),
+            command,
+            url,
+        }
+    }
+
+    /// Answers one request and returns its request line and body.
+    fn webhook_server() -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+
+            let body = String::from_utf8(body).unwrap();
+            let _ = sender.send((request_line.trim_end().to_owned(), body));
+        });
+        (url, receiver)
+    }
+
+    #[test]
+    fn readings_only_reach_hooks_that_list_them() {
+        let all = hook(None, None);
+        let listed = HookConfig {
+            events: vec![EventKind::ReadingUpdated, EventKind::Asleep],
+            ..hook(None, None)
+        };
+
+        let cases = [
+            (EventKind::Connected, true, false),
+            (EventKind::ThresholdCrossed, true, false),
+            (EventKind::Asleep, true, true),
+            (EventKind::ReadingUpdated, false, true),
+        ];
+        for (kind, to_all, to_listed) in cases {
+            assert_eq!(is_subscribed(&all, kind), to_all, "{:?}", kind);
+            assert_eq!(is_subscribed(&listed, kind), to_listed, "{:?}", kind);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_get_the_event() {
+        let path = std::env::temp_dir().join(format!("rbr-hook-{}", std::process::id()));
+        let event = event();
+        let command = format!(
+            "cat > {0}; echo >> {0}; echo \"$RBR_EVENT $RBR_DEVICE_PID $RBR_DEVICE_NAME $RBR_LEVEL $RBR_CHARGING $RBR_RULE\" >> {0}",
+            path.display()
+        );
+
+        run_hook(&hook(Some(command), None), &event, COMMAND_TIMEOUT);
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        let (payload, variables) = output.split_once('\n').unwrap();
+        assert_eq!(payload, serde_json::to_string(&event).unwrap());
+        assert_eq!(
+            variables,
+            "threshold_crossed 0x00B7 DeathAdder 15 0 low:15\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hanging_commands_are_killed() {
+        let started = Instant::now();
+        let result = run_command("sleep 30", &event(), "{}", Duration::from_millis(200));
+
+        assert!(result.unwrap_err().to_string().contains("killed"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn webhooks_get_the_event_as_json() {
+        let (url, requests) = webhook_server();
+        let event = event();
+
+        run_hook(&hook(None, Some(url)), &event, COMMAND_TIMEOUT);
+
+        let (request_line, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(request_line, "POST /hook HTTP/1.1");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body, serde_json::to_value(&event).unwrap());
+        assert_eq!(body["rule"], "low:15");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn webhooks_are_sent_when_the_command_fails() {
+        let (url, requests) = webhook_server();
+
+        run_hook(
+            &hook(Some("exit 1".to_owned()), Some(url)),
+            &event(),
+            COMMAND_TIMEOUT,
+        );
+
+        assert!(requests.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
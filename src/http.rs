@@ -0,0 +1,446 @@
+//! Optional HTTP API for dashboards and widgets, serving the same state as the
+//! IPC endpoint as JSON, device events as a server-sent events stream and
+//! Prometheus metrics.
+
+use log::{error, info, trace, warn};
+use serde::Serialize;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::config::{parse_duration, parse_pid, HttpConfig};
+use crate::history::{unix_timestamp, HistoryFilter};
+use crate::metrics;
+use crate::monitor::{BatteryMonitor, MemoryDevice};
+
+/// How often an idle event stream gets a comment, which also notices clients
+/// that went away.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Requests handled at once, open event streams included. More get a 503.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Starts the server if it is enabled in the config.
+pub fn start(monitor: &Arc<BatteryMonitor>) {
+    let config = monitor.config();
+    if !config.http.enabled {
+        return;
+    }
+
+    if let Err(err) = spawn_server(&config.http, Arc::clone(monitor)) {
+        error!(
+            "Failed to start HTTP API on {}: {}",
+            config.http.address, err
+        );
+    }
+}
+
+/// Returns the address it listens on. Addresses other machines can reach
+/// are refused without a token.
+fn spawn_server(
+    config: &HttpConfig,
+    monitor: Arc<BatteryMonitor>,
+) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    // An empty token would be accepted from anyone
+    let token = config.token.clone().filter(|token| !token.is_empty());
+    let addresses: Vec<SocketAddr> = config.address.to_socket_addrs()?.collect();
+    if token.is_none() && !addresses.iter().all(|address| address.ip().is_loopback()) {
+        return Err("other machines can reach this address, so http.token must be set".into());
+    }
+
+    let server = Server::http(&addresses[..]).map_err(|err| err.to_string())?;
+    let address = server
+        .server_addr()
+        .to_ip()
+        .ok_or("Not listening on an IP address")?;
+    info!("HTTP API listening on http://{}", address);
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let Some(connection) = Connection::open(&connections) else {
+                respond(request, error_response(503, "Too many connections"));
+                continue;
+            };
+
+            let monitor = Arc::clone(&monitor);
+            let token = token.clone();
+            // Event streams stay open, so every request gets its own thread
+            thread::spawn(move || {
+                handle_request(request, &monitor, token.as_deref());
+                drop(connection);
+            });
+        }
+    });
+    Ok(address)
+}
+
+/// Counts as one of the `MAX_CONNECTIONS` until dropped.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn open(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < MAX_CONNECTIONS).then_some(open + 1)
+            })
+            .ok()?;
+        Some(Self(Arc::clone(connections)))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_request(request: Request, monitor: &BatteryMonitor, token: Option<&str>) {
+    trace!("HTTP {} {}", request.method(), request.url());
+
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    if let Some(token) = token {
+        if !is_authorized(&request, &params, token) {
+            respond(request, error_response(401, "Missing or invalid token"));
+            return;
+        }
+    }
+
+    if *request.method() != Method::Get {
+        respond(
+            request,
+            error_response(405, "Only GET requests are supported"),
+        );
+        return;
+    }
+
+    let response = match path.trim_end_matches('/') {
+        "/devices" => json_response(200, &monitor.status()),
+        "/metrics" => Response::from_data(metrics::render(monitor).into_bytes())
+            .with_header(content_type("text/plain; version=0.0.4")),
+        "/history" => match parse_filter(&params) {
+            Ok(filter) => match monitor.history(&filter) {
+                Ok(records) => json_response(200, &records),
+                Err(err) => error_response(500, &err),
+            },
+            Err(err) => error_response(400, &err),
+        },
+        "/events" => {
+            stream_events(request, monitor);
+            return;
+        }
+        path => match path.strip_prefix("/devices/").map(parse_pid) {
+            Some(Ok(pid)) => match monitor.devices().get(&pid).map(MemoryDevice::status) {
+                Some(device) => json_response(200, &device),
+                None => error_response(404, "No such device"),
+            },
+            Some(Err(err)) => error_response(400, &err),
+            None => error_response(404, "Not found"),
+        },
+    };
+
+    respond(request, response);
+}
+
+/// Accepts the token as `Authorization: Bearer <token>`, or as a `token` query
+/// parameter for clients like `EventSource` that can't set headers.
+fn is_authorized(request: &Request, params: &[(String, String)], token: &str) -> bool {
+    let header = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "));
+    let param = params
+        .iter()
+        .find(|(key, _)| key == "token")
+        .map(|(_, value)| value.as_str());
+
+    header
+        .or(param)
+        .is_some_and(|given| constant_time_eq(given, token))
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The `device`, `since` and `limit` parameters, same as the `history` command.
+fn parse_filter(params: &[(String, String)]) -> Result<HistoryFilter, String> {
+    let mut filter = HistoryFilter::default();
+
+    for (key, value) in params {
+        match key.as_str() {
+            "device" => filter.pid = Some(parse_pid(value)?),
+            "since" => {
+                let duration = parse_duration(value)?;
+                filter.since = Some(unix_timestamp().saturating_sub(duration.as_secs()));
+            }
+            "limit" => {
+                filter.limit = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid limit: {}", value))?,
+                );
+            }
+            _ => (),
+        }
+    }
+
+    Ok(filter)
+}
+
+/// Sends every device event as it happens until the client disconnects.
+fn stream_events(request: Request, monitor: &BatteryMonitor) {
+    let events = monitor.events().subscribe();
+    let mut writer = request.into_writer();
+
+    let mut send = |message: &str| -> std::io::Result<()> {
+        writer.write_all(message.as_bytes())?;
+        writer.flush()
+    };
+
+    // The body lasts until the connection closes, so it has no length
+    let header = "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\r\n";
+    if send(header).is_err() {
+        return;
+    }
+    trace!("Event stream opened");
+
+    loop {
+        let message = match events.recv_timeout(KEEP_ALIVE_INTERVAL) {
+            Ok(event) => match serde_json::to_string(&event) {
+                Ok(data) => format!("event: {}\ndata: {}\n\n", event.event.as_str(), data),
+                Err(err) => {
+                    warn!("Failed to serialize event: {}", err);
+                    continue;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_owned(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if send(&message).is_err() {
+            trace!("Event stream closed");
+            return;
+        }
+    }
+}
+
+fn json_response<T: Serialize>(status: u16, value: &T) -> Response<std::io::Cursor<Vec<u8>>> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::from_data(body)
+            .with_status_code(status)
+            .with_header(content_type("application/json")),
+        Err(err) => error_response(500, &err.to_string()),
+    }
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    let body = serde_json::json!({ "error": message }).to_string();
+    Response::from_data(body.into_bytes())
+        .with_status_code(status)
+        .with_header(content_type("application/json"))
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).expect("valid header")
+}
+
+fn respond(request: Request, response: Response<std::io::Cursor<Vec<u8>>>) {
+    if let Err(err) = request.respond(response) {
+        warn!("Failed to send HTTP response: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::monitor::testing::{self, TestMonitor};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+
+    const PID: u32 = 0x00B7;
+    const NAME: &str = "Razer DeathAdder V3 Pro";
+
+    fn config(address: &str, token: Option<&str>) -> HttpConfig {
+        HttpConfig {
+            enabled: true,
+            address: address.to_owned(),
+            token: token.map(str::to_owned),
+        }
+    }
+
+    /// A server on an ephemeral port, with one device read at 80%.
+    fn server(token: Option<&str>) -> (TestMonitor, String) {
+        let test = testing::monitor(Config::default());
+        test.backend.connect(PID, NAME);
+        test.backend.set_level(PID, 80, false);
+        test.monitor.fetch_devices();
+        test.monitor.update(&[PID]);
+
+        let address = spawn_server(&config("127.0.0.1:0", token), test.monitor.clone()).unwrap();
+        (test, format!("http://{}", address))
+    }
+
+    fn call(request: ureq::Request, token: Option<&str>) -> (u16, String) {
+        let request = match token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        };
+        let response = match request.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => panic!("{}", err),
+        };
+        (response.status(), response.into_string().unwrap())
+    }
+
+    fn get(url: &str) -> (u16, String) {
+        call(ureq::get(url), None)
+    }
+
+    fn json(body: &str) -> serde_json::Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    /// Opens `/events` and reads up to the end of the response header.
+    fn open_stream(url: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        reader
+    }
+
+    #[test]
+    fn token_is_required_when_set() {
+        let (_test, url) = server(Some("secret"));
+        let devices = format!("{}/devices", url);
+
+        assert_eq!(get(&devices).0, 401);
+        assert_eq!(call(ureq::get(&devices), Some("wrong")).0, 401);
+        assert_eq!(get(&format!("{}?token=wrong", devices)).0, 401);
+        assert_eq!(call(ureq::get(&devices), Some("secret")).0, 200);
+        assert_eq!(get(&format!("{}?token=secret", devices)).0, 200);
+    }
+
+    #[test]
+    fn no_token_needed_on_loopback_without_one() {
+        let (_test, url) = server(None);
+        assert_eq!(get(&format!("{}/devices", url)).0, 200);
+    }
+
+    #[test]
+    fn other_addresses_need_a_token() {
+        let test = testing::monitor(Config::default());
+        let spawn = |token| spawn_server(&config("0.0.0.0:0", token), test.monitor.clone());
+
+        assert!(spawn(None).is_err());
+        assert!(spawn(Some("")).is_err());
+        assert!(spawn(Some("secret")).is_ok());
+    }
+
+    #[test]
+    fn devices_are_served_as_json() {
+        let (test, url) = server(None);
+
+        let (status, body) = get(&format!("{}/devices", url));
+        assert_eq!(status, 200);
+        assert_eq!(
+            json(&body),
+            serde_json::to_value(test.monitor.status()).unwrap()
+        );
+
+        let (status, body) = get(&format!("{}/devices/0x00B7/", url));
+        assert_eq!(status, 200);
+        let device = json(&body);
+        assert_eq!(device["name"], NAME);
+        assert_eq!(device["level"], 80);
+    }
+
+    #[test]
+    fn unknown_paths_and_methods_are_errors() {
+        let (_test, url) = server(None);
+
+        assert_eq!(get(&format!("{}/devices/0x0001", url)).0, 404);
+        assert_eq!(get(&format!("{}/devices/mouse", url)).0, 400);
+        assert_eq!(get(&format!("{}/battery", url)).0, 404);
+        assert_eq!(get(&format!("{}/history?limit=many", url)).0, 400);
+
+        let (status, body) = call(ureq::post(&format!("{}/devices", url)), None);
+        assert_eq!(status, 405);
+        assert_eq!(json(&body)["error"], "Only GET requests are supported");
+    }
+
+    #[test]
+    fn metrics_are_served() {
+        let (_test, url) = server(None);
+
+        let (status, body) = get(&format!("{}/metrics", url));
+        assert_eq!(status, 200);
+        assert!(body.contains("razer_battery_percent{"), "{}", body);
+    }
+
+    #[test]
+    fn events_are_streamed() {
+        let (test, url) = server(None);
+        let mut stream = open_stream(&url);
+
+        test.backend.disconnect(PID);
+        test.monitor.fetch_devices();
+
+        let mut event = String::new();
+        stream.read_line(&mut event).unwrap();
+        assert_eq!(event, "event: disconnected\n");
+        let mut data = String::new();
+        stream.read_line(&mut data).unwrap();
+        let data = json(data.strip_prefix("data: ").unwrap());
+        assert_eq!(data["pid"], PID);
+        assert_eq!(data["name"], NAME);
+    }
+
+    #[test]
+    fn connections_are_limited() {
+        let (_test, url) = server(None);
+        let devices = format!("{}/devices", url);
+
+        // Finished requests don't count
+        for _ in 0..MAX_CONNECTIONS + 1 {
+            assert_eq!(get(&devices).0, 200);
+        }
+
+        let _streams: Vec<_> = (0..MAX_CONNECTIONS).map(|_| open_stream(&url)).collect();
+        let (status, body) = get(&devices);
+        assert_eq!(status, 503);
+        assert_eq!(json(&body)["error"], "Too many connections");
+    }
+}
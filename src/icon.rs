@@ -0,0 +1,590 @@
+use serde::{Deserialize, Deserializer};
+
+#[cfg(any(feature = "tray", target_os = "linux"))]
+pub use render::*;
+
+/// Range of `icon.size`, from the smallest the number styles fit in.
+const ICON_SIZES: std::ops::RangeInclusive<u32> = 8..=256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconColor {
+    White,
+    Yellow,
+    Red,
+    /// Any other color, tinted from the white icon.
+    Rgb([u8; 3]),
+}
+
+impl<'de> Deserialize<'de> for IconColor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        match value.as_str() {
+            "white" => Ok(IconColor::White),
+            "yellow" => Ok(IconColor::Yellow),
+            "red" => Ok(IconColor::Red),
+            _ => value
+                .strip_prefix('#')
+                .filter(|hex| hex.len() == 6)
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .map(|rgb| {
+                    let [_, r, g, b] = rgb.to_be_bytes();
+                    IconColor::Rgb([r, g, b])
+                })
+                .ok_or_else(|| {
+                    serde::de::Error::custom(format!(
+                        "Invalid icon color {:?}, expected white, yellow, red or #RRGGBB",
+                        value
+                    ))
+                }),
+        }
+    }
+}
+
+/// Icon color used up to and including `level` percent.
+// Parsed without a tray too, so the same config file works with every build
+#[cfg_attr(not(any(feature = "tray", target_os = "linux")), allow(dead_code))]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IconBand {
+    pub level: i32,
+    pub color: IconColor,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IconStyle {
+    /// The mouse icon only.
+    #[default]
+    Icon,
+    /// The battery percentage only.
+    Number,
+    /// The mouse icon with the percentage on top.
+    Combined,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrayBackend {
+    /// The platform's tray, through GTK and libappindicator on Linux.
+    #[default]
+    Native,
+    /// StatusNotifierItem over D-Bus directly, for Wayland bars without GTK.
+    /// Linux only.
+    Sni,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IconConfig {
+    /// Applies after a restart.
+    pub backend: TrayBackend,
+    pub bands: Vec<IconBand>,
+    /// Color above every band.
+    pub color: IconColor,
+    pub style: IconStyle,
+    /// Draw a battery bar along the bottom edge with the number styles.
+    pub bar: bool,
+    /// Icon size in pixels, derived from the display scale if unset.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub size: Option<u32>,
+    /// Show an icon for every device instead of one for the lowest battery.
+    pub per_device: bool,
+}
+
+impl Default for IconConfig {
+    fn default() -> Self {
+        Self {
+            backend: TrayBackend::Native,
+            bands: vec![
+                IconBand {
+                    level: 15,
+                    color: IconColor::Red,
+                },
+                IconBand {
+                    level: 40,
+                    color: IconColor::Yellow,
+                },
+            ],
+            color: IconColor::White,
+            style: IconStyle::Icon,
+            bar: false,
+            size: None,
+            per_device: false,
+        }
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    match Option::<u32>::deserialize(deserializer)? {
+        Some(size) if !ICON_SIZES.contains(&size) => Err(serde::de::Error::custom(format!(
+            "Invalid icon size {}, expected {} to {} pixels",
+            size,
+            ICON_SIZES.start(),
+            ICON_SIZES.end()
+        ))),
+        size => Ok(size),
+    }
+}
+
+/// Drawing the icons, which only the tray backends need.
+#[cfg(any(feature = "tray", target_os = "linux"))]
+mod render {
+    use super::*;
+    use image::imageops::{self, FilterType};
+    use image::{Rgba, RgbaImage};
+    use std::sync::OnceLock;
+
+    const MOUSE_WHITE: &[u8] = include_bytes!("../assets/mouse_white.png");
+    const MOUSE_YELLOW: &[u8] = include_bytes!("../assets/mouse_yellow.png");
+    const MOUSE_RED: &[u8] = include_bytes!("../assets/mouse_red.png");
+
+    const BADGE_COLOR: Rgba<u8> = Rgba([0x4C, 0xAF, 0x50, 0xFF]);
+    const BOLT_COLOR: Rgba<u8> = Rgba([0xFF, 0xFF, 0xFF, 0xFF]);
+    const DISCONNECTED_GREY: u8 = 0x80;
+    const OUTLINE_COLOR: Rgba<u8> = Rgba([0x00, 0x00, 0x00, 0xC0]);
+    const BAR_BACKGROUND: Rgba<u8> = Rgba([0x40, 0x40, 0x40, 0xFF]);
+
+    /// 3x5 bitmap digits, one row per byte with the leftmost pixel in bit 2.
+    const DIGITS: [[u8; 5]; 10] = [
+        [0b111, 0b101, 0b101, 0b101, 0b111],
+        [0b010, 0b110, 0b010, 0b010, 0b111],
+        [0b111, 0b001, 0b111, 0b100, 0b111],
+        [0b111, 0b001, 0b111, 0b001, 0b111],
+        [0b101, 0b101, 0b111, 0b001, 0b001],
+        [0b111, 0b100, 0b111, 0b001, 0b111],
+        [0b111, 0b100, 0b111, 0b101, 0b111],
+        [0b111, 0b001, 0b001, 0b001, 0b001],
+        [0b111, 0b101, 0b111, 0b101, 0b111],
+        [0b111, 0b101, 0b111, 0b001, 0b111],
+    ];
+    const DIGIT_WIDTH: u32 = 3;
+    const DIGIT_HEIGHT: u32 = 5;
+
+    /// Lightning bolt outline in badge coordinates (-1..1).
+    const BOLT: [(f32, f32); 6] = [
+        (0.15, -0.75),
+        (-0.45, 0.1),
+        (-0.02, 0.1),
+        (-0.15, 0.75),
+        (0.45, -0.1),
+        (0.02, -0.1),
+    ];
+
+    impl IconColor {
+        /// Color of the bundled icon's outline.
+        fn rgba(&self) -> Rgba<u8> {
+            match self {
+                IconColor::White => Rgba([0xFF, 0xFF, 0xFF, 0xFF]),
+                IconColor::Yellow => Rgba([0xC6, 0x8C, 0x00, 0xFF]),
+                IconColor::Red => Rgba([0xF2, 0x3C, 0x34, 0xFF]),
+                IconColor::Rgb([r, g, b]) => Rgba([*r, *g, *b, 0xFF]),
+            }
+        }
+    }
+
+    impl IconConfig {
+        pub fn color_for(&self, level: i32) -> IconColor {
+            self.bands
+                .iter()
+                .filter(|band| level <= band.level)
+                .min_by_key(|band| band.level)
+                .map_or(self.color, |band| band.color)
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum IconState {
+        /// No device connected, or it doesn't answer (asleep).
+        Disconnected,
+        Battery {
+            level: i32,
+            is_charging: bool,
+        },
+    }
+
+    /// Renders the tray icon for a state at `size` x `size` pixels. Pure, so the
+    /// output only depends on the arguments.
+    pub fn render_icon(state: IconState, config: &IconConfig, size: u32) -> RgbaImage {
+        let (level, is_charging) = match state {
+            IconState::Disconnected => return disconnected_icon(size),
+            IconState::Battery { level, is_charging } => (level, is_charging),
+        };
+
+        let color = config.color_for(level);
+        let text_color = if is_charging {
+            BADGE_COLOR
+        } else {
+            color.rgba()
+        };
+        let bar_height = if config.bar { (size / 8).max(2) } else { 0 };
+
+        let mut image = match config.style {
+            IconStyle::Icon => {
+                let mut image = mouse_icon(color, size);
+                if is_charging {
+                    draw_charging_badge(&mut image);
+                }
+                return image;
+            }
+            IconStyle::Number => {
+                let mut image = RgbaImage::new(size, size);
+                draw_number(
+                    &mut image,
+                    level,
+                    text_color,
+                    0,
+                    size.saturating_sub(bar_height),
+                );
+                image
+            }
+            IconStyle::Combined => {
+                let mut image = mouse_icon(color, size);
+                let top = size / 2;
+                let height = size.saturating_sub(bar_height + top);
+                draw_number(&mut image, level, text_color, top, height);
+                image
+            }
+        };
+
+        if config.bar {
+            draw_bar(&mut image, level, color.rgba(), bar_height);
+        }
+
+        image
+    }
+
+    fn mouse_icon(color: IconColor, size: u32) -> RgbaImage {
+        let image = match color {
+            IconColor::Rgb(rgb) => tint(base_image(IconColor::White), rgb),
+            color => base_image(color).clone(),
+        };
+        imageops::resize(&image, size, size, FilterType::Lanczos3)
+    }
+
+    fn disconnected_icon(size: u32) -> RgbaImage {
+        let mut image = mouse_icon(IconColor::White, size);
+        for pixel in image.pixels_mut() {
+            let Rgba([_, _, _, alpha]) = *pixel;
+            *pixel = Rgba([
+                DISCONNECTED_GREY,
+                DISCONNECTED_GREY,
+                DISCONNECTED_GREY,
+                alpha / 2,
+            ]);
+        }
+        image
+    }
+
+    fn base_image(color: IconColor) -> &'static RgbaImage {
+        static WHITE: OnceLock<RgbaImage> = OnceLock::new();
+        static YELLOW: OnceLock<RgbaImage> = OnceLock::new();
+        static RED: OnceLock<RgbaImage> = OnceLock::new();
+
+        let (cell, bytes) = match color {
+            IconColor::Yellow => (&YELLOW, MOUSE_YELLOW),
+            IconColor::Red => (&RED, MOUSE_RED),
+            IconColor::White | IconColor::Rgb(_) => (&WHITE, MOUSE_WHITE),
+        };
+
+        cell.get_or_init(|| {
+            image::load_from_memory(bytes)
+                .expect("Failed to open icon")
+                .into_rgba8()
+        })
+    }
+
+    fn tint(image: &RgbaImage, rgb: [u8; 3]) -> RgbaImage {
+        let mut image = image.clone();
+        for pixel in image.pixels_mut() {
+            let Rgba([r, g, b, alpha]) = *pixel;
+            let scale = |channel: u8, tint: u8| (channel as u16 * tint as u16 / 255) as u8;
+            *pixel = Rgba([scale(r, rgb[0]), scale(g, rgb[1]), scale(b, rgb[2]), alpha]);
+        }
+        image
+    }
+
+    /// Draws the level with the largest whole pixel scale that fits the area
+    /// starting at row `top`, centered and outlined for contrast.
+    fn draw_number(image: &mut RgbaImage, level: i32, color: Rgba<u8>, top: u32, height: u32) {
+        let digits: Vec<usize> = level
+            .clamp(0, 100)
+            .to_string()
+            .bytes()
+            .map(|digit| (digit - b'0') as usize)
+            .collect();
+
+        let count = digits.len() as u32;
+        let text_width = count * DIGIT_WIDTH + count - 1;
+        // Leave a pixel for the outline on each side
+        let scale = ((image.width().saturating_sub(2)) / text_width)
+            .min(height.saturating_sub(2) / DIGIT_HEIGHT)
+            .max(1);
+
+        let left = image.width().saturating_sub(text_width * scale) / 2;
+        let top = top + height.saturating_sub(DIGIT_HEIGHT * scale) / 2;
+
+        let mut lit = Vec::new();
+        for (index, &digit) in digits.iter().enumerate() {
+            let digit_left = left + index as u32 * (DIGIT_WIDTH + 1) * scale;
+            for (row, bits) in DIGITS[digit].iter().enumerate() {
+                for column in 0..DIGIT_WIDTH {
+                    if bits & (1 << (DIGIT_WIDTH - 1 - column)) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            lit.push((
+                                digit_left + column * scale + dx,
+                                top + row as u32 * scale + dy,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        for &(x, y) in &lit {
+            for (nx, ny) in [
+                (x + 1, y),
+                (x, y + 1),
+                (x.wrapping_sub(1), y),
+                (x, y.wrapping_sub(1)),
+            ] {
+                if nx < image.width() && ny < image.height() {
+                    image.put_pixel(nx, ny, OUTLINE_COLOR);
+                }
+            }
+        }
+
+        for &(x, y) in &lit {
+            if x < image.width() && y < image.height() {
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Draws a bar filled up to the level along the bottom edge.
+    fn draw_bar(image: &mut RgbaImage, level: i32, color: Rgba<u8>, height: u32) {
+        let (width, size) = (image.width(), image.height());
+        let filled = (width as i64 * level.clamp(0, 100) as i64 / 100) as u32;
+
+        for y in size.saturating_sub(height)..size {
+            for x in 0..width {
+                let color = if x < filled { color } else { BAR_BACKGROUND };
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Draws a green circle with a lightning bolt in the bottom right corner.
+    fn draw_charging_badge(image: &mut RgbaImage) {
+        let size = image.width().min(image.height()) as f32;
+        let radius = size * 0.24;
+        let (cx, cy) = (size - radius - 1.0, size - radius - 1.0);
+
+        for y in (cy - radius).max(0.0) as u32..(cy + radius).ceil().min(size) as u32 {
+            for x in (cx - radius).max(0.0) as u32..(cx + radius).ceil().min(size) as u32 {
+                let dx = (x as f32 + 0.5 - cx) / radius;
+                let dy = (y as f32 + 0.5 - cy) / radius;
+
+                if dx * dx + dy * dy > 1.0 {
+                    continue;
+                }
+
+                let color = if inside_polygon(&BOLT, dx, dy) {
+                    BOLT_COLOR
+                } else {
+                    BADGE_COLOR
+                };
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    fn inside_polygon(polygon: &[(f32, f32)], x: f32, y: f32) -> bool {
+        let mut inside = false;
+        let mut j = polygon.len() - 1;
+
+        for i in 0..polygon.len() {
+            let (xi, yi) = polygon[i];
+            let (xj, yj) = polygon[j];
+            if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                inside = !inside;
+            }
+            j = i;
+        }
+
+        inside
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn config(style: IconStyle, bar: bool) -> IconConfig {
+            IconConfig {
+                style,
+                bar,
+                ..IconConfig::default()
+            }
+        }
+
+        fn battery(level: i32, is_charging: bool) -> IconState {
+            IconState::Battery { level, is_charging }
+        }
+
+        fn count(image: &RgbaImage, color: Rgba<u8>) -> usize {
+            image.pixels().filter(|&&pixel| pixel == color).count()
+        }
+
+        #[test]
+        fn every_style_renders_at_the_requested_size() {
+            for style in [IconStyle::Icon, IconStyle::Number, IconStyle::Combined] {
+                for bar in [false, true] {
+                    for size in [16, 32] {
+                        let config = config(style, bar);
+                        for state in [
+                            IconState::Disconnected,
+                            battery(5, false),
+                            battery(50, true),
+                            battery(100, false),
+                        ] {
+                            let image = render_icon(state, &config, size);
+                            assert_eq!(image.dimensions(), (size, size), "{:?} {:?}", style, state);
+                            assert_eq!(image, render_icon(state, &config, size));
+                        }
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn icon_style_uses_the_band_color_and_badge_while_charging() {
+            let config = config(IconStyle::Icon, false);
+
+            assert_eq!(
+                render_icon(battery(10, false), &config, 32),
+                mouse_icon(IconColor::Red, 32)
+            );
+            assert_eq!(
+                render_icon(battery(30, false), &config, 32),
+                mouse_icon(IconColor::Yellow, 32)
+            );
+            assert_eq!(
+                render_icon(battery(80, false), &config, 32),
+                mouse_icon(IconColor::White, 32)
+            );
+
+            // Below the bolt, inside the badge in the bottom right corner
+            let charging = render_icon(battery(80, true), &config, 32);
+            assert_eq!(*charging.get_pixel(23, 16), BADGE_COLOR);
+            assert!(count(&charging, BOLT_COLOR) > 0);
+            assert_ne!(
+                *render_icon(battery(80, false), &config, 32).get_pixel(23, 16),
+                BADGE_COLOR
+            );
+        }
+
+        #[test]
+        fn number_style_draws_the_level() {
+            let config = config(IconStyle::Number, false);
+            let red = IconColor::Red.rgba();
+
+            let low = render_icon(battery(5, false), &config, 32);
+            assert!(count(&low, red) > 0);
+            assert_eq!(count(&low, BADGE_COLOR), 0);
+
+            // 11 lit cells of "5" at 6x6 pixels, 32 of "100" at 2x2
+            assert_eq!(count(&low, red), 11 * 36);
+            let full = render_icon(battery(100, false), &config, 32);
+            assert_eq!(count(&full, IconColor::White.rgba()), 32 * 4);
+
+            let charging = render_icon(battery(5, true), &config, 32);
+            assert_eq!(count(&charging, red), 0);
+            assert_eq!(count(&charging, BADGE_COLOR), count(&low, red));
+        }
+
+        #[test]
+        fn combined_style_keeps_the_icon_above_the_number() {
+            let config = config(IconStyle::Combined, false);
+            let image = render_icon(battery(30, false), &config, 32);
+            let icon = mouse_icon(IconColor::Yellow, 32);
+
+            // The number starts at the middle, its outline a row above
+            for y in 0..15 {
+                for x in 0..32 {
+                    assert_eq!(
+                        image.get_pixel(x, y),
+                        icon.get_pixel(x, y),
+                        "({}, {})",
+                        x,
+                        y
+                    );
+                }
+            }
+            assert!(
+                count(&image, IconColor::Yellow.rgba()) > count(&icon, IconColor::Yellow.rgba())
+            );
+        }
+
+        #[test]
+        fn bar_fills_up_to_the_level() {
+            let config = config(IconStyle::Number, true);
+            let white = IconColor::White.rgba();
+
+            for (level, filled) in [(0, 0), (50, 16), (100, 32)] {
+                let image = render_icon(battery(level, false), &config, 32);
+                // 32 / 8 rows at the bottom
+                for y in 28..32 {
+                    for x in 0..32 {
+                        let expected = if x < filled { white } else { BAR_BACKGROUND };
+                        assert_eq!(
+                            *image.get_pixel(x, y),
+                            expected,
+                            "{}% at ({}, {})",
+                            level,
+                            x,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn tiny_sizes_do_not_panic() {
+            for style in [IconStyle::Icon, IconStyle::Number, IconStyle::Combined] {
+                for size in 0..4 {
+                    let image = render_icon(battery(50, true), &config(style, true), size);
+                    assert_eq!(image.dimensions(), (size, size));
+                }
+            }
+        }
+
+        #[test]
+        fn size_is_validated() {
+            let size = |toml: &str| toml::from_str::<IconConfig>(toml).map(|config| config.size);
+
+            assert_eq!(size("").unwrap(), None);
+            assert_eq!(size("size = 8").unwrap(), Some(8));
+            assert_eq!(size("size = 256").unwrap(), Some(256));
+            assert!(size("size = 0").is_err());
+            assert!(size("size = 7").is_err());
+            assert!(size("size = 257").is_err());
+        }
+
+        #[test]
+        fn disconnected_icon_is_grey() {
+            let image = render_icon(
+                IconState::Disconnected,
+                &config(IconStyle::Number, true),
+                16,
+            );
+
+            assert!(image.pixels().any(|pixel| pixel[3] > 0));
+            for pixel in image.pixels() {
+                assert_eq!(pixel.0[..3], [DISCONNECTED_GREY; 3]);
+            }
+        }
+    }
+}
@@ -0,0 +1,686 @@
+//! Local endpoint through which command line invocations talk to the running
+//! instance (tray app or daemon): a Unix domain socket on Linux, a named pipe
+//! on Windows.
+//!
+//! Each connection carries one JSON request line answered by one JSON
+//! response line, except `watch`, which is answered by a line per device event
+//! until the client disconnects. Owning the endpoint also marks the single
+//! running instance.
+
+use log::{error, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, ErrorKind, Lines, Read, Write};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::devices::ConnectionType;
+use crate::estimate::Estimate;
+use crate::events::DeviceEvent;
+use crate::history::{HistoryFilter, HistoryRecord};
+
+const ENDPOINT_NAME: &str = env!("CARGO_PKG_NAME");
+/// How long `send` waits to connect to the running instance and for each
+/// read and write.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// The last reading of every connected device.
+    Status,
+    /// Re-enumerate devices and re-read every battery now.
+    Refresh,
+    History {
+        filter: HistoryFilter,
+    },
+    /// Re-read the configuration file.
+    ReloadConfig,
+    /// Sent by a second launch of the tray app, so the running one can show
+    /// itself instead.
+    Activate,
+    /// Every device event from now on.
+    Watch,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status {
+        devices: Vec<DeviceStatus>,
+    },
+    History {
+        records: Vec<HistoryRecord>,
+    },
+    Error {
+        message: String,
+    },
+    /// Answers `watch` with a line per event instead of a response.
+    #[serde(skip)]
+    Events(Receiver<DeviceEvent>),
+}
+
+impl Response {
+    pub fn error(message: impl ToString) -> Self {
+        Response::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A device as known to the running instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub pid: u32,
+    pub name: String,
+    pub level: Option<i32>,
+    pub is_charging: bool,
+    /// Whether the last battery read succeeded.
+    pub is_available: bool,
+    pub connection: Option<ConnectionType>,
+    /// Unix time of the last successful battery read.
+    pub last_update: Option<u64>,
+    /// Estimated seconds until the battery is empty.
+    pub time_to_empty: Option<u64>,
+    /// Estimated seconds until the battery is full, while charging.
+    pub time_to_full: Option<u64>,
+}
+
+impl DeviceStatus {
+    pub fn estimate(&self) -> Option<Estimate> {
+        match (self.time_to_empty, self.time_to_full) {
+            (Some(seconds), _) => Some(Estimate::TimeToEmpty(Duration::from_secs(seconds))),
+            (_, Some(seconds)) => Some(Estimate::TimeToFull(Duration::from_secs(seconds))),
+            _ => None,
+        }
+    }
+}
+
+/// The endpoint of the running instance. Only one process can bind it.
+pub struct Listener(platform::Listener);
+
+impl Listener {
+    /// Claims the endpoint, `None` if another instance holds it.
+    pub fn bind() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        Ok(platform::Listener::bind()?.map(Listener))
+    }
+
+    /// Answers requests on a background thread until the process exits.
+    pub fn serve<F>(self, handler: F)
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.0.listen(move |stream| {
+            let handler = Arc::clone(&handler);
+            thread::spawn(move || {
+                if let Err(err) = handle_connection(stream, handler.as_ref()) {
+                    warn!("IPC connection failed: {}", err);
+                }
+            });
+        });
+    }
+}
+
+/// Sends a request to the running instance, `None` if there is none. Fails
+/// if the instance doesn't answer in time.
+pub fn send(request: &Request) -> Result<Option<Response>, Box<dyn std::error::Error>> {
+    let stream = match platform::connect(Some(TIMEOUT)) {
+        Ok(stream) => stream,
+        Err(err) if err.kind() == ErrorKind::TimedOut => return Err(err.into()),
+        Err(err) => {
+            trace!("No running instance: {}", err);
+            return Ok(None);
+        }
+    };
+
+    exchange(stream, request).map(Some)
+}
+
+/// Sends a request answered by a stream of lines, like `watch`. `None` if
+/// there is no running instance. Waits for lines without a timeout.
+pub fn send_streaming(
+    request: &Request,
+) -> Result<Option<Lines<BufReader<impl Read>>>, Box<dyn std::error::Error>> {
+    let mut stream = match platform::connect(None) {
+        Ok(stream) => stream,
+        Err(err) => {
+            trace!("No running instance: {}", err);
+            return Ok(None);
+        }
+    };
+
+    write_request(&mut stream, request)?;
+    Ok(Some(BufReader::new(stream).lines()))
+}
+
+fn exchange<S: Read + Write>(
+    mut stream: S,
+    request: &Request,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    write_request(&mut stream, request)?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+fn write_request(stream: &mut impl Write, request: &Request) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())
+}
+
+fn handle_connection<S, F>(stream: S, handler: &F) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: Read + Write,
+    F: Fn(Request) -> Response,
+{
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => {
+            trace!("IPC request: {:?}", request);
+            handler(request)
+        }
+        Err(err) => Response::error(format!("Invalid request: {}", err)),
+    };
+
+    let stream = reader.get_mut();
+    if let Response::Events(events) = response {
+        for event in events {
+            let mut line = serde_json::to_string(&event)?;
+            line.push('\n');
+            if stream
+                .write_all(line.as_bytes())
+                .and_then(|()| stream.flush())
+                .is_err()
+            {
+                trace!("Event stream closed");
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut line = serde_json::to_string(&response)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::*;
+    use std::fs::{self, File, TryLockError};
+    use std::io;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc;
+
+    /// `$XDG_RUNTIME_DIR`, or else a directory of our own in the temp
+    /// directory, so other users can't connect to or replace the socket.
+    fn socket_dir() -> io::Result<PathBuf> {
+        if let Some(dir) = dirs::runtime_dir() {
+            return Ok(dir);
+        }
+
+        let uid = unsafe { libc::getuid() };
+        let dir = std::env::temp_dir().join(format!("{}-{}", ENDPOINT_NAME, uid));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => (),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(err),
+        }
+
+        // It may have been created by someone else first
+        let metadata = fs::symlink_metadata(&dir)?;
+        if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} is not a private directory", dir.display()),
+            ));
+        }
+        Ok(dir)
+    }
+
+    fn socket_path() -> io::Result<PathBuf> {
+        Ok(socket_dir()?.join(format!("{}.sock", ENDPOINT_NAME)))
+    }
+
+    pub struct Listener {
+        listener: UnixListener,
+        /// Held for the lifetime of the process. Guards the socket, which
+        /// can't tell a crashed owner from a live one by itself.
+        lock: File,
+    }
+
+    impl Listener {
+        pub fn bind() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+            Self::bind_at(&socket_path()?)
+        }
+
+        pub(super) fn bind_at(path: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+            let lock = File::create(path.with_extension("lock"))?;
+            match lock.try_lock() {
+                Ok(()) => (),
+                Err(TryLockError::WouldBlock) => return Ok(None),
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+
+            // Left over from an instance that didn't shut down cleanly
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+
+            let listener = UnixListener::bind(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            trace!("Listening on {}", path.display());
+
+            Ok(Some(Self { listener, lock }))
+        }
+
+        pub fn listen<F>(self, on_connection: F)
+        where
+            F: Fn(UnixStream) + Send + 'static,
+        {
+            let Listener { listener, lock } = self;
+
+            thread::spawn(move || {
+                let _lock = lock;
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => on_connection(stream),
+                        Err(err) => error!("Failed to accept IPC connection: {}", err),
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn connect(timeout: Option<Duration>) -> io::Result<UnixStream> {
+        connect_to(socket_path()?, timeout)
+    }
+
+    pub(super) fn connect_to(path: PathBuf, timeout: Option<Duration>) -> io::Result<UnixStream> {
+        let Some(timeout) = timeout else {
+            return UnixStream::connect(path);
+        };
+
+        // Connecting blocks while the listener's backlog is full, and has no
+        // timeout of its own
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(UnixStream::connect(path));
+        });
+        let stream = receiver.recv_timeout(timeout).map_err(|_| {
+            io::Error::new(
+                ErrorKind::TimedOut,
+                "Timed out connecting to the running instance",
+            )
+        })??;
+
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::fs::{File, OpenOptions};
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+    use std::{io, mem, ptr, slice};
+    use winapi::shared::sddl;
+    use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_PIPE_CONNECTED};
+    use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+    use winapi::um::winnt::{TokenUser, HANDLE, PSECURITY_DESCRIPTOR, TOKEN_QUERY, TOKEN_USER};
+    use winapi::um::{
+        handleapi, ioapiset, namedpipeapi, processthreadsapi, securitybaseapi, winbase,
+    };
+
+    const BUFFER_SIZE: u32 = 4096;
+    /// How often reads and writes past the deadline are cancelled.
+    const CANCEL_INTERVAL: Duration = Duration::from_millis(100);
+
+    fn wide(value: &str) -> Vec<u16> {
+        OsStr::new(value)
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect()
+    }
+
+    /// The SID of the user running this process, like `S-1-5-21-…`.
+    fn user_sid() -> io::Result<String> {
+        let mut token: HANDLE = ptr::null_mut();
+        let opened = unsafe {
+            processthreadsapi::OpenProcessToken(
+                processthreadsapi::GetCurrentProcess(),
+                TOKEN_QUERY,
+                &mut token,
+            )
+        };
+        if opened == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let sid = unsafe { token_user_sid(token) };
+        unsafe { handleapi::CloseHandle(token) };
+        sid
+    }
+
+    unsafe fn token_user_sid(token: HANDLE) -> io::Result<String> {
+        let mut size = 0;
+        securitybaseapi::GetTokenInformation(token, TokenUser, ptr::null_mut(), 0, &mut size);
+        // Sized in u64s, so the `TOKEN_USER` at its start is aligned
+        let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+        if securitybaseapi::GetTokenInformation(
+            token,
+            TokenUser,
+            buffer.as_mut_ptr() as _,
+            size,
+            &mut size,
+        ) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let user = &*(buffer.as_ptr() as *const TOKEN_USER);
+
+        let mut string = ptr::null_mut();
+        if sddl::ConvertSidToStringSidW(user.User.Sid, &mut string) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let length = (0..).take_while(|&i| *string.add(i) != 0).count();
+        let sid = String::from_utf16_lossy(slice::from_raw_parts(string, length));
+        winbase::LocalFree(string as _);
+        Ok(sid)
+    }
+
+    /// Named after the user, since pipe names are shared by every session on
+    /// the machine.
+    fn pipe_name() -> io::Result<String> {
+        Ok(format!(r"\\.\pipe\{}-{}", ENDPOINT_NAME, user_sid()?))
+    }
+
+    /// Grants access to the current user only, instead of the default that
+    /// lets everyone read the pipe.
+    struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+    impl SecurityDescriptor {
+        fn current_user() -> io::Result<Self> {
+            // Protected DACL with a single "generic all" entry for the user
+            let sddl = wide(&format!("D:P(A;;GA;;;{})", user_sid()?));
+            let mut descriptor = ptr::null_mut();
+            let converted = unsafe {
+                sddl::ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                    sddl.as_ptr(),
+                    sddl::SDDL_REVISION_1.into(),
+                    &mut descriptor,
+                    ptr::null_mut(),
+                )
+            };
+            if converted == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(descriptor))
+        }
+    }
+
+    impl Drop for SecurityDescriptor {
+        fn drop(&mut self) {
+            unsafe { winbase::LocalFree(self.0) };
+        }
+    }
+
+    /// A server end of the pipe that can be used like a file once a client
+    /// connected. Flushing waits until the client has read everything, so
+    /// closing the handle right after doesn't discard the response.
+    pub struct PipeStream(File);
+
+    impl Read for PipeStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for PipeStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.sync_all()
+        }
+    }
+
+    fn create_pipe(first: bool) -> std::io::Result<File> {
+        let name = wide(&pipe_name()?);
+        let descriptor = SecurityDescriptor::current_user()?;
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.0,
+            bInheritHandle: 0,
+        };
+        let mut open_mode = winbase::PIPE_ACCESS_DUPLEX;
+        if first {
+            open_mode |= winbase::FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+
+        let handle = unsafe {
+            namedpipeapi::CreateNamedPipeW(
+                name.as_ptr(),
+                open_mode,
+                winbase::PIPE_TYPE_BYTE
+                    | winbase::PIPE_READMODE_BYTE
+                    | winbase::PIPE_WAIT
+                    | winbase::PIPE_REJECT_REMOTE_CLIENTS,
+                winbase::PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                &mut attributes,
+            )
+        };
+
+        if handle == handleapi::INVALID_HANDLE_VALUE {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_handle(handle as _) })
+    }
+
+    fn wait_for_client(pipe: &File) -> std::io::Result<()> {
+        let connected = unsafe {
+            namedpipeapi::ConnectNamedPipe(pipe.as_raw_handle() as _, std::ptr::null_mut())
+        };
+        if connected != 0 {
+            return Ok(());
+        }
+
+        let err = std::io::Error::last_os_error();
+        // The client connected between creating the pipe and waiting for it
+        if err.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32) {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    pub struct Listener {
+        /// The first instance of the pipe. Creating it fails while another
+        /// process owns the pipe name.
+        pipe: File,
+    }
+
+    impl Listener {
+        pub fn bind() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+            let pipe = match create_pipe(true) {
+                Ok(pipe) => pipe,
+                Err(err) if err.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) => {
+                    return Ok(None);
+                }
+                Err(err) => return Err(format!("Failed to create the IPC pipe: {}", err).into()),
+            };
+            trace!("Listening on {}", pipe_name()?);
+            Ok(Some(Self { pipe }))
+        }
+
+        pub fn listen<F>(self, on_connection: F)
+        where
+            F: Fn(PipeStream) + Send + 'static,
+        {
+            let mut pipe = self.pipe;
+
+            thread::spawn(move || loop {
+                let connected = wait_for_client(&pipe);
+
+                // Create the next instance before handing this one off, so
+                // clients never find the pipe missing
+                let next = match create_pipe(false) {
+                    Ok(next) => next,
+                    Err(err) => {
+                        error!("Failed to create the IPC pipe: {}", err);
+                        return;
+                    }
+                };
+                let current = std::mem::replace(&mut pipe, next);
+
+                match connected {
+                    Ok(()) => on_connection(PipeStream(current)),
+                    Err(err) => warn!("Failed to accept IPC connection: {}", err),
+                }
+            });
+        }
+    }
+
+    /// The client end of the pipe. Pipe handles can't time out, so with a
+    /// timeout, reads and writes still blocked at the deadline are cancelled.
+    pub struct ClientPipe {
+        pipe: Arc<File>,
+        /// Dropped to stop the deadline.
+        _deadline: Option<Sender<()>>,
+    }
+
+    impl Read for ClientPipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            (&*self.pipe).read(buf)
+        }
+    }
+
+    impl Write for ClientPipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            (&*self.pipe).write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            (&*self.pipe).flush()
+        }
+    }
+
+    /// Opening the pipe doesn't block: it fails right away if every instance
+    /// is busy.
+    pub fn connect(timeout: Option<Duration>) -> std::io::Result<ClientPipe> {
+        let pipe = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(pipe_name()?)?,
+        );
+
+        let deadline = timeout.map(|timeout| {
+            let (sender, receiver) = mpsc::channel::<()>();
+            let pipe = Arc::clone(&pipe);
+            thread::spawn(move || {
+                let mut wait = timeout;
+                // Again and again, in case a read starts right after a cancel
+                while receiver.recv_timeout(wait) == Err(RecvTimeoutError::Timeout) {
+                    unsafe { ioapiset::CancelIoEx(pipe.as_raw_handle() as _, ptr::null_mut()) };
+                    wait = CANCEL_INTERVAL;
+                }
+            });
+            sender
+        });
+
+        Ok(ClientPipe {
+            pipe,
+            _deadline: deadline,
+        })
+    }
+}
+
+// Only the socket can be tested here
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let dir = crate::history::testing::TempDir::new();
+        let path = dir.path().join("test.sock");
+        let listener = platform::Listener::bind_at(&path).unwrap().unwrap();
+        // The endpoint marks the single running instance
+        assert!(platform::Listener::bind_at(&path).unwrap().is_none());
+
+        Listener(listener).serve(|request| match request {
+            Request::Status => Response::Status {
+                devices: vec![DeviceStatus {
+                    pid: 0x00B7,
+                    name: "DeathAdder".to_owned(),
+                    level: Some(80),
+                    is_charging: false,
+                    is_available: true,
+                    connection: Some(ConnectionType::Wireless),
+                    last_update: Some(1_750_000_000),
+                    time_to_empty: Some(3600),
+                    time_to_full: None,
+                }],
+            },
+            request => Response::error(format!("Unexpected {:?}", request)),
+        });
+
+        let connect = || platform::connect_to(path.clone(), Some(TIMEOUT)).unwrap();
+        let Response::Status { devices } = exchange(connect(), &Request::Status).unwrap() else {
+            panic!("Expected a status");
+        };
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].level, Some(80));
+        assert!(matches!(
+            devices[0].estimate(),
+            Some(Estimate::TimeToEmpty(duration)) if duration.as_secs() == 3600
+        ));
+
+        let response = exchange(connect(), &Request::Refresh).unwrap();
+        assert!(matches!(response, Response::Error { message } if message == "Unexpected Refresh"));
+    }
+
+    #[test]
+    fn hung_instances_time_out() {
+        let dir = crate::history::testing::TempDir::new();
+        let path = dir.path().join("test.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        // Accepts, but never answers
+        let (sender, _accepted) = std::sync::mpsc::channel();
+        thread::spawn(move || sender.send(listener.accept().unwrap()));
+
+        let started = std::time::Instant::now();
+        let stream = platform::connect_to(path, Some(Duration::from_millis(200))).unwrap();
+        let err = exchange(stream, &Request::Status).unwrap_err();
+
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert!(
+            matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+            "{}",
+            err
+        );
+        assert!(started.elapsed() < TIMEOUT);
+    }
+}
@@ -1,20 +1,136 @@
 #![windows_subsystem = "windows"]
 
-use console::DebugConsole;
-use tray::TrayApp;
+use cli::Command;
 
+mod bar;
+mod bus;
+mod cli;
+mod config;
 mod console;
 mod controller;
+mod daemon;
+#[cfg(target_os = "linux")]
+mod dbus;
 mod devices;
+mod estimate;
+mod events;
+mod history;
+mod hooks;
+mod http;
+mod icon;
+mod ipc;
 mod manager;
+// Shared by both tray backends
+#[cfg(any(feature = "tray", target_os = "linux"))]
+mod menu;
+mod metrics;
+mod monitor;
+mod mqtt;
+mod notify;
+mod rules;
+mod scheduler;
+#[cfg(unix)]
+mod signals;
+#[cfg(target_os = "linux")]
+mod sni;
+mod stats;
+#[cfg(feature = "tray")]
 mod tray;
 
 fn main() {
-    let console = DebugConsole::new("Razer Battery Report Debug Console");
+    let command = match cli::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            console::attach_parent_console();
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Command::Tray = command {
+        run_tray();
+        return;
+    }
+
+    console::attach_parent_console();
+    if let Command::Daemon = command {
+        // Nothing shows the log otherwise, so default to informational messages
+        let filters = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned());
+        pretty_env_logger::formatted_builder()
+            .parse_filters(&filters)
+            .init();
+    } else {
+        pretty_env_logger::init();
+    }
+
+    if let Err(err) = cli::run(command) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(any(feature = "tray", target_os = "linux"))]
+fn run_tray() {
+    use icon::TrayBackend;
+    use monitor::BatteryMonitor;
+    use std::sync::Arc;
+
+    #[cfg(unix)]
+    let signals = signals::SignalSet::block_termination();
+
+    let listener = match ipc::Listener::bind() {
+        Ok(Some(listener)) => Ok(listener),
+        Ok(None) => {
+            // Let the running instance show itself instead of starting a second one
+            let _ = ipc::send(&ipc::Request::Activate);
+            return;
+        }
+        Err(err) => Err(err),
+    };
+
+    #[cfg(feature = "tray")]
+    let console = console::DebugConsole::new("Razer Battery Report Debug Console");
 
     std::env::set_var("RUST_LOG", "trace");
     pretty_env_logger::init();
 
-    let checker = TrayApp::new(console);
-    checker.run();
+    let listener = listener
+        .map_err(|err| log::warn!("Running without IPC: {}", err))
+        .ok();
+    let monitor = Arc::new(BatteryMonitor::new());
+
+    #[cfg(unix)]
+    match signals {
+        Ok(signals) => signals.spawn_handler(&monitor),
+        Err(err) => log::warn!("Failed to block signals: {}", err),
+    }
+
+    // Without the tray feature, StatusNotifierItem is the only tray on Linux
+    #[cfg(target_os = "linux")]
+    let use_sni = !cfg!(feature = "tray") || monitor.config().icon.backend == TrayBackend::Sni;
+    #[cfg(not(target_os = "linux"))]
+    let use_sni = false;
+
+    if use_sni {
+        #[cfg(target_os = "linux")]
+        if let Err(err) = sni::run(monitor, listener) {
+            log::error!("Failed to start the tray icon: {}", err);
+            std::process::exit(1);
+        }
+    } else {
+        #[cfg(feature = "tray")]
+        {
+            if monitor.config().icon.backend == TrayBackend::Sni {
+                log::warn!("The sni tray backend is only available on Linux");
+            }
+            tray::TrayApp::new(monitor, console).run(listener);
+        }
+    }
+}
+
+#[cfg(not(any(feature = "tray", target_os = "linux")))]
+fn run_tray() {
+    console::attach_parent_console();
+    eprintln!("Built without the tray icon, run `razer-battery-report daemon` instead");
+    std::process::exit(2);
 }
@@ -6,7 +6,20 @@ use std::sync::Arc;
 use std::vec::Vec;
 
 use crate::controller::DeviceController;
-use crate::devices::RAZER_DEVICE_LIST;
+use crate::devices::{ConnectionType, RAZER_DEVICE_LIST};
+
+/// Where the monitor gets its devices and readings from.
+pub trait DeviceBackend: Send {
+    /// Enumerates devices and returns the removed and newly connected ones.
+    fn fetch_devices(&mut self) -> (Vec<u32>, Vec<u32>);
+    fn get_device_name(&self, id: u32) -> Option<String>;
+    fn get_device_connection(&self, id: u32) -> Option<ConnectionType>;
+    fn get_device_battery_raw(&self, id: u32) -> Option<u8>;
+    fn is_device_charging(&self, id: u32) -> Option<bool>;
+    fn get_device_serial(&self, id: u32) -> Option<String>;
+    /// Releases the devices, once no more reads follow.
+    fn close(&mut self) {}
+}
 
 #[derive(Debug)]
 pub struct DeviceManager {
@@ -20,7 +33,59 @@ impl DeviceManager {
         }
     }
 
-    pub fn fetch_devices(&mut self) -> (Vec<u32>, Vec<u32>) {
+    fn get_connected_devices(&self) -> Vec<DeviceController> {
+        let mut connected_devices = Vec::new();
+        let mut added_devices = HashSet::new();
+
+        for device in RAZER_DEVICE_LIST.iter() {
+            let api = match HidApi::new() {
+                Ok(api) => api,
+                Err(err) => {
+                    warn!("Failed to initialize HidApi: {:?}", err);
+                    continue;
+                }
+            };
+
+            for hid_device in api.device_list() {
+                // Spelled out since serde_json's `PartialEq<Value> for i32`
+                // makes a bare `.into()` ambiguous
+                if hid_device.vendor_id() == device.vid
+                    && hid_device.product_id() == device.pid
+                    && hid_device.interface_number() == i32::from(device.interface)
+                {
+                    // Check platform-specific usage if on Windows
+                    if cfg!(target_os = "windows")
+                        && (hid_device.usage_page() != device.usage_page
+                            || hid_device.usage() != device.usage)
+                    {
+                        continue;
+                    }
+
+                    // Only add the device if it hasn't been added yet
+                    if !added_devices.contains(&device.pid) {
+                        match DeviceController::new(
+                            device.name.to_owned(),
+                            device.pid,
+                            hid_device.path().to_string_lossy().into_owned(),
+                        ) {
+                            Ok(controller) => {
+                                connected_devices.push(controller);
+                                added_devices.insert(device.pid);
+                            }
+                            Err(err) => warn!("Failed to create device controller: {:?}", err),
+                        }
+                    }
+                }
+            }
+        }
+
+        connected_devices
+    }
+}
+
+/// The HID devices from `RAZER_DEVICE_LIST`.
+impl DeviceBackend for DeviceManager {
+    fn fetch_devices(&mut self) -> (Vec<u32>, Vec<u32>) {
         let old_ids: HashSet<u32> = {
             let controllers = self.device_controllers.lock();
             controllers
@@ -43,7 +108,7 @@ impl DeviceManager {
         (removed_devices, connected_devices)
     }
 
-    pub fn get_device_name(&self, id: u32) -> Option<String> {
+    fn get_device_name(&self, id: u32) -> Option<String> {
         let controllers = self.device_controllers.lock();
         controllers
             .iter()
@@ -51,14 +116,22 @@ impl DeviceManager {
             .map(|controller| controller.name.clone())
     }
 
-    pub fn get_device_battery_level(&self, id: u32) -> Option<i32> {
+    fn get_device_connection(&self, id: u32) -> Option<ConnectionType> {
+        let controllers = self.device_controllers.lock();
+        controllers
+            .iter()
+            .find(|controller| controller.pid as u32 == id)
+            .map(|controller| controller.connection)
+    }
+
+    fn get_device_battery_raw(&self, id: u32) -> Option<u8> {
         let controllers = self.device_controllers.lock();
         let controller = controllers
             .iter()
             .find(|controller| controller.pid as u32 == id)?;
 
-        match controller.get_battery_level() {
-            Ok(level) => Some(level),
+        match controller.get_battery_raw() {
+            Ok(raw) => Some(raw),
             Err(err) => {
                 warn!("Failed to get battery level: {:?}", err);
                 None
@@ -66,7 +139,7 @@ impl DeviceManager {
         }
     }
 
-    pub fn is_device_charging(&self, id: u32) -> Option<bool> {
+    fn is_device_charging(&self, id: u32) -> Option<bool> {
         let controllers = self.device_controllers.lock();
         let controller = controllers
             .iter()
@@ -81,50 +154,22 @@ impl DeviceManager {
         }
     }
 
-    fn get_connected_devices(&self) -> Vec<DeviceController> {
-        let mut connected_devices = Vec::new();
-        let mut added_devices = HashSet::new();
-
-        for device in RAZER_DEVICE_LIST.iter() {
-            let api = match HidApi::new() {
-                Ok(api) => api,
-                Err(err) => {
-                    warn!("Failed to initialize HidApi: {:?}", err);
-                    continue;
-                }
-            };
+    fn close(&mut self) {
+        self.device_controllers.lock().clear();
+    }
 
-            for hid_device in api.device_list() {
-                if hid_device.vendor_id() == device.vid
-                    && hid_device.product_id() == device.pid
-                    && hid_device.interface_number() == device.interface.into()
-                {
-                    // Check platform-specific usage if on Windows
-                    if cfg!(target_os = "windows")
-                        && (hid_device.usage_page() != device.usage_page
-                            || hid_device.usage() != device.usage)
-                    {
-                        continue;
-                    }
+    fn get_device_serial(&self, id: u32) -> Option<String> {
+        let controllers = self.device_controllers.lock();
+        let controller = controllers
+            .iter()
+            .find(|controller| controller.pid as u32 == id)?;
 
-                    // Only add the device if it hasn't been added yet
-                    if !added_devices.contains(&device.pid) {
-                        match DeviceController::new(
-                            device.name.to_owned(),
-                            device.pid,
-                            hid_device.path().to_string_lossy().into_owned(),
-                        ) {
-                            Ok(controller) => {
-                                connected_devices.push(controller);
-                                added_devices.insert(device.pid);
-                            }
-                            Err(err) => warn!("Failed to create device controller: {:?}", err),
-                        }
-                    }
-                }
+        match controller.get_serial() {
+            Ok(serial) => Some(serial),
+            Err(err) => {
+                warn!("Failed to get serial number: {:?}", err);
+                None
             }
         }
-
-        connected_devices
     }
 }
@@ -0,0 +1,565 @@
+//! Menu, tooltip and icon state of the tray, shared by the tray backends.
+
+use std::{collections::HashMap, fs, io, path::Path, process::Command, sync::Arc, thread};
+
+use chrono::{Local, TimeZone};
+use log::{error, info};
+
+use crate::{
+    cli::format_timestamp,
+    config::{format_duration, Config},
+    icon::IconState,
+    monitor::{BatteryMonitor, MemoryDevice},
+};
+
+impl MemoryDevice {
+    /// Menu entry text, e.g. `DeathAdder V3 Pro — 64%, charging (14:05)`.
+    pub fn label(&self) -> String {
+        let status = match self.battery_level {
+            Some(_) if !self.is_available => "not responding".to_owned(),
+            Some(level) if self.is_charging => format!("{}%, charging", level),
+            Some(level) => format!("{}%", level),
+            None => "waiting for reading".to_owned(),
+        };
+
+        match self.last_update {
+            Some(timestamp) => format!("{} — {} ({})", self.name, status, format_time(timestamp)),
+            None => format!("{} — {}", self.name, status),
+        }
+    }
+
+    pub fn details(&self) -> String {
+        let mut details = format!("{}\nProduct ID: 0x{:04X}", self.name, self.pid);
+        if let Some(connection) = self.connection {
+            details.push_str(&format!("\nConnection: {}", connection));
+        }
+        match self.battery_level {
+            Some(level) => {
+                details.push_str(&format!("\nBattery: {}%", level));
+                if self.is_charging {
+                    details.push_str(" (charging)");
+                }
+            }
+            None => details.push_str("\nBattery: unknown"),
+        }
+        if let Some(estimate) = self.estimate {
+            details.push_str(&format!("\nEstimate: {}", estimate));
+        }
+        if let Some(timestamp) = self.last_update {
+            details.push_str(&format!("\nLast update: {}", format_timestamp(timestamp)));
+        }
+        if !self.is_available {
+            details.push_str("\nThe device is not responding, it may be asleep.");
+        }
+        details
+    }
+
+    pub fn icon_state(&self) -> IconState {
+        match self.battery_level {
+            Some(level) if self.is_available => IconState::Battery {
+                level,
+                is_charging: self.is_charging,
+            },
+            _ => IconState::Disconnected,
+        }
+    }
+}
+
+/// Time of day of a Unix timestamp in local time.
+fn format_time(timestamp: u64) -> String {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map_or_else(String::new, |time| time.format("%H:%M").to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuAction {
+    RefreshAll,
+    ReloadConfig,
+    ToggleConsole,
+    ToggleSnooze,
+    Exit,
+    Refresh(u32),
+    Details(u32),
+    Rename(u32),
+    Settings,
+}
+
+impl MenuAction {
+    /// Menu items are rebuilt on every update, so actions are encoded in
+    /// stable ids rather than tracked per item.
+    #[cfg(feature = "tray")]
+    pub fn id(self) -> String {
+        match self {
+            MenuAction::RefreshAll => "refresh_all".to_owned(),
+            MenuAction::ReloadConfig => "reload_config".to_owned(),
+            MenuAction::ToggleConsole => "console".to_owned(),
+            MenuAction::ToggleSnooze => "snooze".to_owned(),
+            MenuAction::Exit => "exit".to_owned(),
+            MenuAction::Refresh(pid) => format!("refresh:{:04X}", pid),
+            MenuAction::Details(pid) => format!("details:{:04X}", pid),
+            MenuAction::Rename(pid) => format!("rename:{:04X}", pid),
+            MenuAction::Settings => "settings".to_owned(),
+        }
+    }
+
+    #[cfg(feature = "tray")]
+    pub fn from_id(id: &str) -> Option<Self> {
+        let (action, pid) = match id.split_once(':') {
+            Some((action, pid)) => (action, u32::from_str_radix(pid, 16).ok()),
+            None => (id, None),
+        };
+
+        match (action, pid) {
+            ("refresh_all", _) => Some(MenuAction::RefreshAll),
+            ("reload_config", _) => Some(MenuAction::ReloadConfig),
+            ("console", _) => Some(MenuAction::ToggleConsole),
+            ("snooze", _) => Some(MenuAction::ToggleSnooze),
+            ("exit", _) => Some(MenuAction::Exit),
+            ("refresh", Some(pid)) => Some(MenuAction::Refresh(pid)),
+            ("details", Some(pid)) => Some(MenuAction::Details(pid)),
+            ("rename", Some(pid)) => Some(MenuAction::Rename(pid)),
+            ("settings", _) => Some(MenuAction::Settings),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MenuEntry {
+    /// Disabled when there is no action.
+    Item {
+        text: String,
+        action: Option<MenuAction>,
+    },
+    Submenu {
+        text: String,
+        entries: Vec<MenuEntry>,
+    },
+    Separator,
+}
+
+impl MenuEntry {
+    fn item(action: MenuAction, text: &str) -> Self {
+        MenuEntry::Item {
+            text: text.to_owned(),
+            action: Some(action),
+        }
+    }
+}
+
+/// One submenu per device above the global items. The log window entry is
+/// left out when `console_visible` is `None`.
+pub fn menu_entries(
+    devices: &[&MemoryDevice],
+    console_visible: Option<bool>,
+    snooze_text: &str,
+) -> Vec<MenuEntry> {
+    let mut entries = Vec::new();
+
+    if devices.is_empty() {
+        entries.push(MenuEntry::Item {
+            text: "No devices connected".to_owned(),
+            action: None,
+        });
+    }
+
+    for device in devices {
+        entries.push(MenuEntry::Submenu {
+            text: device.label(),
+            entries: vec![
+                MenuEntry::item(MenuAction::Refresh(device.pid), "Refresh"),
+                MenuEntry::item(MenuAction::Details(device.pid), "Details"),
+                MenuEntry::item(MenuAction::Rename(device.pid), "Rename…"),
+                MenuEntry::item(MenuAction::Settings, "Settings…"),
+            ],
+        });
+    }
+
+    entries.push(MenuEntry::Separator);
+    entries.push(MenuEntry::item(MenuAction::RefreshAll, "Refresh Now"));
+    entries.push(MenuEntry::item(MenuAction::ReloadConfig, "Reload Settings"));
+    if let Some(console_visible) = console_visible {
+        let console_text = if console_visible {
+            "Hide Log Window"
+        } else {
+            "Show Log Window"
+        };
+        entries.push(MenuEntry::item(MenuAction::ToggleConsole, console_text));
+    }
+    entries.push(MenuEntry::item(MenuAction::ToggleSnooze, snooze_text));
+    entries.push(MenuEntry::item(MenuAction::Exit, "Exit"));
+    entries
+}
+
+pub fn sorted_devices(devices: &HashMap<u32, MemoryDevice>) -> Vec<&MemoryDevice> {
+    let mut devices: Vec<_> = devices.values().collect();
+    devices.sort_by(|a, b| a.name.cmp(&b.name).then(a.pid.cmp(&b.pid)));
+    devices
+}
+
+/// The icon follows the available device with the lowest battery.
+pub fn icon_state(devices: &[&MemoryDevice]) -> IconState {
+    devices
+        .iter()
+        .map(|device| device.icon_state())
+        .min_by_key(|state| match state {
+            IconState::Battery { level, .. } => *level,
+            IconState::Disconnected => i32::MAX,
+        })
+        .unwrap_or(IconState::Disconnected)
+}
+
+/// One line per device, e.g. `DeathAdder V3 Pro: 64% — about 31 h left`.
+pub fn tooltip(devices: &[&MemoryDevice]) -> String {
+    let lines: Vec<String> = devices
+        .iter()
+        .map(|device| match (device.battery_level, device.estimate) {
+            (Some(level), Some(estimate)) if device.is_available => {
+                format!("{}: {}% — {}", device.name, level, estimate)
+            }
+            (Some(level), _) if device.is_available => format!("{}: {}%", device.name, level),
+            _ => format!("{}: not responding", device.name),
+        })
+        .collect();
+
+    if lines.is_empty() {
+        "No devices connected".to_owned()
+    } else {
+        lines.join("\n")
+    }
+}
+
+pub fn snooze_text(config: &Config, snoozed: bool) -> String {
+    if snoozed {
+        "Resume Notifications".to_owned()
+    } else {
+        format!(
+            "Snooze Notifications for {}",
+            format_duration(config.notifications.snooze)
+        )
+    }
+}
+
+/// Asks for a new name for the device, saves it in the config file and
+/// applies it. The dialog runs on a thread of its own, since it blocks.
+pub fn rename_device(monitor: &Arc<BatteryMonitor>, pid: u32) {
+    let Some(name) = monitor
+        .devices()
+        .get(&pid)
+        .map(|device| device.name.clone())
+    else {
+        return;
+    };
+
+    let monitor = Arc::clone(monitor);
+    thread::spawn(move || {
+        let new_name = match prompt_name(&name) {
+            Ok(Some(new_name)) if new_name != name => new_name,
+            Ok(_) => return,
+            Err(err) => {
+                error!("Failed to ask for a device name: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = Config::set_device_name(pid, &new_name) {
+            error!("Failed to save the device name: {}", err);
+            return;
+        }
+        if let Err(err) = monitor.reload_config() {
+            error!("Failed to reload config: {}", err);
+        }
+    });
+}
+
+/// Shows a text input dialog, `None` if it was cancelled or left empty.
+fn prompt_name(name: &str) -> io::Result<Option<String>> {
+    let text = format!("New name for {}:", name);
+
+    for mut command in prompt_commands(&text, name) {
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+
+        let input = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        return Ok((output.status.success() && !input.is_empty()).then_some(input));
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No dialog program found, install zenity or kdialog",
+    ))
+}
+
+pub fn open_settings() {
+    match Config::path() {
+        Some(path) => {
+            info!("Reload settings to apply changes");
+            open_file(&path);
+        }
+        None => error!("Failed to determine config directory"),
+    }
+}
+
+/// Opens a file with its associated application, creating it if needed.
+fn open_file(path: &Path) {
+    if !path.exists() {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Err(err) = fs::write(path, "") {
+            error!("Failed to create {}: {}", path.display(), err);
+            return;
+        }
+    }
+
+    if let Err(err) = open_command(path).spawn() {
+        error!("Failed to open {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+#[cfg(windows)]
+fn open_command(path: &Path) -> Command {
+    use std::os::windows::process::CommandExt;
+
+    let mut process = Command::new("cmd");
+    process
+        .args(["/C", "start", ""])
+        .arg(path)
+        .creation_flags(CREATE_NO_WINDOW);
+    process
+}
+
+#[cfg(not(windows))]
+fn open_command(path: &Path) -> Command {
+    let mut process = Command::new("xdg-open");
+    process.arg(path);
+    process
+}
+
+/// A Visual Basic input box through PowerShell, which every Windows has.
+#[cfg(windows)]
+fn prompt_commands(text: &str, name: &str) -> Vec<Command> {
+    use std::os::windows::process::CommandExt;
+
+    let quote = |value: &str| format!("'{}'", value.replace('\'', "''"));
+    let script = format!(
+        "[Console]::OutputEncoding = [Text.Encoding]::UTF8; \
+         Add-Type -AssemblyName Microsoft.VisualBasic; \
+         [Microsoft.VisualBasic.Interaction]::InputBox({}, {}, {})",
+        quote(text),
+        quote("Rename Device"),
+        quote(name)
+    );
+
+    let mut process = Command::new("powershell");
+    process
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .creation_flags(CREATE_NO_WINDOW);
+    vec![process]
+}
+
+/// zenity on GNOME and most other desktops, kdialog on KDE.
+#[cfg(not(windows))]
+fn prompt_commands(text: &str, name: &str) -> Vec<Command> {
+    let mut zenity = Command::new("zenity");
+    zenity.args([
+        "--entry",
+        "--title",
+        "Rename Device",
+        "--text",
+        text,
+        "--entry-text",
+        name,
+    ]);
+    let mut kdialog = Command::new("kdialog");
+    kdialog.args(["--title", "Rename Device", "--inputbox", text, name]);
+    vec![zenity, kdialog]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::estimate::Estimate;
+    use std::time::Duration;
+
+    const TIMESTAMP: u64 = 1_750_000_000;
+
+    fn device(name: &str, pid: u32, level: Option<i32>) -> MemoryDevice {
+        MemoryDevice {
+            name: name.to_owned(),
+            model: name.to_owned(),
+            serial: None,
+            pid,
+            battery_level: level,
+            battery_raw: None,
+            is_charging: false,
+            is_available: level.is_some(),
+            connection: None,
+            last_update: level.map(|_| TIMESTAMP),
+            estimate: None,
+        }
+    }
+
+    /// Texts and actions, with submenu entries indented.
+    fn outline(entries: &[MenuEntry]) -> Vec<(String, Option<MenuAction>)> {
+        let mut outline = Vec::new();
+        for entry in entries {
+            match entry {
+                MenuEntry::Item { text, action } => outline.push((text.clone(), *action)),
+                MenuEntry::Submenu { text, entries } => {
+                    outline.push((text.clone(), None));
+                    for (text, action) in self::outline(entries) {
+                        outline.push((format!("  {}", text), action));
+                    }
+                }
+                MenuEntry::Separator => outline.push(("---".to_owned(), None)),
+            }
+        }
+        outline
+    }
+
+    #[test]
+    fn labels_show_level_state_and_time() {
+        let time = format_time(TIMESTAMP);
+        let charging = MemoryDevice {
+            is_charging: true,
+            ..device("Mouse", 0x00B7, Some(64))
+        };
+        let asleep = MemoryDevice {
+            is_available: false,
+            ..device("Mouse", 0x00B7, Some(64))
+        };
+
+        let cases = [
+            (
+                device("Mouse", 0x00B7, Some(64)),
+                format!("Mouse — 64% ({})", time),
+            ),
+            (charging, format!("Mouse — 64%, charging ({})", time)),
+            (asleep, format!("Mouse — not responding ({})", time)),
+            (
+                device("Mouse", 0x00B7, None),
+                "Mouse — waiting for reading".to_owned(),
+            ),
+        ];
+
+        for (device, expected) in cases {
+            assert_eq!(device.label(), expected);
+        }
+    }
+
+    #[test]
+    fn devices_get_submenus() {
+        let mouse = device("Mouse", 0x00B7, Some(64));
+        let keyboard = device("Keyboard", 0x0258, None);
+
+        let entries = menu_entries(&[&mouse, &keyboard], Some(false), "Snooze");
+        let expected = [
+            (mouse.label(), None),
+            ("  Refresh".to_owned(), Some(MenuAction::Refresh(0x00B7))),
+            ("  Details".to_owned(), Some(MenuAction::Details(0x00B7))),
+            ("  Rename…".to_owned(), Some(MenuAction::Rename(0x00B7))),
+            ("  Settings…".to_owned(), Some(MenuAction::Settings)),
+            (keyboard.label(), None),
+            ("  Refresh".to_owned(), Some(MenuAction::Refresh(0x0258))),
+            ("  Details".to_owned(), Some(MenuAction::Details(0x0258))),
+            ("  Rename…".to_owned(), Some(MenuAction::Rename(0x0258))),
+            ("  Settings…".to_owned(), Some(MenuAction::Settings)),
+            ("---".to_owned(), None),
+            ("Refresh Now".to_owned(), Some(MenuAction::RefreshAll)),
+            ("Reload Settings".to_owned(), Some(MenuAction::ReloadConfig)),
+            (
+                "Show Log Window".to_owned(),
+                Some(MenuAction::ToggleConsole),
+            ),
+            ("Snooze".to_owned(), Some(MenuAction::ToggleSnooze)),
+            ("Exit".to_owned(), Some(MenuAction::Exit)),
+        ];
+        assert_eq!(outline(&entries), expected);
+    }
+
+    #[test]
+    fn menus_without_devices_or_log_window() {
+        let entries = menu_entries(&[], None, "Resume Notifications");
+        let texts: Vec<String> = outline(&entries)
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect();
+
+        assert_eq!(
+            texts,
+            [
+                "No devices connected",
+                "---",
+                "Refresh Now",
+                "Reload Settings",
+                "Resume Notifications",
+                "Exit"
+            ]
+        );
+        assert!(matches!(&entries[0], MenuEntry::Item { action: None, .. }));
+
+        let entries = menu_entries(&[], Some(true), "Snooze");
+        assert!(outline(&entries).contains(&(
+            "Hide Log Window".to_owned(),
+            Some(MenuAction::ToggleConsole)
+        )));
+    }
+
+    #[cfg(feature = "tray")]
+    #[test]
+    fn action_ids_round_trip() {
+        let actions = [
+            MenuAction::RefreshAll,
+            MenuAction::ReloadConfig,
+            MenuAction::ToggleConsole,
+            MenuAction::ToggleSnooze,
+            MenuAction::Exit,
+            MenuAction::Refresh(0x00B7),
+            MenuAction::Details(0x00B7),
+            MenuAction::Rename(0x00B7),
+            MenuAction::Settings,
+        ];
+
+        for action in actions {
+            assert_eq!(MenuAction::from_id(&action.id()), Some(action));
+        }
+        assert_eq!(MenuAction::from_id("rename:zz"), None);
+        assert_eq!(MenuAction::from_id("unknown"), None);
+    }
+
+    #[test]
+    fn tooltips_list_every_device() {
+        let mouse = MemoryDevice {
+            estimate: Some(Estimate::TimeToEmpty(Duration::from_secs(31 * 3600))),
+            ..device("Mouse", 0x00B7, Some(64))
+        };
+        let keyboard = device("Keyboard", 0x0258, Some(80));
+        let headset = MemoryDevice {
+            is_available: false,
+            ..device("Headset", 0x0527, Some(50))
+        };
+
+        assert_eq!(
+            tooltip(&[&mouse, &keyboard, &headset]),
+            "Mouse: 64% — about 31 h left\nKeyboard: 80%\nHeadset: not responding"
+        );
+        assert_eq!(tooltip(&[]), "No devices connected");
+    }
+
+    #[test]
+    fn snooze_text_names_the_duration() {
+        let config = Config::default();
+
+        assert_eq!(snooze_text(&config, false), "Snooze Notifications for 1 h");
+        assert_eq!(snooze_text(&config, true), "Resume Notifications");
+    }
+}
@@ -0,0 +1,270 @@
+//! Prometheus metrics: battery state per device and HID protocol counters,
+//! served at `/metrics` and optionally written for node_exporter's textfile
+//! collector.
+
+use log::warn;
+use parking_lot::{const_mutex, Mutex};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::bus::{MonitorEvent, Subscriber};
+use crate::monitor::{BatteryMonitor, MemoryDevice};
+
+/// Protocol counters per product id, filled in by the device controllers.
+static PROTOCOL: Mutex<BTreeMap<u16, ProtocolCounters>> = const_mutex(BTreeMap::new());
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ProtocolCounters {
+    commands: u64,
+    failures: u64,
+    retries: u64,
+    crc_failures: u64,
+    not_supported: u64,
+    duration_seconds: f64,
+}
+
+/// Counts a finished command and how long it took, including retries.
+pub fn record_command(pid: u16, duration: Duration, success: bool) {
+    let mut protocol = PROTOCOL.lock();
+    let counters = protocol.entry(pid).or_default();
+    counters.commands += 1;
+    counters.duration_seconds += duration.as_secs_f64();
+    if !success {
+        counters.failures += 1;
+    }
+}
+
+/// Counts a command resent because the device was busy or didn't answer.
+pub fn record_retry(pid: u16) {
+    PROTOCOL.lock().entry(pid).or_default().retries += 1;
+}
+
+pub fn record_crc_failure(pid: u16) {
+    PROTOCOL.lock().entry(pid).or_default().crc_failures += 1;
+}
+
+pub fn record_not_supported(pid: u16) {
+    PROTOCOL.lock().entry(pid).or_default().not_supported += 1;
+}
+
+/// Name, help text and value of every protocol counter.
+type Counter = (&'static str, &'static str, fn(&ProtocolCounters) -> f64);
+
+const COUNTERS: [Counter; 6] = [
+    (
+        "razer_hid_commands_total",
+        "HID commands sent, counting retries once.",
+        |c| c.commands as f64,
+    ),
+    (
+        "razer_hid_command_failures_total",
+        "HID commands that failed after all retries.",
+        |c| c.failures as f64,
+    ),
+    (
+        "razer_hid_retries_total",
+        "HID commands resent because the device was busy or timed out.",
+        |c| c.retries as f64,
+    ),
+    (
+        "razer_hid_crc_failures_total",
+        "HID responses with an invalid checksum.",
+        |c| c.crc_failures as f64,
+    ),
+    (
+        "razer_hid_not_supported_total",
+        "HID commands the device answered as not supported.",
+        |c| c.not_supported as f64,
+    ),
+    (
+        "razer_hid_command_duration_seconds_total",
+        "Total time spent on HID commands, including retries.",
+        |c| c.duration_seconds,
+    ),
+];
+
+/// All metrics in the Prometheus text format.
+pub fn render(monitor: &BatteryMonitor) -> String {
+    let mut output = String::new();
+
+    {
+        let devices = monitor.devices();
+        let mut devices: Vec<&MemoryDevice> = devices.values().collect();
+        devices.sort_by_key(|device| device.pid);
+
+        write_metric(
+            &mut output,
+            "razer_battery_percent",
+            "gauge",
+            "Battery level in percent.",
+            devices.iter().filter_map(|device| {
+                Some((device_labels(device), device.battery_level?.to_string()))
+            }),
+        );
+        write_metric(
+            &mut output,
+            "razer_battery_raw",
+            "gauge",
+            "Battery level byte as reported by the device (0-255).",
+            devices.iter().filter_map(|device| {
+                Some((device_labels(device), device.battery_raw?.to_string()))
+            }),
+        );
+        write_metric(
+            &mut output,
+            "razer_battery_charging",
+            "gauge",
+            "Whether the device is charging.",
+            devices
+                .iter()
+                .filter(|device| device.battery_level.is_some())
+                .map(|device| {
+                    (
+                        device_labels(device),
+                        u8::from(device.is_charging).to_string(),
+                    )
+                }),
+        );
+        write_metric(
+            &mut output,
+            "razer_device_available",
+            "gauge",
+            "Whether the last battery read succeeded, 0 while a wireless device is asleep.",
+            devices.iter().map(|device| {
+                (
+                    device_labels(device),
+                    u8::from(device.is_available).to_string(),
+                )
+            }),
+        );
+        write_metric(
+            &mut output,
+            "razer_device_connection_info",
+            "gauge",
+            "How the device is connected, in the connection label.",
+            devices.iter().filter_map(|device| {
+                let labels = format!(
+                    "{},connection=\"{}\"",
+                    device_labels(device),
+                    device.connection?.as_str()
+                );
+                Some((labels, "1".to_owned()))
+            }),
+        );
+    }
+
+    let protocol = PROTOCOL.lock().clone();
+    for (name, help, value) in COUNTERS {
+        write_metric(
+            &mut output,
+            name,
+            "counter",
+            help,
+            protocol.iter().map(|(pid, counters)| {
+                (
+                    format!("pid=\"0x{:04X}\"", pid),
+                    value(counters).to_string(),
+                )
+            }),
+        );
+    }
+
+    output
+}
+
+/// Rewrites the textfile from `metrics.textfile` whenever a device changes.
+pub struct Textfile;
+
+impl Subscriber for Textfile {
+    fn handle(&self, monitor: &BatteryMonitor, event: &MonitorEvent) {
+        if !event.changes_state() {
+            return;
+        }
+        if let Some(path) = &monitor.config().metrics.textfile {
+            if let Err(err) = write_textfile(path, &render(monitor)) {
+                warn!("Failed to write metrics to {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Replaces the file in one step, so the collector never reads half of it.
+pub fn write_textfile(path: &Path, metrics: &str) -> std::io::Result<()> {
+    let temp_path = path.with_extension("prom.tmp");
+    fs::write(&temp_path, metrics)?;
+    fs::rename(&temp_path, path)
+}
+
+fn write_metric(
+    output: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl Iterator<Item = (String, String)>,
+) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(output, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn device_labels(device: &MemoryDevice) -> String {
+    format!(
+        "pid=\"0x{:04X}\",name=\"{}\"",
+        device.pid,
+        escape_label(&device.name)
+    )
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::monitor::testing;
+
+    #[test]
+    fn device_metrics_share_their_labels() {
+        let test = testing::monitor(Config::default());
+        test.backend.connect(0x00B7, "Razer \"DeathAdder\"");
+        test.backend.set_level(0x00B7, 80, true);
+        test.monitor.fetch_devices();
+        test.monitor.update(&[0x00B7]);
+
+        let output = render(&test.monitor);
+        let labels = r#"{pid="0x00B7",name="Razer \"DeathAdder\""}"#;
+        for sample in [
+            format!("razer_battery_percent{} 80", labels),
+            format!("razer_battery_raw{} 204", labels),
+            format!("razer_battery_charging{} 1", labels),
+            format!("razer_device_available{} 1", labels),
+            r#"razer_device_connection_info{pid="0x00B7",name="Razer \"DeathAdder\"",connection="wireless"} 1"#.to_owned(),
+        ] {
+            assert!(output.lines().any(|line| line == sample), "{}", output);
+        }
+    }
+
+    #[test]
+    fn textfiles_are_replaced() {
+        let dir = std::env::temp_dir().join(format!("rbr-metrics-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("razer.prom");
+
+        write_textfile(&path, "old\n").unwrap();
+        write_textfile(&path, "new\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(!path.with_extension("prom.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1009 @@
+use log::{error, info, warn};
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::bus::{EventBus, MonitorEvent, Subscriber};
+use crate::config::{Config, SharedConfig};
+use crate::controller::battery_percentage;
+use crate::devices::ConnectionType;
+use crate::estimate::{Estimate, Estimator};
+use crate::events::EventDispatcher;
+use crate::history::{unix_timestamp, History, HistoryFilter, HistoryRecord};
+use crate::ipc::DeviceStatus;
+use crate::manager::{DeviceBackend, DeviceManager};
+use crate::metrics;
+use crate::mqtt::MqttPublisher;
+use crate::notify::LogNotifier;
+use crate::rules::RuleEngine;
+use crate::scheduler::{self, PollRequest};
+
+#[derive(Debug)]
+pub struct MemoryDevice {
+    pub name: String,
+    /// The model name, `name` unless the device was renamed in the config.
+    pub model: String,
+    pub serial: Option<String>,
+    pub pid: u32,
+    pub battery_level: Option<i32>,
+    /// The battery byte as reported by the device (0-255).
+    pub battery_raw: Option<u8>,
+    pub is_charging: bool,
+    /// Whether the last battery read succeeded. Wireless devices stop
+    /// answering while asleep although the receiver stays connected.
+    pub is_available: bool,
+    pub connection: Option<ConnectionType>,
+    /// Unix time of the last successful battery read.
+    pub last_update: Option<u64>,
+    pub estimate: Option<Estimate>,
+}
+
+impl MemoryDevice {
+    fn new(name: String, model: String, pid: u32) -> Self {
+        Self {
+            name,
+            model,
+            serial: None,
+            pid,
+            battery_level: None,
+            battery_raw: None,
+            is_charging: false,
+            is_available: false,
+            connection: None,
+            last_update: None,
+            estimate: None,
+        }
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        let (time_to_empty, time_to_full) = match self.estimate {
+            Some(Estimate::TimeToEmpty(duration)) => (Some(duration.as_secs()), None),
+            Some(Estimate::TimeToFull(duration)) => (None, Some(duration.as_secs())),
+            None => (None, None),
+        };
+
+        DeviceStatus {
+            pid: self.pid,
+            name: self.name.clone(),
+            level: self.battery_level,
+            is_charging: self.is_charging,
+            is_available: self.is_available,
+            connection: self.connection,
+            last_update: self.last_update,
+            time_to_empty,
+            time_to_full,
+        }
+    }
+}
+
+/// Mirrors device state somewhere else, e.g. to an MQTT broker.
+pub trait StatePublisher: Send + Sync {
+    /// Called after every battery read, successful or not.
+    fn update(&self, device: &MemoryDevice);
+    fn removed(&self, pid: u32);
+}
+
+impl<T: StatePublisher> Subscriber for T {
+    fn handle(&self, monitor: &BatteryMonitor, event: &MonitorEvent) {
+        match event {
+            MonitorEvent::ReadingUpdated { pid, .. } | MonitorEvent::Error { pid, .. } => {
+                if let Some(device) = monitor.devices().get(pid) {
+                    self.update(device);
+                }
+            }
+            MonitorEvent::DeviceRemoved { pid, .. } => self.removed(*pid),
+            _ => (),
+        }
+    }
+}
+
+/// Unix time in seconds.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        unix_timestamp()
+    }
+}
+
+/// Device state and polling, shared by the tray icon and the daemon.
+pub struct BatteryMonitor {
+    backend: Mutex<Box<dyn DeviceBackend>>,
+    clock: Arc<dyn Clock>,
+    devices: Mutex<HashMap<u32, MemoryDevice>>,
+    config: SharedConfig,
+    history: Mutex<Option<History>>,
+    rules: Mutex<RuleEngine>,
+    bus: EventBus,
+    events: Arc<EventDispatcher>,
+    poll_sender: Sender<PollRequest>,
+    /// Taken by the polling thread when it starts.
+    poll_receiver: Mutex<Option<Receiver<PollRequest>>>,
+    poll_thread: Mutex<Option<JoinHandle<()>>>,
+    stopped: AtomicBool,
+    /// When the devices were last enumerated.
+    last_fetch: Mutex<Instant>,
+}
+
+impl BatteryMonitor {
+    /// Monitors the HID devices, with the battery history, notifications,
+    /// hooks and exporters the config enables.
+    pub fn new() -> Self {
+        let config = SharedConfig::new(Config::load());
+        let mut monitor = Self::with_backend(
+            config.clone(),
+            Box::new(DeviceManager::new()),
+            Arc::new(SystemClock),
+        );
+
+        match History::open_default() {
+            Ok(history) => *monitor.history.get_mut() = Some(history),
+            Err(err) => warn!("Failed to open battery history: {}", err),
+        }
+        *monitor.rules.get_mut() = RuleEngine::load(config.clone());
+        monitor.events = Arc::new(EventDispatcher::new(config));
+
+        // History first, so everything after it sees the new estimate
+        monitor.bus.add(Arc::new(HistoryRecorder::default()));
+        monitor.bus.add(monitor.events.clone());
+        monitor.bus.add(Arc::new(metrics::Textfile));
+        for publisher in state_publishers(&monitor.config()) {
+            monitor.bus.add(publisher);
+        }
+        monitor
+    }
+
+    /// Only the core, without history, with notification rules kept in
+    /// memory and notifications only logged. Nothing but the receivers of
+    /// `bus` sees its events.
+    pub fn with_backend(
+        config: SharedConfig,
+        backend: Box<dyn DeviceBackend>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let (poll_sender, poll_receiver) = mpsc::channel();
+
+        Self {
+            backend: Mutex::new(backend),
+            clock,
+            devices: Mutex::new(HashMap::new()),
+            history: Mutex::new(None),
+            rules: Mutex::new(RuleEngine::in_memory(config.clone())),
+            bus: EventBus::default(),
+            events: Arc::new(EventDispatcher::with_notifier(
+                config.clone(),
+                Box::new(LogNotifier),
+            )),
+            poll_sender,
+            poll_receiver: Mutex::new(Some(poll_receiver)),
+            poll_thread: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+            last_fetch: Mutex::new(Instant::now()),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.get()
+    }
+
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    pub fn bus(&self) -> &EventBus {
+        &self.bus
+    }
+
+    pub fn events(&self) -> &EventDispatcher {
+        &self.events
+    }
+
+    pub fn devices(&self) -> MutexGuard<'_, HashMap<u32, MemoryDevice>> {
+        self.devices.lock()
+    }
+
+    /// Enumerates devices and reads their batteries in the background, until
+    /// `shutdown`.
+    pub fn start_polling(self: &Arc<Self>) {
+        let Some(poll_receiver) = self.poll_receiver.lock().take() else {
+            error!("Polling was already started");
+            return;
+        };
+
+        let monitor = Arc::clone(self);
+        let thread = thread::spawn(move || scheduler::run(&monitor, poll_receiver));
+        *self.poll_thread.lock() = Some(thread);
+    }
+
+    /// Waits for a read in progress to finish, then stops polling.
+    fn stop_polling(&self) {
+        let _ = self.poll_sender.send(PollRequest::Stop);
+        if let Some(thread) = self.poll_thread.lock().take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Stops polling, releases the devices and flushes the history, then
+    /// publishes `Stopped`. Only the first call does anything.
+    pub fn shutdown(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("Shutting down");
+
+        self.stop_polling();
+        self.backend.lock().close();
+        if let Some(history) = self.history.lock().as_ref() {
+            if let Err(err) = history.flush() {
+                warn!("Failed to flush battery history: {}", err);
+            }
+        }
+
+        self.bus.publish(self, MonitorEvent::Stopped);
+    }
+
+    /// Time since the devices were last enumerated, which keeps growing
+    /// while a device read hangs.
+    pub fn since_fetch(&self) -> Duration {
+        self.last_fetch.lock().elapsed()
+    }
+
+    fn publish(&self, events: Vec<MonitorEvent>) {
+        for event in events {
+            self.bus.publish(self, event);
+        }
+    }
+
+    /// Enumerates devices and returns the removed and newly connected ones.
+    pub fn fetch_devices(&self) -> (Vec<u32>, Vec<u32>) {
+        let (removed_devices, connected_devices, models) = {
+            let mut manager = self.backend.lock();
+            let (removed_devices, connected_devices) = manager.fetch_devices();
+            let models: Vec<Option<String>> = connected_devices
+                .iter()
+                .map(|&id| manager.get_device_name(id))
+                .collect();
+            (removed_devices, connected_devices, models)
+        };
+        *self.last_fetch.lock() = Instant::now();
+        let config = self.config.get();
+        let mut events = Vec::new();
+
+        let mut devices = self.devices.lock();
+        for &id in &removed_devices {
+            if let Some(device) = devices.remove(&id) {
+                info!("Device removed: {}", device.name);
+                events.push(MonitorEvent::DeviceRemoved {
+                    pid: id,
+                    name: device.name,
+                });
+            }
+        }
+
+        for (&id, model) in connected_devices.iter().zip(models) {
+            if let Entry::Vacant(entry) = devices.entry(id) {
+                if let Some(model) = model {
+                    let name = config.device_name(id, &model);
+                    entry.insert(MemoryDevice::new(name.clone(), model, id));
+                    info!("New device: {}", name);
+                    events.push(MonitorEvent::DeviceConnected { pid: id, name });
+                } else {
+                    error!("Failed to get device name for id: {}", id);
+                }
+            }
+        }
+        drop(devices);
+
+        self.publish(events);
+        (removed_devices, connected_devices)
+    }
+
+    /// Reads the battery of the given devices.
+    pub fn update(&self, device_ids: &[u32]) {
+        let events = self.read_batteries(device_ids);
+        self.publish(events);
+    }
+
+    fn read_batteries(&self, device_ids: &[u32]) -> Vec<MonitorEvent> {
+        let mut events = Vec::new();
+
+        for &id in device_ids {
+            let needs_serial = match self.devices.lock().get(&id) {
+                Some(device) => device.serial.is_none(),
+                None => continue,
+            };
+
+            // Only the backend stays locked for the HID round trip, which can
+            // take seconds of retries, so readers of `devices` don't wait
+            let reading = {
+                let manager = self.backend.lock();
+                match (
+                    manager.get_device_battery_raw(id),
+                    manager.is_device_charging(id),
+                    manager.get_device_connection(id),
+                ) {
+                    (Some(battery_raw), Some(is_charging), Some(connection)) => {
+                        let serial = needs_serial
+                            .then(|| manager.get_device_serial(id))
+                            .flatten();
+                        Some((battery_raw, is_charging, connection, serial))
+                    }
+                    _ => None,
+                }
+            };
+
+            let mut devices = self.devices.lock();
+            // Removed during the read
+            let Some(device) = devices.get_mut(&id) else {
+                continue;
+            };
+            let Some((battery_raw, is_charging, connection, serial)) = reading else {
+                events.push(MonitorEvent::Error {
+                    pid: id,
+                    name: device.name.clone(),
+                    was_available: device.is_available,
+                });
+                device.is_available = false;
+                continue;
+            };
+
+            let battery_level = battery_percentage(battery_raw);
+            info!("{}  battery level: {}%", device.name, battery_level);
+            info!("{}  charging status: {}", device.name, is_charging);
+
+            let was_charging = device.battery_level.map(|_| device.is_charging);
+            let timestamp = self.clock.now();
+            device.battery_level = Some(battery_level);
+            device.battery_raw = Some(battery_raw);
+            device.is_charging = is_charging;
+            device.is_available = true;
+            device.connection = Some(connection);
+            device.last_update = Some(timestamp);
+            if device.serial.is_none() {
+                device.serial = serial;
+            }
+            let name = device.name.clone();
+            drop(devices);
+
+            events.push(MonitorEvent::ReadingUpdated {
+                pid: id,
+                name: name.clone(),
+                timestamp,
+                level: battery_level,
+                raw: battery_raw,
+                is_charging,
+                connection,
+            });
+
+            // `None` for the first reading of a device
+            if was_charging.is_some_and(|was| was != is_charging) {
+                events.push(MonitorEvent::ChargingChanged {
+                    pid: id,
+                    name: name.clone(),
+                    level: battery_level,
+                    is_charging,
+                });
+            }
+
+            let fired = self
+                .rules
+                .lock()
+                .evaluate(id, battery_level, is_charging, timestamp);
+            for (rule, notification) in fired {
+                events.push(MonitorEvent::ThresholdCrossed {
+                    pid: id,
+                    name: name.clone(),
+                    level: battery_level,
+                    is_charging,
+                    rule,
+                    notification,
+                });
+            }
+        }
+
+        events
+    }
+
+    /// Re-enumerates devices and reads every battery as soon as possible.
+    pub fn request_poll(&self) {
+        let _ = self.poll_sender.send(PollRequest::All);
+    }
+
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    pub fn request_update(&self, pid: u32) {
+        let _ = self.poll_sender.send(PollRequest::Device(pid));
+    }
+
+    /// Every device sorted by name.
+    pub fn status(&self) -> Vec<DeviceStatus> {
+        let mut devices: Vec<DeviceStatus> = self
+            .devices
+            .lock()
+            .values()
+            .map(MemoryDevice::status)
+            .collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name).then(a.pid.cmp(&b.pid)));
+        devices
+    }
+
+    pub fn is_snoozed(&self) -> bool {
+        self.rules.lock().is_snoozed(self.clock.now())
+    }
+
+    /// Snoozes notifications, or resumes them if already snoozed. Returns
+    /// whether notifications are snoozed afterwards.
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    pub fn toggle_snooze(&self) -> bool {
+        let mut rules = self.rules.lock();
+        let now = self.clock.now();
+
+        if rules.is_snoozed(now) {
+            rules.resume();
+            false
+        } else {
+            rules.snooze(now);
+            true
+        }
+    }
+
+    pub fn history(&self, filter: &HistoryFilter) -> Result<Vec<HistoryRecord>, String> {
+        match self.history.lock().as_ref() {
+            Some(history) => history.query(filter).map_err(|err| err.to_string()),
+            None => Err("Battery history is unavailable".to_owned()),
+        }
+    }
+
+    /// Re-reads the configuration file and applies device names.
+    pub fn reload_config(&self) -> Result<(), String> {
+        self.config.set(Config::try_load()?);
+        info!("Config reloaded");
+
+        let config = self.config.get();
+        let pids: Vec<u32> = self.devices.lock().keys().copied().collect();
+        // Looked up first, so `devices` isn't locked while a read holds the
+        // backend
+        let models: Vec<(u32, String)> = {
+            let manager = self.backend.lock();
+            pids.into_iter()
+                .filter_map(|pid| Some((pid, manager.get_device_name(pid)?)))
+                .collect()
+        };
+        let mut devices = self.devices.lock();
+        for (pid, model) in models {
+            if let Some(device) = devices.get_mut(&pid) {
+                device.name = config.device_name(pid, &model);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Records every reading and updates the estimate from it.
+#[derive(Default)]
+struct HistoryRecorder {
+    estimator: Mutex<Estimator>,
+}
+
+impl Subscriber for HistoryRecorder {
+    fn handle(&self, monitor: &BatteryMonitor, event: &MonitorEvent) {
+        let &MonitorEvent::ReadingUpdated {
+            pid,
+            ref name,
+            timestamp,
+            level,
+            raw,
+            is_charging,
+            connection,
+        } = event
+        else {
+            return;
+        };
+
+        let estimate = {
+            let mut history = monitor.history.lock();
+            let Some(history) = history.as_mut() else {
+                return;
+            };
+
+            let record = HistoryRecord {
+                timestamp,
+                pid,
+                name: name.clone(),
+                level,
+                raw,
+                is_charging,
+                connection,
+            };
+            // Loads the device's history before the record is appended
+            let estimate = self.estimator.lock().add(history, record.clone());
+            if let Err(err) = history.append(&record) {
+                warn!("Failed to record battery reading: {}", err);
+            }
+
+            match estimate {
+                Ok(estimate) => estimate,
+                Err(err) => {
+                    warn!("Failed to estimate battery time: {}", err);
+                    return;
+                }
+            }
+        };
+
+        if let Some(device) = monitor.devices().get_mut(&pid) {
+            device.estimate = estimate;
+        }
+        if let Some(estimate) = estimate {
+            info!("{}  estimate: {}", name, estimate);
+        }
+    }
+}
+
+/// The publishers enabled in the config.
+fn state_publishers(config: &Config) -> Vec<Arc<dyn Subscriber>> {
+    let mut publishers: Vec<Arc<dyn Subscriber>> = Vec::new();
+
+    if let Some(broker) = &config.mqtt.broker {
+        publishers.push(Arc::new(MqttPublisher::start(&config.mqtt, broker)));
+    }
+
+    #[cfg(target_os = "linux")]
+    if config.dbus.enabled {
+        match crate::dbus::DbusService::start() {
+            Ok(service) => publishers.push(Arc::new(service)),
+            Err(err) => warn!("Failed to start D-Bus service: {}", err),
+        }
+    }
+
+    publishers
+}
+
+/// A scripted backend, a clock that only moves when told to and a subscriber
+/// that records events, for tests of the monitor and what is built on it.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::notify::RecordingNotifier;
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicU64;
+
+    #[derive(Debug, Clone)]
+    struct FakeDevice {
+        name: String,
+        /// `None` while the device doesn't answer.
+        raw: Option<u8>,
+        is_charging: bool,
+    }
+
+    /// Clones share the devices, so a test can change them while the monitor
+    /// owns the backend.
+    #[derive(Clone, Default)]
+    pub struct FakeBackend {
+        devices: Arc<Mutex<HashMap<u32, FakeDevice>>>,
+        /// Devices reported by the last `fetch_devices` of this clone.
+        known: HashSet<u32>,
+        closed: Arc<AtomicBool>,
+        on_read: Arc<Mutex<Option<ReadHook>>>,
+    }
+
+    type ReadHook = Box<dyn Fn() + Send>;
+
+    impl FakeBackend {
+        pub fn connect(&self, pid: u32, name: &str) {
+            let device = FakeDevice {
+                name: name.to_owned(),
+                raw: None,
+                is_charging: false,
+            };
+            self.devices.lock().insert(pid, device);
+        }
+
+        pub fn disconnect(&self, pid: u32) {
+            self.devices.lock().remove(&pid);
+        }
+
+        /// Sets the level in percent, as the closest raw battery byte.
+        pub fn set_level(&self, pid: u32, level: i32, is_charging: bool) {
+            if let Some(device) = self.devices.lock().get_mut(&pid) {
+                device.raw = Some((level as f32 * 255.0 / 100.0).round() as u8);
+                device.is_charging = is_charging;
+            }
+        }
+
+        /// Makes reads of the device fail, like a wireless device asleep.
+        pub fn set_asleep(&self, pid: u32) {
+            if let Some(device) = self.devices.lock().get_mut(&pid) {
+                device.raw = None;
+            }
+        }
+
+        /// Runs during every battery read, like a slow device would.
+        pub fn on_read(&self, hook: impl Fn() + Send + 'static) {
+            *self.on_read.lock() = Some(Box::new(hook));
+        }
+
+        pub fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::SeqCst)
+        }
+
+        fn device<T>(&self, pid: u32, read: impl Fn(&FakeDevice) -> Option<T>) -> Option<T> {
+            self.devices.lock().get(&pid).and_then(read)
+        }
+    }
+
+    impl DeviceBackend for FakeBackend {
+        fn fetch_devices(&mut self) -> (Vec<u32>, Vec<u32>) {
+            let current: HashSet<u32> = self.devices.lock().keys().copied().collect();
+            let mut removed: Vec<u32> = self.known.difference(&current).copied().collect();
+            let mut connected: Vec<u32> = current.difference(&self.known).copied().collect();
+            removed.sort_unstable();
+            connected.sort_unstable();
+            self.known = current;
+            (removed, connected)
+        }
+
+        fn get_device_name(&self, id: u32) -> Option<String> {
+            self.device(id, |device| Some(device.name.clone()))
+        }
+
+        fn get_device_connection(&self, id: u32) -> Option<ConnectionType> {
+            self.device(id, |device| device.raw.map(|_| ConnectionType::Wireless))
+        }
+
+        fn get_device_battery_raw(&self, id: u32) -> Option<u8> {
+            if let Some(hook) = &*self.on_read.lock() {
+                hook();
+            }
+            self.device(id, |device| device.raw)
+        }
+
+        fn is_device_charging(&self, id: u32) -> Option<bool> {
+            self.device(id, |device| device.raw.map(|_| device.is_charging))
+        }
+
+        fn get_device_serial(&self, id: u32) -> Option<String> {
+            self.device(id, |_| Some(format!("PM{:04X}", id)))
+        }
+
+        fn close(&mut self) {
+            self.closed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub struct FixedClock(AtomicU64);
+
+    impl FixedClock {
+        pub fn advance(&self, seconds: u64) {
+            self.0.fetch_add(seconds, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for FixedClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Keeps every event published on the bus.
+    #[derive(Default)]
+    pub struct Recorder(Mutex<Vec<MonitorEvent>>);
+
+    impl Recorder {
+        pub fn take(&self) -> Vec<MonitorEvent> {
+            std::mem::take(&mut *self.0.lock())
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn handle(&self, _monitor: &BatteryMonitor, event: &MonitorEvent) {
+            self.0.lock().push(event.clone());
+        }
+    }
+
+    pub struct TestMonitor {
+        pub monitor: Arc<BatteryMonitor>,
+        pub backend: FakeBackend,
+        pub clock: Arc<FixedClock>,
+        pub events: Arc<Recorder>,
+    }
+
+    /// A monitor over a `FakeBackend`, with the clock at 1 000 000. Device
+    /// events are dispatched too, with notifications only recorded.
+    pub fn monitor(config: Config) -> TestMonitor {
+        let config = SharedConfig::new(config);
+        let backend = FakeBackend::default();
+        let clock = Arc::new(FixedClock(AtomicU64::new(1_000_000)));
+        let events = Arc::new(Recorder::default());
+
+        let mut monitor =
+            BatteryMonitor::with_backend(config.clone(), Box::new(backend.clone()), clock.clone());
+        monitor.events = Arc::new(EventDispatcher::with_notifier(
+            config,
+            Box::new(RecordingNotifier::default()),
+        ));
+        monitor.bus.add(events.clone());
+        monitor.bus.add(monitor.events.clone());
+
+        TestMonitor {
+            monitor: Arc::new(monitor),
+            backend,
+            clock,
+            events,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{self, TestMonitor};
+    use super::*;
+    use crate::notify::NotificationKind;
+
+    const PID: u32 = 0x00B7;
+    const NAME: &str = "Razer DeathAdder V3 Pro";
+
+    fn connected() -> TestMonitor {
+        let test = testing::monitor(Config::default());
+        test.backend.connect(PID, NAME);
+        test.monitor.fetch_devices();
+        test.events.take();
+        test
+    }
+
+    /// Reads the device at a level and returns the rules that fired.
+    fn read(test: &TestMonitor, level: i32, is_charging: bool) -> Vec<String> {
+        test.backend.set_level(PID, level, is_charging);
+        test.clock.advance(60);
+        test.monitor.update(&[PID]);
+        test.events
+            .take()
+            .into_iter()
+            .filter_map(|event| match event {
+                MonitorEvent::ThresholdCrossed { rule, .. } => Some(rule),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn connecting_and_removing_devices() {
+        let test = testing::monitor(Config::default());
+        test.backend.connect(PID, NAME);
+
+        assert_eq!(test.monitor.fetch_devices(), (vec![], vec![PID]));
+        assert_eq!(
+            test.events.take(),
+            [MonitorEvent::DeviceConnected {
+                pid: PID,
+                name: NAME.to_owned()
+            }]
+        );
+        // Nothing changed since
+        assert_eq!(test.monitor.fetch_devices(), (vec![], vec![]));
+        assert!(test.events.take().is_empty());
+
+        test.backend.disconnect(PID);
+        assert_eq!(test.monitor.fetch_devices(), (vec![PID], vec![]));
+        assert_eq!(
+            test.events.take(),
+            [MonitorEvent::DeviceRemoved {
+                pid: PID,
+                name: NAME.to_owned()
+            }]
+        );
+        assert!(test.monitor.devices().is_empty());
+    }
+
+    #[test]
+    fn configured_names_replace_the_model() {
+        let config = toml::from_str("[devices.\"0x00B7\"]\nname = \"Desk mouse\"").unwrap();
+        let test = testing::monitor(config);
+        test.backend.connect(PID, NAME);
+        test.monitor.fetch_devices();
+
+        let devices = test.monitor.devices();
+        assert_eq!(devices[&PID].name, "Desk mouse");
+        assert_eq!(devices[&PID].model, NAME);
+    }
+
+    #[test]
+    fn readings_update_the_device() {
+        let test = connected();
+        test.backend.set_level(PID, 80, false);
+        test.monitor.update(&[PID]);
+
+        assert_eq!(
+            test.events.take(),
+            [MonitorEvent::ReadingUpdated {
+                pid: PID,
+                name: NAME.to_owned(),
+                timestamp: 1_000_000,
+                level: 80,
+                raw: 204,
+                is_charging: false,
+                connection: ConnectionType::Wireless,
+            }]
+        );
+
+        let devices = test.monitor.devices();
+        let device = &devices[&PID];
+        assert_eq!(device.battery_level, Some(80));
+        assert!(device.is_available);
+        assert_eq!(device.last_update, Some(1_000_000));
+        assert_eq!(device.serial.as_deref(), Some("PM00B7"));
+    }
+
+    #[test]
+    fn charging_changes_follow_the_reading() {
+        let test = connected();
+        let charging_changes = |test: &TestMonitor, level, is_charging| {
+            test.backend.set_level(PID, level, is_charging);
+            test.monitor.update(&[PID]);
+            test.events
+                .take()
+                .into_iter()
+                .filter(|event| matches!(event, MonitorEvent::ChargingChanged { .. }))
+                .collect::<Vec<_>>()
+        };
+
+        // The first reading has nothing to compare with
+        assert!(charging_changes(&test, 50, true).is_empty());
+        assert!(charging_changes(&test, 51, true).is_empty());
+        assert_eq!(
+            charging_changes(&test, 52, false),
+            [MonitorEvent::ChargingChanged {
+                pid: PID,
+                name: NAME.to_owned(),
+                level: 52,
+                is_charging: false,
+            }]
+        );
+        assert_eq!(charging_changes(&test, 52, true).len(), 1);
+    }
+
+    #[test]
+    fn devices_stay_readable_during_reads() {
+        let test = connected();
+        let monitor = Arc::downgrade(&test.monitor);
+        let readable = Arc::new(AtomicBool::new(false));
+        let was_readable = Arc::clone(&readable);
+        test.backend.on_read(move || {
+            let monitor = monitor.upgrade().unwrap();
+            was_readable.store(monitor.devices.try_lock().is_some(), Ordering::SeqCst);
+        });
+
+        read(&test, 80, false);
+        assert!(readable.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn failed_reads_report_the_device_asleep_once() {
+        let test = connected();
+        read(&test, 80, false);
+
+        test.backend.set_asleep(PID);
+        test.monitor.update(&[PID]);
+        test.monitor.update(&[PID]);
+
+        let error = |was_available| MonitorEvent::Error {
+            pid: PID,
+            name: NAME.to_owned(),
+            was_available,
+        };
+        assert_eq!(test.events.take(), [error(true), error(false)]);
+        let devices = test.monitor.devices();
+        assert!(!devices[&PID].is_available);
+        // The last reading is kept
+        assert_eq!(devices[&PID].battery_level, Some(80));
+    }
+
+    #[test]
+    fn threshold_crossings_fire_with_their_notification() {
+        let test = connected();
+        read(&test, 20, false);
+        test.backend.set_level(PID, 15, false);
+        test.monitor.update(&[PID]);
+
+        assert!(test
+            .events
+            .take()
+            .contains(&MonitorEvent::ThresholdCrossed {
+                pid: PID,
+                name: NAME.to_owned(),
+                level: 15,
+                is_charging: false,
+                rule: "low:15".to_owned(),
+                notification: NotificationKind::Low,
+            }));
+    }
+
+    #[test]
+    fn default_rules_fire_once_per_crossing() {
+        let test = connected();
+
+        assert!(read(&test, 50, false).is_empty());
+        assert_eq!(read(&test, 15, false), ["low:15"]);
+        assert!(read(&test, 10, false).is_empty());
+        assert_eq!(read(&test, 5, false), ["critical:5"]);
+        assert!(read(&test, 3, false).is_empty());
+        // Charging doesn't fire the low rules
+        assert!(read(&test, 3, true).is_empty());
+        assert!(read(&test, 99, true).is_empty());
+        assert_eq!(read(&test, 100, true), ["charged:100"]);
+        assert!(read(&test, 100, true).is_empty());
+    }
+
+    #[test]
+    fn rules_rearm_after_hysteresis() {
+        let test = connected();
+
+        assert_eq!(read(&test, 15, false), ["low:15"]);
+        // Bouncing around the threshold, within the default hysteresis of 2
+        assert!(read(&test, 16, false).is_empty());
+        assert!(read(&test, 15, false).is_empty());
+        assert!(read(&test, 16, true).is_empty());
+        assert!(read(&test, 14, false).is_empty());
+        // Back above it rearms
+        assert!(read(&test, 17, true).is_empty());
+        assert_eq!(read(&test, 15, false), ["low:15"]);
+    }
+
+    #[test]
+    fn repeating_rules_fire_again_after_their_interval() {
+        let config = toml::from_str(
+            "[notifications]\nrules = [{ kind = \"low\", level = 20, repeat = \"10m\" }]",
+        )
+        .unwrap();
+        let test = testing::monitor(config);
+        test.backend.connect(PID, NAME);
+        test.monitor.fetch_devices();
+
+        // `read` advances the clock by a minute each time
+        assert_eq!(read(&test, 20, false), ["low:20"]);
+        for _ in 0..9 {
+            assert!(read(&test, 19, false).is_empty());
+        }
+        assert_eq!(read(&test, 18, false), ["low:20"]);
+    }
+
+    #[test]
+    fn charge_targets_notify_before_full() {
+        let config =
+            toml::from_str("[notifications]\nrules = [{ kind = \"charged\", level = 80 }]")
+                .unwrap();
+        let test = testing::monitor(config);
+        test.backend.connect(PID, NAME);
+        test.monitor.fetch_devices();
+        test.backend.set_level(PID, 80, true);
+        test.monitor.update(&[PID]);
+
+        let notifications: Vec<_> = test
+            .events
+            .take()
+            .into_iter()
+            .filter_map(|event| match event {
+                MonitorEvent::ThresholdCrossed { notification, .. } => Some(notification),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notifications, [NotificationKind::ChargeReached]);
+    }
+
+    #[test]
+    fn shutdown_closes_the_backend_once() {
+        let test = connected();
+
+        test.monitor.shutdown();
+        assert!(test.backend.is_closed());
+        assert_eq!(test.events.take(), [MonitorEvent::Stopped]);
+
+        test.monitor.shutdown();
+        assert!(test.events.take().is_empty());
+    }
+}
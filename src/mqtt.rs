@@ -0,0 +1,635 @@
+//! Publishes device state to an MQTT broker as retained messages, with Home
+//! Assistant discovery so devices show up as battery sensors on their own.
+//!
+//! Speaks just enough MQTT 3.1.1 for that: QoS 0 publishes, keep-alive pings
+//! and a last will that marks the app offline.
+
+use chrono::{TimeZone, Utc};
+use log::{info, trace, warn};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::MqttConfig;
+use crate::ipc::DeviceStatus;
+use crate::monitor::{MemoryDevice, StatePublisher};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+enum Message {
+    Update(Device),
+    Removed(u32),
+}
+
+/// Handle to the publisher thread, which connects and reconnects on its own.
+pub struct MqttPublisher {
+    sender: Sender<Message>,
+}
+
+impl MqttPublisher {
+    pub fn start(config: &MqttConfig, broker: &str) -> Self {
+        Self::spawn(config, broker, RECONNECT_INTERVAL)
+    }
+
+    fn spawn(config: &MqttConfig, broker: &str, reconnect_interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let mut publisher = Publisher {
+            broker: broker.to_owned(),
+            config: config.clone(),
+            reconnect_interval,
+            devices: BTreeMap::new(),
+            receiver,
+        };
+        thread::spawn(move || publisher.run());
+        Self { sender }
+    }
+}
+
+impl StatePublisher for MqttPublisher {
+    fn update(&self, device: &MemoryDevice) {
+        let _ = self.sender.send(Message::Update(Device {
+            status: device.status(),
+            model: device.model.clone(),
+        }));
+    }
+
+    fn removed(&self, pid: u32) {
+        let _ = self.sender.send(Message::Removed(pid));
+    }
+}
+
+struct Device {
+    status: DeviceStatus,
+    model: String,
+}
+
+struct Publisher {
+    broker: String,
+    config: MqttConfig,
+    reconnect_interval: Duration,
+    /// The last state of every connected device, republished after reconnecting.
+    devices: BTreeMap<u32, Device>,
+    receiver: Receiver<Message>,
+}
+
+impl Publisher {
+    fn run(&mut self) {
+        loop {
+            match Connection::open(&self.broker, &self.config, &self.status_topic()) {
+                Ok(mut connection) => {
+                    info!("Connected to MQTT broker {}", self.broker);
+                    match self.publish_all(&mut connection) {
+                        Ok(true) => return,
+                        Ok(false) => (),
+                        Err(err) => warn!("MQTT connection to {} lost: {}", self.broker, err),
+                    }
+                }
+                Err(err) => warn!("Failed to connect to MQTT broker {}: {}", self.broker, err),
+            }
+
+            // Keep track of devices meanwhile, so the state is current once
+            // the broker is back
+            let deadline = Instant::now() + self.reconnect_interval;
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                match self.receiver.recv_timeout(timeout) {
+                    Ok(message) => self.apply(message),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, message: Message) {
+        match message {
+            Message::Update(device) => {
+                self.devices.insert(device.status.pid, device);
+            }
+            Message::Removed(pid) => {
+                self.devices.remove(&pid);
+            }
+        }
+    }
+
+    /// Publishes everything known, then every change until the connection
+    /// fails. Returns `true` once there is nothing left to publish.
+    fn publish_all(&mut self, connection: &mut Connection) -> io::Result<bool> {
+        connection.publish(&self.status_topic(), b"online")?;
+        for device in self.devices.values() {
+            self.publish_discovery(connection, device)?;
+            self.publish_state(connection, &device.status)?;
+        }
+
+        loop {
+            match self.receiver.recv_timeout(KEEP_ALIVE / 2) {
+                // Kept first, so a failed publish is retried after reconnecting
+                Ok(Message::Update(device)) => {
+                    let pid = device.status.pid;
+                    let is_new = self.devices.insert(pid, device).is_none();
+                    let device = &self.devices[&pid];
+                    if is_new {
+                        self.publish_discovery(connection, device)?;
+                    }
+                    self.publish_state(connection, &device.status)?;
+                }
+                Ok(Message::Removed(pid)) => {
+                    if self.devices.remove(&pid).is_some() {
+                        connection.publish(&self.availability_topic(pid), b"offline")?;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => connection.ping()?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(true),
+            }
+        }
+    }
+
+    fn publish_state(&self, connection: &mut Connection, device: &DeviceStatus) -> io::Result<()> {
+        let last_seen = device
+            .last_update
+            .and_then(|timestamp| Utc.timestamp_opt(timestamp as i64, 0).single())
+            .map(|time| time.to_rfc3339());
+        let state = json!({
+            "name": device.name,
+            "level": device.level,
+            "charging": device.is_charging,
+            "available": device.is_available,
+            "connection": device.connection,
+            "last_seen": last_seen,
+        });
+
+        connection.publish(&self.availability_topic(device.pid), b"online")?;
+        connection.publish(&self.state_topic(device.pid), state.to_string().as_bytes())
+    }
+
+    /// One Home Assistant entity per value, grouped into a device.
+    fn publish_discovery(&self, connection: &mut Connection, device: &Device) -> io::Result<()> {
+        if !self.config.discovery {
+            return Ok(());
+        }
+
+        let Device { status, model } = device;
+        let object_id = format!("razer_{:04x}", status.pid);
+        let device_info = json!({
+            "identifiers": [object_id],
+            "name": status.name,
+            "manufacturer": "Razer",
+            "model": model,
+        });
+        let entities = [
+            (
+                "sensor",
+                "battery",
+                json!({
+                    "name": "Battery",
+                    "device_class": "battery",
+                    "unit_of_measurement": "%",
+                    "state_class": "measurement",
+                    "value_template": "{{ value_json.level }}",
+                }),
+            ),
+            (
+                "binary_sensor",
+                "charging",
+                json!({
+                    "name": "Charging",
+                    "device_class": "battery_charging",
+                    "value_template": "{{ 'ON' if value_json.charging else 'OFF' }}",
+                }),
+            ),
+            (
+                "sensor",
+                "connection",
+                json!({
+                    "name": "Connection",
+                    "entity_category": "diagnostic",
+                    "value_template": "{{ value_json.connection }}",
+                }),
+            ),
+            (
+                "sensor",
+                "last_seen",
+                json!({
+                    "name": "Last seen",
+                    "device_class": "timestamp",
+                    "entity_category": "diagnostic",
+                    "value_template": "{{ value_json.last_seen }}",
+                }),
+            ),
+        ];
+
+        for (component, key, mut entity) in entities {
+            entity["unique_id"] = json!(format!("{}_{}", object_id, key));
+            entity["state_topic"] = json!(self.state_topic(status.pid));
+            entity["availability"] = json!([
+                { "topic": self.status_topic() },
+                { "topic": self.availability_topic(status.pid) },
+            ]);
+            entity["availability_mode"] = json!("all");
+            entity["device"] = device_info.clone();
+
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                self.config.discovery_prefix, component, object_id, key
+            );
+            connection.publish(&topic, entity.to_string().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.config.topic_prefix)
+    }
+
+    fn state_topic(&self, pid: u32) -> String {
+        format!("{}/{:04x}/state", self.config.topic_prefix, pid)
+    }
+
+    fn availability_topic(&self, pid: u32) -> String {
+        format!("{}/{:04x}/availability", self.config.topic_prefix, pid)
+    }
+}
+
+/// A connection to the broker that publishes retained QoS 0 messages.
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    /// Connects with a retained last will of `offline` on `status_topic`.
+    fn open(broker: &str, config: &MqttConfig, status_topic: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(broker)?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        let mut connection = Self { stream };
+
+        // Clean session, retained QoS 0 will
+        let mut flags = 0x02 | 0x04 | 0x20;
+        let mut payload = Vec::new();
+        write_string(&mut payload, &config.client_id);
+        write_string(&mut payload, status_topic);
+        write_string(&mut payload, "offline");
+        if let Some(username) = &config.username {
+            flags |= 0x80;
+            write_string(&mut payload, username);
+        }
+        if let Some(password) = &config.password {
+            flags |= 0x40;
+            write_string(&mut payload, password);
+        }
+
+        let mut body = Vec::new();
+        write_string(&mut body, "MQTT");
+        body.push(4); // Protocol level 3.1.1
+        body.push(flags);
+        body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        body.extend_from_slice(&payload);
+        connection.send(0x10, &body)?;
+
+        let mut connack = [0; 4];
+        connection.stream.read_exact(&mut connack)?;
+        match connack {
+            [0x20, 0x02, _, 0] => Ok(connection),
+            [0x20, 0x02, _, code] => Err(io::Error::other(format!(
+                "connection refused ({})",
+                connack_error(code)
+            ))),
+            _ => Err(io::Error::other("unexpected response to CONNECT")),
+        }
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        trace!("MQTT publish {}", topic);
+        let mut body = Vec::new();
+        write_string(&mut body, topic);
+        body.extend_from_slice(payload);
+        // PUBLISH, QoS 0, retained
+        self.send(0x31, &body)
+    }
+
+    /// Keeps the connection alive and notices a broker that went away.
+    fn ping(&mut self) -> io::Result<()> {
+        self.send(0xC0, &[])?;
+        let mut response = [0; 2];
+        self.stream.read_exact(&mut response)?;
+        if response != [0xD0, 0x00] {
+            return Err(io::Error::other("unexpected response to PINGREQ"));
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> io::Result<()> {
+        let mut packet = vec![header];
+        // Remaining length, 7 bits per byte
+        let mut length = body.len();
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+            if length > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn connack_error(code: u8) -> &'static str {
+    match code {
+        1 => "unacceptable protocol version",
+        2 => "client id rejected",
+        3 => "server unavailable",
+        4 => "bad username or password",
+        5 => "not authorized",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::monitor::testing::{self, TestMonitor};
+    use serde_json::Value;
+    use std::net::TcpListener;
+
+    const PID: u32 = 0x00B7;
+
+    /// Accepts the publisher and decodes what it sends.
+    struct FakeBroker {
+        listener: TcpListener,
+    }
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl FakeBroker {
+        fn start() -> Self {
+            Self {
+                listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            }
+        }
+
+        fn address(&self) -> String {
+            self.listener.local_addr().unwrap().to_string()
+        }
+
+        fn accept(&self) -> Client {
+            let (stream, _) = self.listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            Client { stream }
+        }
+    }
+
+    impl Client {
+        fn read_packet(&mut self) -> (u8, Vec<u8>) {
+            let mut header = [0; 1];
+            self.stream.read_exact(&mut header).unwrap();
+
+            let mut length = 0;
+            for shift in (0..4).map(|byte| byte * 7) {
+                let mut byte = [0; 1];
+                self.stream.read_exact(&mut byte).unwrap();
+                length |= usize::from(byte[0] & 0x7F) << shift;
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+            }
+
+            let mut body = vec![0; length];
+            self.stream.read_exact(&mut body).unwrap();
+            (header[0], body)
+        }
+
+        /// Reads the CONNECT packet and accepts it, returning its flags and
+        /// strings: client id, will topic and message, then credentials.
+        fn connect(&mut self) -> (u8, Vec<String>) {
+            let (header, body) = self.read_packet();
+            assert_eq!(header, 0x10);
+
+            let mut body = body.as_slice();
+            assert_eq!(read_string(&mut body), "MQTT");
+            assert_eq!(body[0], 4);
+            let flags = body[1];
+            assert_eq!(u16::from_be_bytes([body[2], body[3]]), 60);
+            body = &body[4..];
+
+            let mut strings = Vec::new();
+            while !body.is_empty() {
+                strings.push(read_string(&mut body));
+            }
+
+            self.stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            (flags, strings)
+        }
+
+        /// Topic and payload of a retained publish.
+        fn publish(&mut self) -> (String, String) {
+            let (header, body) = self.read_packet();
+            assert_eq!(header, 0x31, "not a retained QoS 0 publish");
+
+            let mut body = body.as_slice();
+            let topic = read_string(&mut body);
+            (topic, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        /// Discovery config topics and entities, then availability and state.
+        fn device(&mut self) -> (Vec<(String, Value)>, String, Value) {
+            let discovery = (0..4)
+                .map(|_| {
+                    let (topic, payload) = self.publish();
+                    (topic, serde_json::from_str(&payload).unwrap())
+                })
+                .collect();
+            assert_eq!(
+                self.publish(),
+                (
+                    "razer-battery-report/00b7/availability".to_owned(),
+                    "online".to_owned()
+                )
+            );
+            let (topic, state) = self.publish();
+            (discovery, topic, serde_json::from_str(&state).unwrap())
+        }
+    }
+
+    fn read_string(body: &mut &[u8]) -> String {
+        let length = usize::from(u16::from_be_bytes([body[0], body[1]]));
+        let value = String::from_utf8(body[2..2 + length].to_vec()).unwrap();
+        *body = &body[2 + length..];
+        value
+    }
+
+    fn read_device(level: i32, is_charging: bool) -> TestMonitor {
+        let test = testing::monitor(Config::default());
+        test.backend.connect(PID, "Razer DeathAdder V3 Pro");
+        test.backend.set_level(PID, level, is_charging);
+        test.monitor.fetch_devices();
+        test.monitor.update(&[PID]);
+        test
+    }
+
+    fn start(broker: &FakeBroker, config: &MqttConfig) -> MqttPublisher {
+        MqttPublisher::spawn(config, &broker.address(), Duration::from_millis(10))
+    }
+
+    #[test]
+    fn connects_with_a_last_will() {
+        let broker = FakeBroker::start();
+        let config = MqttConfig {
+            username: Some("user".to_owned()),
+            password: Some("pass".to_owned()),
+            ..MqttConfig::default()
+        };
+        let _publisher = start(&broker, &config);
+        let mut client = broker.accept();
+
+        let (flags, strings) = client.connect();
+        // Credentials, retained will, clean session
+        assert_eq!(flags, 0x80 | 0x40 | 0x20 | 0x04 | 0x02);
+        assert_eq!(
+            strings,
+            [
+                "razer-battery-report",
+                "razer-battery-report/status",
+                "offline",
+                "user",
+                "pass"
+            ]
+        );
+        assert_eq!(
+            client.publish(),
+            (
+                "razer-battery-report/status".to_owned(),
+                "online".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn devices_get_discovery_and_state() {
+        let broker = FakeBroker::start();
+        let publisher = start(&broker, &MqttConfig::default());
+        let mut client = broker.accept();
+        client.connect();
+        client.publish();
+
+        let test = read_device(80, true);
+        publisher.update(&test.monitor.devices()[&PID]);
+        let (discovery, topic, state) = client.device();
+
+        let topics: Vec<&str> = discovery.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/razer_00b7/battery/config",
+                "homeassistant/binary_sensor/razer_00b7/charging/config",
+                "homeassistant/sensor/razer_00b7/connection/config",
+                "homeassistant/sensor/razer_00b7/last_seen/config",
+            ]
+        );
+        let battery = &discovery[0].1;
+        assert_eq!(battery["unique_id"], "razer_00b7_battery");
+        assert_eq!(battery["device_class"], "battery");
+        assert_eq!(battery["state_topic"], "razer-battery-report/00b7/state");
+        assert_eq!(battery["device"]["model"], "Razer DeathAdder V3 Pro");
+        assert_eq!(battery["availability_mode"], "all");
+
+        assert_eq!(topic, "razer-battery-report/00b7/state");
+        assert_eq!(state["level"], 80);
+        assert_eq!(state["charging"], true);
+        assert_eq!(state["available"], true);
+        assert_eq!(state["connection"], "wireless");
+        assert_eq!(state["last_seen"], "1970-01-12T13:46:40+00:00");
+
+        // Known devices only get their state
+        publisher.update(&test.monitor.devices()[&PID]);
+        client.publish();
+        assert_eq!(client.publish().0, "razer-battery-report/00b7/state");
+
+        publisher.removed(PID);
+        assert_eq!(
+            client.publish(),
+            (
+                "razer-battery-report/00b7/availability".to_owned(),
+                "offline".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn discovery_can_be_turned_off() {
+        let broker = FakeBroker::start();
+        let config = MqttConfig {
+            discovery: false,
+            ..MqttConfig::default()
+        };
+        let publisher = start(&broker, &config);
+        let mut client = broker.accept();
+        client.connect();
+        client.publish();
+
+        publisher.update(&read_device(80, false).monitor.devices()[&PID]);
+        assert_eq!(client.publish().1, "online");
+        assert_eq!(client.publish().0, "razer-battery-report/00b7/state");
+    }
+
+    #[test]
+    fn state_is_republished_after_reconnecting() {
+        let broker = FakeBroker::start();
+        let publisher = start(&broker, &MqttConfig::default());
+        let mut client = broker.accept();
+        client.connect();
+        client.publish();
+
+        let test = read_device(80, false);
+        publisher.update(&test.monitor.devices()[&PID]);
+        client.device();
+        drop(client);
+
+        // Writes notice the closed connection, and the readings meanwhile
+        // are kept for the next one
+        broker.listener.set_nonblocking(true).unwrap();
+        let mut level = 80;
+        let (stream, _) = loop {
+            match broker.listener.accept() {
+                Ok(connection) => break connection,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    level -= 1;
+                    test.backend.set_level(PID, level, false);
+                    test.monitor.update(&[PID]);
+                    publisher.update(&test.monitor.devices()[&PID]);
+                    thread::sleep(Duration::from_millis(20));
+                }
+                Err(err) => panic!("{}", err),
+            }
+        };
+        stream.set_nonblocking(false).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = Client { stream };
+
+        client.connect();
+        assert_eq!(client.publish().1, "online");
+        let (discovery, _, state) = client.device();
+        assert_eq!(discovery.len(), 4);
+        assert!(state["level"].as_i64().unwrap() < 80);
+    }
+}
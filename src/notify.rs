@@ -0,0 +1,175 @@
+use log::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationKind {
+    Low,
+    Critical,
+    Full,
+    ChargeReached,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub device: String,
+    pub level: Option<i32>,
+}
+
+impl Notification {
+    pub fn new(kind: NotificationKind, device: &str, level: Option<i32>) -> Self {
+        Self {
+            kind,
+            device: device.to_owned(),
+            level,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self.kind {
+            NotificationKind::Low => "Battery low",
+            NotificationKind::Critical => "Battery critically low",
+            NotificationKind::Full => "Battery fully charged",
+            NotificationKind::ChargeReached => "Battery charge target reached",
+            NotificationKind::Connected => "Device connected",
+            NotificationKind::Disconnected => "Device disconnected",
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match self.level {
+            Some(level) => format!("{}: {}%", self.device, level),
+            None => self.device.clone(),
+        }
+    }
+}
+
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Picks the desktop notification backend for the current platform, falling
+/// back to the log if it is unavailable.
+#[cfg(target_os = "linux")]
+pub fn default_notifier() -> Box<dyn Notifier> {
+    match DbusNotifier::new() {
+        Ok(notifier) => Box::new(notifier),
+        Err(err) => {
+            log::warn!("Failed to connect to the notification service: {}", err);
+            Box::new(LogNotifier)
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn default_notifier() -> Box<dyn Notifier> {
+    Box::new(ToastNotifier)
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn default_notifier() -> Box<dyn Notifier> {
+    Box::new(LogNotifier)
+}
+
+/// Only writes notifications to the log.
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<dyn std::error::Error>> {
+        info!("{}: {}", notification.title(), notification.body());
+        Ok(())
+    }
+}
+
+/// Keeps every notification in memory instead of showing it. Clones share
+/// the notifications.
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub struct RecordingNotifier {
+    notifications: std::sync::Arc<parking_lot::Mutex<Vec<Notification>>>,
+}
+
+#[cfg(test)]
+impl RecordingNotifier {
+    pub fn take(&self) -> Vec<Notification> {
+        std::mem::take(&mut *self.notifications.lock())
+    }
+}
+
+#[cfg(test)]
+impl Notifier for RecordingNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<dyn std::error::Error>> {
+        self.notifications.lock().push(notification.clone());
+        Ok(())
+    }
+}
+
+/// Sends notifications through the freedesktop notification service.
+#[cfg(target_os = "linux")]
+pub struct DbusNotifier {
+    connection: zbus::blocking::Connection,
+}
+
+#[cfg(target_os = "linux")]
+impl DbusNotifier {
+    pub fn new() -> zbus::Result<Self> {
+        Ok(Self {
+            connection: zbus::blocking::Connection::session()?,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier for DbusNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<dyn std::error::Error>> {
+        use std::collections::HashMap;
+        use zbus::zvariant::Value;
+
+        let (icon, urgency) = match notification.kind {
+            NotificationKind::Low => ("battery-low", 1u8),
+            NotificationKind::Critical => ("battery-caution", 2u8),
+            NotificationKind::Full => ("battery-full-charged", 1u8),
+            NotificationKind::ChargeReached => ("battery-good-charging", 1u8),
+            NotificationKind::Connected | NotificationKind::Disconnected => ("input-mouse", 0u8),
+        };
+        let hints: HashMap<&str, Value> = HashMap::from([("urgency", Value::U8(urgency))]);
+
+        self.connection.call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &(
+                "Razer Battery Report",
+                0u32,
+                icon,
+                notification.title(),
+                notification.body(),
+                Vec::<&str>::new(),
+                hints,
+                -1i32,
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Shows notifications as Windows toasts.
+#[cfg(windows)]
+pub struct ToastNotifier;
+
+#[cfg(windows)]
+impl Notifier for ToastNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<dyn std::error::Error>> {
+        use tauri_winrt_notification::Toast;
+
+        Toast::new(Toast::POWERSHELL_APP_ID)
+            .title(notification.title())
+            .text1(&notification.body())
+            .show()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::{Rule, RuleKind, SharedConfig};
+use crate::history::data_dir;
+use crate::notify::NotificationKind;
+
+const STATE_FILE_NAME: &str = "notification_state.json";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct RuleState {
+    /// Whether the rule may fire the next time its condition holds.
+    armed: bool,
+    last_fired: u64,
+}
+
+impl Default for RuleState {
+    fn default() -> Self {
+        Self {
+            armed: true,
+            last_fired: 0,
+        }
+    }
+}
+
+/// De-duplication state, persisted so restarts don't repeat notifications.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct NotificationState {
+    snoozed_until: u64,
+    /// Rule states keyed by product id (hex) and rule key.
+    devices: HashMap<String, HashMap<String, RuleState>>,
+}
+
+pub struct RuleEngine {
+    config: SharedConfig,
+    state: NotificationState,
+    state_path: Option<PathBuf>,
+}
+
+impl RuleEngine {
+    pub fn load(config: SharedConfig) -> Self {
+        let state_path = data_dir().map(|dir| dir.join(STATE_FILE_NAME));
+
+        let state = state_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| match serde_json::from_str(&content) {
+                Ok(state) => Some(state),
+                Err(err) => {
+                    warn!("Discarding invalid notification state: {}", err);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        Self {
+            config,
+            state,
+            state_path,
+        }
+    }
+
+    /// Keeps the state for this run only.
+    pub fn in_memory(config: SharedConfig) -> Self {
+        Self {
+            config,
+            state: NotificationState::default(),
+            state_path: None,
+        }
+    }
+
+    pub fn is_snoozed(&self, now: u64) -> bool {
+        now < self.state.snoozed_until
+    }
+
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    pub fn snooze(&mut self, now: u64) {
+        let duration = self.config.get().notifications.snooze;
+        self.state.snoozed_until = now + duration.as_secs();
+        log::info!(
+            "Notifications snoozed for {}",
+            crate::config::format_duration(duration)
+        );
+        self.save();
+    }
+
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    pub fn resume(&mut self) {
+        self.state.snoozed_until = 0;
+        log::info!("Notifications resumed");
+        self.save();
+    }
+
+    /// Updates every rule of the device with a new reading and returns the key
+    /// and notification of every rule that fired. Nothing fires while snoozed,
+    /// so a threshold crossed meanwhile fires with the first reading after.
+    pub fn evaluate(
+        &mut self,
+        pid: u32,
+        level: i32,
+        is_charging: bool,
+        now: u64,
+    ) -> Vec<(String, NotificationKind)> {
+        let device_state = self
+            .state
+            .devices
+            .entry(format!("{:04X}", pid))
+            .or_default();
+
+        let snoozed = now < self.state.snoozed_until;
+        let config = self.config.get();
+        let mut fired = Vec::new();
+        let mut changed = false;
+
+        for rule in config.rules(pid) {
+            let state = device_state.entry(rule.key()).or_default();
+            let before = *state;
+
+            if Self::check_rule(rule, state, level, is_charging, snoozed, now) {
+                state.last_fired = now;
+                fired.push((rule.key(), Self::notification_kind(rule, level)));
+            }
+
+            changed |= state.armed != before.armed || state.last_fired != before.last_fired;
+        }
+
+        if changed {
+            self.save();
+        }
+
+        fired
+    }
+
+    /// Advances the rule's state and reports whether it fires.
+    fn check_rule(
+        rule: &Rule,
+        state: &mut RuleState,
+        level: i32,
+        is_charging: bool,
+        snoozed: bool,
+        now: u64,
+    ) -> bool {
+        let (triggered, rearmed) = match rule.kind {
+            RuleKind::Low | RuleKind::Critical => (
+                !is_charging && level <= rule.level,
+                level >= rule.level + rule.hysteresis,
+            ),
+            RuleKind::Charged => (
+                is_charging && level >= rule.level,
+                level <= rule.level - rule.hysteresis,
+            ),
+        };
+
+        if rearmed {
+            state.armed = true;
+        }
+
+        // Stays armed while snoozed
+        if !triggered || snoozed {
+            return false;
+        }
+
+        if state.armed {
+            state.armed = false;
+            return true;
+        }
+
+        rule.repeat
+            .is_some_and(|repeat| now >= state.last_fired + repeat.as_secs())
+    }
+
+    fn notification_kind(rule: &Rule, level: i32) -> NotificationKind {
+        match rule.kind {
+            RuleKind::Low => NotificationKind::Low,
+            RuleKind::Critical => NotificationKind::Critical,
+            RuleKind::Charged if level >= 100 => NotificationKind::Full,
+            RuleKind::Charged => NotificationKind::ChargeReached,
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.state_path else {
+            return;
+        };
+
+        let result = serde_json::to_string(&self.state)
+            .map_err(|err| err.to_string())
+            .and_then(|content| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+                }
+                fs::write(path, content).map_err(|err| err.to_string())
+            });
+
+        if let Err(err) = result {
+            warn!("Failed to save notification state: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PID: u32 = 0x00B7;
+
+    fn engine() -> RuleEngine {
+        RuleEngine::in_memory(SharedConfig::default())
+    }
+
+    fn rules(fired: Vec<(String, NotificationKind)>) -> Vec<String> {
+        fired.into_iter().map(|(rule, _)| rule).collect()
+    }
+
+    #[test]
+    fn threshold_crossed_while_snoozed_fires_after() {
+        let mut engine = engine();
+        let until = 1000 + 3600;
+        engine.state.snoozed_until = until;
+
+        assert!(engine.evaluate(PID, 14, false, 1001).is_empty());
+        assert!(engine.evaluate(PID, 13, false, until - 1).is_empty());
+        assert_eq!(rules(engine.evaluate(PID, 13, false, until)), ["low:15"]);
+        assert!(engine.evaluate(PID, 12, false, until + 1).is_empty());
+    }
+
+    #[test]
+    fn rules_rearm_while_snoozed() {
+        let mut engine = engine();
+        assert_eq!(rules(engine.evaluate(PID, 15, false, 0)), ["low:15"]);
+
+        engine.state.snoozed_until = 1000 + 3600;
+        assert!(engine.evaluate(PID, 40, true, 1001).is_empty());
+        engine.state.snoozed_until = 0;
+
+        assert_eq!(rules(engine.evaluate(PID, 15, false, 1002)), ["low:15"]);
+    }
+}
@@ -0,0 +1,407 @@
+//! Decides when devices are enumerated and when each one is read: right after
+//! it connects, then at an interval that follows its state, backing off while
+//! it doesn't answer. Delays are shortened by a little jitter per device, so
+//! devices that connected together aren't read in lockstep.
+
+use log::trace;
+use std::collections::HashMap;
+use std::iter;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, RuleKind};
+use crate::monitor::{BatteryMonitor, MemoryDevice};
+
+pub const DEVICE_FETCH_INTERVAL: Duration = Duration::from_secs(5);
+/// The first retry of a device that stopped answering, doubled every time.
+const FIRST_RETRY: Duration = Duration::from_secs(30);
+/// Levels this close above a `low` or `critical` rule count as near it.
+const NEAR_THRESHOLD: i32 = 5;
+/// Delays are shortened by up to this fraction of them.
+const JITTER_DIVISOR: u64 = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PollRequest {
+    /// Re-enumerate devices and read every battery.
+    All,
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    Device(u32),
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DeviceSchedule {
+    next_poll: Instant,
+    /// Reads failed in a row.
+    failures: u32,
+}
+
+/// Times are monotonic, so changes to the system clock don't stop polling.
+#[derive(Debug)]
+pub struct Schedule {
+    next_fetch: Instant,
+    devices: HashMap<u32, DeviceSchedule>,
+}
+
+impl Schedule {
+    /// A schedule that enumerates devices first thing.
+    pub fn new(now: Instant) -> Self {
+        Self {
+            next_fetch: now,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn connected(&mut self, pid: u32, now: Instant) {
+        self.devices.insert(
+            pid,
+            DeviceSchedule {
+                next_poll: now,
+                failures: 0,
+            },
+        );
+    }
+
+    pub fn removed(&mut self, pid: u32) {
+        self.devices.remove(&pid);
+    }
+
+    pub fn fetch_due(&self, now: Instant) -> bool {
+        now >= self.next_fetch
+    }
+
+    pub fn fetched(&mut self, now: Instant) {
+        self.next_fetch = now + DEVICE_FETCH_INTERVAL;
+    }
+
+    /// Devices to read now, in order of their product ids.
+    pub fn due(&self, now: Instant) -> Vec<u32> {
+        let mut due: Vec<u32> = self
+            .devices
+            .iter()
+            .filter(|(_, device)| device.next_poll <= now)
+            .map(|(&pid, _)| pid)
+            .collect();
+        due.sort_unstable();
+        due
+    }
+
+    pub fn poll_all(&mut self, now: Instant) {
+        self.next_fetch = now;
+        for device in self.devices.values_mut() {
+            device.next_poll = now;
+        }
+    }
+
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    pub fn poll_device(&mut self, pid: u32, now: Instant) {
+        if let Some(device) = self.devices.get_mut(&pid) {
+            device.next_poll = now;
+        }
+    }
+
+    pub fn succeeded(&mut self, pid: u32, now: Instant, interval: Duration) {
+        if let Some(device) = self.devices.get_mut(&pid) {
+            device.failures = 0;
+            device.next_poll = now + jittered(pid, interval);
+        }
+    }
+
+    pub fn failed(&mut self, pid: u32, now: Instant, max_backoff: Duration) {
+        if let Some(device) = self.devices.get_mut(&pid) {
+            device.failures = device.failures.saturating_add(1);
+            let delay = jittered(pid, backoff(device.failures, max_backoff));
+            trace!("Retrying 0x{:04X} in {} s", pid, delay.as_secs());
+            device.next_poll = now + delay;
+        }
+    }
+
+    /// Time until the next enumeration or read.
+    pub fn wait(&self, now: Instant) -> Duration {
+        let next = self
+            .devices
+            .values()
+            .map(|device| device.next_poll)
+            .fold(self.next_fetch, Instant::min);
+        next.saturating_duration_since(now)
+    }
+}
+
+/// The interval after a successful read: slower when full, faster while
+/// charging or near a `low` or `critical` rule.
+pub fn interval(config: &Config, device: &MemoryDevice) -> Duration {
+    let polling = &config.polling;
+    let interval = config
+        .device(device.pid)
+        .and_then(|device| device.interval)
+        .unwrap_or(polling.interval);
+
+    let Some(level) = device.battery_level else {
+        return interval;
+    };
+    let near_threshold = config.rules(device.pid).iter().any(|rule| {
+        matches!(rule.kind, RuleKind::Low | RuleKind::Critical)
+            && level <= rule.level + NEAR_THRESHOLD
+    });
+
+    if level >= 100 {
+        interval.max(polling.full_interval)
+    } else if device.is_charging {
+        interval.min(polling.charging_interval)
+    } else if near_threshold {
+        interval.min(polling.low_interval)
+    } else {
+        interval
+    }
+}
+
+fn backoff(failures: u32, max_backoff: Duration) -> Duration {
+    let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+    FIRST_RETRY.saturating_mul(factor).min(max_backoff)
+}
+
+/// The delay in whole seconds, less up to a tenth of it. The amount is fixed
+/// per device, so configured intervals and `max_backoff` stay upper bounds.
+fn jittered(pid: u32, delay: Duration) -> Duration {
+    let delay = delay.as_secs();
+    // Knuth's multiplicative hash, so close product ids land far apart
+    let hash = u64::from(pid.wrapping_mul(2_654_435_761));
+    Duration::from_secs(delay - hash % (delay / JITTER_DIVISOR + 1))
+}
+
+/// Enumerates and reads devices when they are due or requested, until a
+/// `Stop` request arrives.
+pub fn run(monitor: &BatteryMonitor, requests: Receiver<PollRequest>) {
+    let mut schedule = Schedule::new(Instant::now());
+
+    loop {
+        let now = Instant::now();
+        if schedule.fetch_due(now) {
+            let (removed_devices, connected_devices) = monitor.fetch_devices();
+            for pid in removed_devices {
+                schedule.removed(pid);
+            }
+            for pid in connected_devices {
+                schedule.connected(pid, now);
+            }
+            schedule.fetched(now);
+        }
+
+        let due = schedule.due(now);
+        if !due.is_empty() {
+            monitor.update(&due);
+
+            let config = monitor.config();
+            let devices = monitor.devices();
+            for pid in due {
+                match devices.get(&pid) {
+                    Some(device) if device.is_available => {
+                        schedule.succeeded(pid, now, interval(&config, device));
+                    }
+                    Some(_) => schedule.failed(pid, now, config.polling.max_backoff),
+                    // Its name couldn't be read, so it was never added
+                    None => schedule.removed(pid),
+                }
+            }
+        }
+
+        let first = match requests.recv_timeout(schedule.wait(Instant::now())) {
+            Ok(request) => request,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        // Requests that piled up during a read are merged
+        let now = Instant::now();
+        for request in iter::once(first).chain(requests.try_iter()) {
+            match request {
+                PollRequest::All => schedule.poll_all(now),
+                #[cfg(any(feature = "tray", target_os = "linux"))]
+                PollRequest::Device(pid) => schedule.poll_device(pid, now),
+                PollRequest::Stop => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PID: u32 = 0x00B7;
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn device(level: Option<i32>, is_charging: bool) -> MemoryDevice {
+        MemoryDevice {
+            name: "DeathAdder".to_owned(),
+            model: "DeathAdder".to_owned(),
+            serial: None,
+            pid: PID,
+            battery_level: level,
+            battery_raw: None,
+            is_charging,
+            is_available: true,
+            connection: None,
+            last_update: None,
+            estimate: None,
+        }
+    }
+
+    /// Seconds after the start of a test.
+    fn at(secs: u64) -> Instant {
+        thread_local!(static START: Instant = Instant::now());
+        START.with(|start| *start + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn connected_devices_are_read_at_once() {
+        let mut schedule = Schedule::new(at(0));
+        assert!(schedule.fetch_due(at(0)));
+        schedule.fetched(at(100));
+        schedule.connected(PID, at(100));
+
+        assert_eq!(schedule.due(at(100)), [PID]);
+        assert_eq!(schedule.wait(at(100)), Duration::ZERO);
+
+        schedule.succeeded(PID, at(100), 5 * MINUTE);
+        assert!(schedule.due(at(100)).is_empty());
+        // Enumeration comes first
+        assert_eq!(schedule.wait(at(100)), DEVICE_FETCH_INTERVAL);
+        assert!(!schedule.fetch_due(at(104)));
+        assert!(schedule.fetch_due(at(105)));
+    }
+
+    #[test]
+    fn waits_end_at_the_next_poll() {
+        let mut schedule = Schedule::new(at(0));
+        schedule.fetched(at(0));
+        schedule.connected(PID, at(0));
+        schedule.succeeded(PID, at(0), 5 * MINUTE);
+        schedule.fetched(at(600));
+
+        let next_poll = jittered(PID, 5 * MINUTE);
+        assert_eq!(schedule.wait(at(0)), next_poll);
+        assert_eq!(schedule.due(at(0) + next_poll), [PID]);
+        // Overdue polls don't wait at all
+        assert_eq!(schedule.wait(at(1000)), Duration::ZERO);
+    }
+
+    /// A schedule with two devices read at the start.
+    fn polled() -> Schedule {
+        let mut schedule = Schedule::new(at(0));
+        schedule.fetched(at(0));
+        for pid in [PID, PID + 1] {
+            schedule.connected(pid, at(0));
+            schedule.succeeded(pid, at(0), 5 * MINUTE);
+        }
+        schedule
+    }
+
+    #[test]
+    fn requests_make_everything_due() {
+        let mut schedule = polled();
+        assert!(schedule.due(at(20)).is_empty());
+
+        schedule.poll_all(at(20));
+        assert_eq!(schedule.due(at(20)), [PID, PID + 1]);
+        assert!(schedule.fetch_due(at(20)));
+    }
+
+    #[cfg(any(feature = "tray", target_os = "linux"))]
+    #[test]
+    fn device_requests_make_one_device_due() {
+        let mut schedule = polled();
+
+        schedule.poll_device(PID, at(3));
+        assert_eq!(schedule.due(at(3)), [PID]);
+        assert!(!schedule.fetch_due(at(3)));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let delays: Vec<u64> = (1..=7)
+            .map(|failures| backoff(failures, 10 * MINUTE).as_secs())
+            .collect();
+        assert_eq!(delays, [30, 60, 120, 240, 480, 600, 600]);
+    }
+
+    #[test]
+    fn backoff_survives_overflow() {
+        let longest = FIRST_RETRY * u32::MAX;
+        assert_eq!(backoff(32, Duration::MAX), FIRST_RETRY * (1 << 31));
+        assert_eq!(backoff(33, Duration::MAX), longest);
+        assert_eq!(backoff(u32::MAX, Duration::MAX), longest);
+        assert_eq!(backoff(u32::MAX, 10 * MINUTE), 10 * MINUTE);
+    }
+
+    #[test]
+    fn failures_back_off_until_a_read_succeeds() {
+        let mut schedule = Schedule::new(at(0));
+        schedule.connected(PID, at(0));
+
+        let mut retries = Vec::new();
+        for _ in 0..3 {
+            schedule.failed(PID, at(0), 10 * MINUTE);
+            retries.push(schedule.devices[&PID].next_poll);
+        }
+        assert_eq!(
+            retries,
+            [30, 60, 120].map(|delay| at(0) + jittered(PID, Duration::from_secs(delay)))
+        );
+
+        schedule.succeeded(PID, at(0), 5 * MINUTE);
+        assert_eq!(schedule.devices[&PID].failures, 0);
+        schedule.failed(PID, at(0), 10 * MINUTE);
+        assert_eq!(
+            schedule.devices[&PID].next_poll,
+            at(0) + jittered(PID, FIRST_RETRY)
+        );
+    }
+
+    #[test]
+    fn failure_counts_saturate() {
+        let mut schedule = Schedule::new(at(0));
+        schedule.connected(PID, at(0));
+        schedule.devices.get_mut(&PID).unwrap().failures = u32::MAX;
+
+        schedule.failed(PID, at(0), 10 * MINUTE);
+        assert!(schedule.devices[&PID].next_poll <= at(600));
+    }
+
+    #[test]
+    fn jitter_shortens_by_up_to_a_tenth() {
+        for pid in 0..1000 {
+            let delay = jittered(pid, 5 * MINUTE).as_secs();
+            assert!((270..=300).contains(&delay), "{}", delay);
+        }
+        assert_ne!(jittered(0x00B7, 5 * MINUTE), jittered(0x00B8, 5 * MINUTE));
+        assert_eq!(jittered(PID, Duration::from_secs(9)).as_secs(), 9);
+    }
+
+    #[test]
+    fn intervals_follow_the_device_state() {
+        let config = Config::default();
+        let interval = |level, is_charging| interval(&config, &device(level, is_charging));
+
+        assert_eq!(interval(None, false), 5 * MINUTE);
+        assert_eq!(interval(Some(50), false), 5 * MINUTE);
+        assert_eq!(interval(Some(100), false), 15 * MINUTE);
+        assert_eq!(interval(Some(100), true), 15 * MINUTE);
+        assert_eq!(interval(Some(50), true), MINUTE);
+        // Within 5 of the `low:15` rule
+        assert_eq!(interval(Some(21), false), 5 * MINUTE);
+        assert_eq!(interval(Some(20), false), MINUTE);
+        assert_eq!(interval(Some(3), false), MINUTE);
+    }
+
+    #[test]
+    fn device_intervals_replace_the_default() {
+        let config: Config = toml::from_str("[devices.\"0x00B7\"]\ninterval = \"20m\"").unwrap();
+        let interval = |level, is_charging| interval(&config, &device(level, is_charging));
+
+        assert_eq!(interval(Some(50), false), 20 * MINUTE);
+        // Still shorter while charging, and never shorter when full
+        assert_eq!(interval(Some(50), true), MINUTE);
+        assert_eq!(interval(Some(100), false), 20 * MINUTE);
+    }
+}
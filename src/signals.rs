@@ -0,0 +1,59 @@
+//! Termination signals, blocked in every thread and waited for in one, so
+//! they stop the monitor between two device reads rather than during one.
+//!
+//! They have to be blocked before any thread starts, so all of them inherit
+//! the mask.
+
+use log::{error, info};
+use std::sync::Arc;
+use std::{io, mem, ptr, thread};
+
+use crate::monitor::BatteryMonitor;
+
+pub struct SignalSet(libc::sigset_t);
+
+impl SignalSet {
+    pub fn block(signals: &[libc::c_int]) -> io::Result<Self> {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for &signal in signals {
+                libc::sigaddset(&mut set, signal);
+            }
+
+            let err = libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+            Ok(Self(set))
+        }
+    }
+
+    /// SIGINT and SIGTERM.
+    pub fn block_termination() -> io::Result<Self> {
+        Self::block(&[libc::SIGINT, libc::SIGTERM])
+    }
+
+    /// Blocks until one of the signals arrives and returns it.
+    pub fn wait(&self) -> io::Result<libc::c_int> {
+        let mut signal = 0;
+        let err = unsafe { libc::sigwait(&self.0, &mut signal) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        Ok(signal)
+    }
+
+    /// Shuts the monitor down when one of the signals arrives.
+    pub fn spawn_handler(self, monitor: &Arc<BatteryMonitor>) {
+        let monitor = Arc::clone(monitor);
+
+        thread::spawn(move || match self.wait() {
+            Ok(signal) => {
+                info!("Received signal {}, exiting", signal);
+                monitor.shutdown();
+            }
+            Err(err) => error!("Failed to wait for signals: {}", err),
+        });
+    }
+}
@@ -0,0 +1,961 @@
+//! Tray backend speaking the StatusNotifierItem and DBusMenu protocols over
+//! the session bus directly, without GTK, so the icon works in any tray host
+//! including Wayland bars like waybar.
+//!
+//! Only shows the single icon, `per_device` is not supported here.
+
+use log::{error, info, trace, warn};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::fdo::DBusProxy;
+use zbus::blocking::Connection;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Type, Value};
+use zbus::{block_on, fdo, interface};
+
+use crate::bus::MonitorEvent;
+use crate::http;
+use crate::icon::{render_icon, IconConfig, IconState};
+use crate::ipc::{self, Request, Response};
+use crate::menu::{
+    self, menu_entries, open_settings, rename_device, snooze_text, sorted_devices, tooltip,
+    MenuAction, MenuEntry,
+};
+use crate::monitor::{BatteryMonitor, MemoryDevice};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const TITLE: &str = "Razer Battery Report";
+
+/// Icon sizes offered to the host, which picks the one closest to its own.
+const ICON_SIZES: [u32; 4] = [16, 22, 32, 48];
+
+#[derive(Debug)]
+enum TrayEvent {
+    Monitor(MonitorEvent),
+    ConfigReloaded,
+    /// A tray host started, or restarted and forgot about us.
+    WatcherStarted,
+    Menu(MenuAction),
+}
+
+pub fn run(
+    monitor: Arc<BatteryMonitor>,
+    listener: Option<ipc::Listener>,
+) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel();
+    let mut tray = StatusNotifier::start(sender.clone())?;
+
+    spawn_watcher_thread(&tray.connection, sender.clone())?;
+    spawn_event_thread(&monitor, sender.clone());
+    monitor.start_polling();
+    if let Some(listener) = listener {
+        spawn_ipc_server(listener, Arc::clone(&monitor), sender);
+    }
+    http::start(&monitor);
+
+    if let Err(err) = tray.update(&monitor, true) {
+        error!("Failed to update tray icon: {}", err);
+    }
+    if let Err(err) = tray.register() {
+        warn!(
+            "No tray host found ({}), the icon appears once one starts",
+            err
+        );
+    }
+
+    for event in receiver {
+        let rerender = match event {
+            TrayEvent::Monitor(MonitorEvent::Stopped) => return Ok(()),
+            TrayEvent::Monitor(event) if event.changes_state() => false,
+            TrayEvent::Monitor(_) => continue,
+            TrayEvent::ConfigReloaded => true,
+            TrayEvent::WatcherStarted => {
+                if let Err(err) = tray.register() {
+                    error!("Failed to register tray icon: {}", err);
+                }
+                continue;
+            }
+            TrayEvent::Menu(action) => match action {
+                MenuAction::RefreshAll => {
+                    monitor.request_poll();
+                    continue;
+                }
+                MenuAction::ReloadConfig => match monitor.reload_config() {
+                    Ok(()) => true,
+                    Err(err) => {
+                        error!("Failed to reload config: {}", err);
+                        continue;
+                    }
+                },
+                MenuAction::ToggleSnooze => {
+                    monitor.toggle_snooze();
+                    false
+                }
+                // Exits once the monitor reports it stopped
+                MenuAction::Exit => {
+                    monitor.shutdown();
+                    continue;
+                }
+                MenuAction::Refresh(pid) => {
+                    monitor.request_update(pid);
+                    continue;
+                }
+                MenuAction::Details(pid) => {
+                    if let Some(device) = monitor.devices().get(&pid) {
+                        tray.show_details(device);
+                    }
+                    continue;
+                }
+                MenuAction::Rename(pid) => {
+                    rename_device(&monitor, pid);
+                    continue;
+                }
+                MenuAction::Settings => {
+                    open_settings();
+                    continue;
+                }
+                // There is no log window outside Windows
+                MenuAction::ToggleConsole => continue,
+            },
+        };
+
+        if let Err(err) = tray.update(&monitor, rerender) {
+            error!("Failed to update tray icon: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Re-registers the item whenever the watcher changes hands, since it keeps
+/// its items in memory only.
+fn spawn_watcher_thread(connection: &Connection, sender: Sender<TrayEvent>) -> zbus::Result<()> {
+    let proxy = DBusProxy::new(connection)?;
+    let changes = proxy.receive_name_owner_changed_with_args(&[(0, WATCHER_NAME)])?;
+
+    thread::spawn(move || {
+        for signal in changes {
+            let started = signal.args().is_ok_and(|args| args.new_owner().is_some());
+            if started && sender.send(TrayEvent::WatcherStarted).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+fn spawn_event_thread(monitor: &BatteryMonitor, sender: Sender<TrayEvent>) {
+    let events = monitor.bus().subscribe();
+
+    thread::spawn(move || {
+        for event in events {
+            if sender.send(TrayEvent::Monitor(event)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn spawn_ipc_server(
+    listener: ipc::Listener,
+    monitor: Arc<BatteryMonitor>,
+    sender: Sender<TrayEvent>,
+) {
+    listener.serve(move |request| match request {
+        Request::Status => Response::Status {
+            devices: monitor.status(),
+        },
+        Request::Refresh => {
+            monitor.request_poll();
+            Response::Ok
+        }
+        Request::History { filter } => match monitor.history(&filter) {
+            Ok(records) => Response::History { records },
+            Err(err) => Response::error(err),
+        },
+        Request::ReloadConfig => match monitor.reload_config() {
+            Ok(()) => {
+                let _ = sender.send(TrayEvent::ConfigReloaded);
+                Response::Ok
+            }
+            Err(err) => Response::error(err),
+        },
+        Request::Activate => {
+            info!("Another instance was started");
+            Response::Ok
+        }
+        Request::Watch => Response::Events(monitor.events().subscribe()),
+    });
+}
+
+struct StatusNotifier {
+    connection: Connection,
+    bus_name: String,
+    icon_state: Option<IconState>,
+}
+
+impl StatusNotifier {
+    fn start(sender: Sender<TrayEvent>) -> zbus::Result<Self> {
+        Self::serve(Builder::session()?, sender)
+    }
+
+    fn serve(builder: Builder, sender: Sender<TrayEvent>) -> zbus::Result<Self> {
+        let bus_name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+        let connection = builder
+            .name(bus_name.as_str())?
+            .serve_at(ITEM_PATH, Item::default())?
+            .serve_at(MENU_PATH, DbusMenu::new(sender))?
+            .build()?;
+
+        Ok(Self {
+            connection,
+            bus_name,
+            icon_state: None,
+        })
+    }
+
+    fn register(&self) -> zbus::Result<()> {
+        self.connection.call_method(
+            Some(WATCHER_NAME),
+            WATCHER_PATH,
+            Some(WATCHER_NAME),
+            "RegisterStatusNotifierItem",
+            &self.bus_name,
+        )?;
+        info!("Registered tray icon as {}", self.bus_name);
+        Ok(())
+    }
+
+    /// Icons are re-rendered on changes only, unless `rerender` is set.
+    fn update(&mut self, monitor: &BatteryMonitor, rerender: bool) -> zbus::Result<()> {
+        let config = monitor.config();
+        let (state, tooltip, entries) = {
+            let devices = monitor.devices();
+            let devices = sorted_devices(&devices);
+            let snooze_text = snooze_text(&config, monitor.is_snoozed());
+            (
+                menu::icon_state(&devices),
+                tooltip(&devices),
+                menu_entries(&devices, None, &snooze_text),
+            )
+        };
+
+        let server = self.connection.object_server();
+        let item = server.interface::<_, Item>(ITEM_PATH)?;
+        let dbus_menu = server.interface::<_, DbusMenu>(MENU_PATH)?;
+
+        if rerender || self.icon_state != Some(state) {
+            trace!("Tray icon state: {:?}", state);
+            self.icon_state = Some(state);
+            item.get_mut().icon = icon_pixmaps(state, &config.icon);
+            block_on(Item::new_icon(item.signal_emitter()))?;
+        }
+
+        if item.get().tooltip != tooltip {
+            item.get_mut().tooltip = tooltip;
+            block_on(Item::new_tool_tip(item.signal_emitter()))?;
+        }
+
+        let revision = dbus_menu.get_mut().set_entries(&entries);
+        if let Some(revision) = revision {
+            block_on(DbusMenu::layout_updated(
+                dbus_menu.signal_emitter(),
+                revision,
+                0,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// There is no window to show them in, so details become a notification.
+    fn show_details(&self, device: &MemoryDevice) {
+        let result = self.connection.call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &(
+                TITLE,
+                0u32,
+                "input-mouse",
+                &device.name,
+                device.details(),
+                Vec::<&str>::new(),
+                HashMap::<&str, Value>::new(),
+                -1i32,
+            ),
+        );
+        if let Err(err) = result {
+            warn!("Failed to show device details: {}", err);
+        }
+    }
+}
+
+/// ARGB32 pixels in network byte order, as the spec wants them.
+type Pixmap = (i32, i32, Vec<u8>);
+
+fn icon_pixmaps(state: IconState, config: &IconConfig) -> Vec<Pixmap> {
+    let sizes = match config.size {
+        Some(size) => vec![size],
+        None => ICON_SIZES.to_vec(),
+    };
+
+    sizes
+        .into_iter()
+        .map(|size| {
+            let image = render_icon(state, config, size);
+            let (width, height) = image.dimensions();
+            let argb = image
+                .pixels()
+                .flat_map(|pixel| {
+                    let [r, g, b, a] = pixel.0;
+                    [a, r, g, b]
+                })
+                .collect();
+            (width as i32, height as i32, argb)
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Item {
+    icon: Vec<Pixmap>,
+    tooltip: String,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+    #[zbus(property)]
+    fn category(&self) -> &str {
+        "Hardware"
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> &str {
+        env!("CARGO_PKG_NAME")
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> &str {
+        TITLE
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "Active"
+    }
+
+    #[zbus(property)]
+    fn window_id(&self) -> i32 {
+        0
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> &str {
+        ""
+    }
+
+    #[zbus(property)]
+    fn icon_pixmap(&self) -> Vec<Pixmap> {
+        self.icon.clone()
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_name(&self) -> &str {
+        ""
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_pixmap(&self) -> Vec<Pixmap> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn attention_icon_name(&self) -> &str {
+        ""
+    }
+
+    #[zbus(property)]
+    fn attention_icon_pixmap(&self) -> Vec<Pixmap> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn attention_movie_name(&self) -> &str {
+        ""
+    }
+
+    /// Icon name, icon pixmaps, title and description.
+    #[zbus(property)]
+    fn tool_tip(&self) -> (&str, Vec<Pixmap>, &str, &str) {
+        ("", Vec::new(), TITLE, &self.tooltip)
+    }
+
+    /// Clicking the icon opens the menu, there is nothing else to activate.
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(MENU_PATH).expect("valid object path")
+    }
+
+    fn activate(&self, _x: i32, _y: i32) {}
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: &str) {}
+
+    #[zbus(signal)]
+    async fn new_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_tool_tip(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+/// A menu item as DBusMenu sees it, with its index as id and 0 as the root.
+#[derive(Debug, Default, PartialEq)]
+struct Node {
+    label: String,
+    disabled: bool,
+    separator: bool,
+    action: Option<MenuAction>,
+    children: Vec<i32>,
+}
+
+impl Node {
+    /// Only the non-default ones, filtered by `names` unless it is empty.
+    fn properties(&self, names: &[String]) -> HashMap<String, OwnedValue> {
+        let mut properties = HashMap::new();
+        let mut insert = |name: &str, value: Value| {
+            if names.is_empty() || names.iter().any(|wanted| wanted == name) {
+                if let Ok(value) = OwnedValue::try_from(value) {
+                    properties.insert(name.to_owned(), value);
+                }
+            }
+        };
+
+        if self.separator {
+            insert("type", Value::from("separator"));
+        } else if !self.label.is_empty() {
+            // Underscores mark access keys
+            insert("label", Value::from(self.label.replace('_', "__")));
+        }
+        if self.disabled {
+            insert("enabled", Value::from(false));
+        }
+        if !self.children.is_empty() {
+            insert("children-display", Value::from("submenu"));
+        }
+        properties
+    }
+}
+
+/// Item id, properties and children, each child the same structure again.
+#[derive(Debug, Type, Value, OwnedValue, serde::Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize))]
+struct Layout {
+    id: i32,
+    properties: HashMap<String, OwnedValue>,
+    children: Vec<OwnedValue>,
+}
+
+struct DbusMenu {
+    revision: u32,
+    nodes: Vec<Node>,
+    sender: Sender<TrayEvent>,
+}
+
+impl DbusMenu {
+    fn new(sender: Sender<TrayEvent>) -> Self {
+        Self {
+            revision: 0,
+            nodes: vec![Node::default()],
+            sender,
+        }
+    }
+
+    /// Replaces the menu, returning the new revision if anything changed.
+    fn set_entries(&mut self, entries: &[MenuEntry]) -> Option<u32> {
+        let mut nodes = vec![Node::default()];
+        Self::add_entries(&mut nodes, 0, entries);
+        if nodes == self.nodes {
+            return None;
+        }
+
+        self.nodes = nodes;
+        self.revision += 1;
+        Some(self.revision)
+    }
+
+    fn add_entries(nodes: &mut Vec<Node>, parent: usize, entries: &[MenuEntry]) {
+        for entry in entries {
+            let id = nodes.len();
+            nodes[parent].children.push(id as i32);
+            match entry {
+                MenuEntry::Item { text, action } => nodes.push(Node {
+                    label: text.clone(),
+                    disabled: action.is_none(),
+                    action: *action,
+                    ..Node::default()
+                }),
+                MenuEntry::Submenu { text, entries } => {
+                    nodes.push(Node {
+                        label: text.clone(),
+                        ..Node::default()
+                    });
+                    Self::add_entries(nodes, id, entries);
+                }
+                MenuEntry::Separator => nodes.push(Node {
+                    separator: true,
+                    ..Node::default()
+                }),
+            }
+        }
+    }
+
+    fn node(&self, id: i32) -> fdo::Result<&Node> {
+        usize::try_from(id)
+            .ok()
+            .and_then(|id| self.nodes.get(id))
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No menu item {}", id)))
+    }
+
+    /// A negative `depth` includes all descendants.
+    fn layout(&self, id: i32, depth: i32, names: &[String]) -> fdo::Result<Layout> {
+        let node = self.node(id)?;
+        let mut children = Vec::new();
+        if depth != 0 {
+            for &child in &node.children {
+                let layout = self.layout(child, depth - 1, names)?;
+                children.push(OwnedValue::try_from(layout).map_err(zbus::Error::from)?);
+            }
+        }
+
+        Ok(Layout {
+            id,
+            properties: node.properties(names),
+            children,
+        })
+    }
+
+    fn clicked(&self, id: i32) {
+        if let Some(action) = self.nodes.get(id as usize).and_then(|node| node.action) {
+            trace!("Menu action {:?}", action);
+            let _ = self.sender.send(TrayEvent::Menu(action));
+        }
+    }
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl DbusMenu {
+    #[zbus(out_args("revision", "layout"))]
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        recursion_depth: i32,
+        property_names: Vec<String>,
+    ) -> fdo::Result<(u32, Layout)> {
+        let layout = self.layout(parent_id, recursion_depth, &property_names)?;
+        Ok((self.revision, layout))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+        ids.into_iter()
+            .filter_map(|id| {
+                let node = self.node(id).ok()?;
+                Some((id, node.properties(&property_names)))
+            })
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: String) -> fdo::Result<OwnedValue> {
+        self.node(id)?
+            .properties(std::slice::from_ref(&name))
+            .remove(&name)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No property {}", name)))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: OwnedValue, _timestamp: u32) {
+        if event_id == "clicked" {
+            self.clicked(id);
+        }
+    }
+
+    /// Returns the ids that were not found.
+    fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+        let mut id_errors = Vec::new();
+        for (id, event_id, _, _) in events {
+            if self.node(id).is_err() {
+                id_errors.push(id);
+            } else if event_id == "clicked" {
+                self.clicked(id);
+            }
+        }
+        id_errors
+    }
+
+    /// The menu is always up to date, so it never needs an update.
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    #[zbus(out_args("updates_needed", "id_errors"))]
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (Vec::new(), Vec::new())
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(signal)]
+    async fn layout_updated(
+        emitter: &SignalEmitter<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::dbus::testing::TestBus;
+    use crate::monitor::testing::{self, TestMonitor};
+    use parking_lot::Mutex;
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+    use zbus::blocking::MessageIterator;
+    use zbus::message::Type as MessageType;
+    use zbus::MatchRule;
+
+    const PID: u32 = 0x00B7;
+    const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem";
+    const MENU_INTERFACE: &str = "com.canonical.dbusmenu";
+
+    /// Records the items registered with it, like a tray host would.
+    #[derive(Clone, Default)]
+    struct FakeWatcher {
+        items: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.kde.StatusNotifierWatcher")]
+    impl FakeWatcher {
+        fn register_status_notifier_item(&self, service: String) {
+            self.items.lock().push(service);
+        }
+    }
+
+    impl FakeWatcher {
+        fn start(bus: &TestBus) -> (Self, Connection) {
+            let watcher = Self::default();
+            let connection = bus
+                .builder()
+                .name(WATCHER_NAME)
+                .unwrap()
+                .serve_at(WATCHER_PATH, watcher.clone())
+                .unwrap()
+                .build()
+                .unwrap();
+            (watcher, connection)
+        }
+    }
+
+    struct Tray {
+        bus: TestBus,
+        tray: StatusNotifier,
+        receiver: Receiver<TrayEvent>,
+        client: Connection,
+        test: TestMonitor,
+    }
+
+    /// The item on a bus of its own, showing one device read at 42%.
+    fn start() -> Option<Tray> {
+        let bus = TestBus::start()?;
+        let (sender, receiver) = mpsc::channel();
+        let mut tray = StatusNotifier::serve(bus.builder(), sender).unwrap();
+
+        let test = testing::monitor(Config::default());
+        test.backend.connect(PID, "Razer DeathAdder V3 Pro");
+        test.backend.set_level(PID, 42, false);
+        test.monitor.fetch_devices();
+        test.monitor.update(&[PID]);
+        tray.update(&test.monitor, true).unwrap();
+
+        Some(Tray {
+            client: bus.connect(),
+            bus,
+            tray,
+            receiver,
+            test,
+        })
+    }
+
+    impl Tray {
+        fn call<B, R>(&self, path: &str, interface: &str, method: &str, body: &B) -> R
+        where
+            B: serde::Serialize + Type,
+            R: for<'de> serde::Deserialize<'de> + Type,
+        {
+            self.client
+                .call_method(
+                    Some(self.tray.bus_name.as_str()),
+                    path,
+                    Some(interface),
+                    method,
+                    body,
+                )
+                .unwrap()
+                .body()
+                .deserialize()
+                .unwrap()
+        }
+
+        fn property<T>(&self, path: &str, interface: &str, name: &str) -> T
+        where
+            T: TryFrom<OwnedValue>,
+            T::Error: std::fmt::Debug,
+        {
+            let value: OwnedValue = self.call(
+                path,
+                "org.freedesktop.DBus.Properties",
+                "Get",
+                &(interface, name),
+            );
+            T::try_from(value).unwrap()
+        }
+
+        /// Every menu item, indented by depth, with its id.
+        fn menu(&self) -> (u32, Vec<(i32, String)>) {
+            let (revision, layout): (u32, Layout) = self.call(
+                MENU_PATH,
+                MENU_INTERFACE,
+                "GetLayout",
+                &(0, -1, Vec::<String>::new()),
+            );
+            let mut items = Vec::new();
+            flatten(layout, 0, &mut items);
+            (revision, items)
+        }
+
+        fn signals(&self) -> MessageIterator {
+            let rule = MatchRule::builder()
+                .msg_type(MessageType::Signal)
+                .sender(self.tray.bus_name.as_str())
+                .unwrap()
+                .build();
+            MessageIterator::for_match_rule(rule, &self.client, None).unwrap()
+        }
+
+        fn event(&self) -> TrayEvent {
+            self.receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+        }
+    }
+
+    fn flatten(layout: Layout, depth: usize, items: &mut Vec<(i32, String)>) {
+        let text = |name: &str| {
+            layout
+                .properties
+                .get(name)
+                .map(|value| String::try_from(value.try_clone().unwrap()).unwrap())
+        };
+        let label = match text("type").as_deref() {
+            Some("separator") => "---".to_owned(),
+            _ => text("label").unwrap_or_default(),
+        };
+        if layout.id != 0 {
+            items.push((layout.id, format!("{}{}", "  ".repeat(depth - 1), label)));
+        }
+
+        for child in layout.children {
+            flatten(Layout::try_from(child).unwrap(), depth + 1, items);
+        }
+    }
+
+    #[test]
+    fn registers_with_the_watcher() {
+        let Some(tray) = start() else {
+            return;
+        };
+        // Nothing to register with yet
+        assert!(tray.tray.register().is_err());
+
+        let (sender, receiver) = mpsc::channel();
+        spawn_watcher_thread(&tray.tray.connection, sender).unwrap();
+        let (watcher, _connection) = FakeWatcher::start(&tray.bus);
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Ok(TrayEvent::WatcherStarted)
+        ));
+
+        tray.tray.register().unwrap();
+        assert_eq!(
+            *watcher.items.lock(),
+            std::slice::from_ref(&tray.tray.bus_name)
+        );
+    }
+
+    #[test]
+    fn item_properties() {
+        let Some(tray) = start() else {
+            return;
+        };
+        let property = |name| tray.property::<String>(ITEM_PATH, ITEM_INTERFACE, name);
+
+        assert_eq!(property("Category"), "Hardware");
+        assert_eq!(property("Id"), "razer-battery-report");
+        assert_eq!(property("Title"), TITLE);
+        assert_eq!(property("Status"), "Active");
+        assert!(tray.property::<bool>(ITEM_PATH, ITEM_INTERFACE, "ItemIsMenu"));
+        assert_eq!(
+            tray.property::<OwnedObjectPath>(ITEM_PATH, ITEM_INTERFACE, "Menu")
+                .as_str(),
+            MENU_PATH
+        );
+
+        let pixmaps: Vec<Pixmap> = tray.property(ITEM_PATH, ITEM_INTERFACE, "IconPixmap");
+        let sizes: Vec<(i32, i32, usize)> = pixmaps
+            .iter()
+            .map(|(width, height, data)| (*width, *height, data.len()))
+            .collect();
+        assert_eq!(
+            sizes,
+            ICON_SIZES.map(|size| (size as i32, size as i32, (size * size * 4) as usize))
+        );
+
+        let (_, _, title, description): (String, Vec<Pixmap>, String, String) =
+            tray.property(ITEM_PATH, ITEM_INTERFACE, "ToolTip");
+        assert_eq!(title, TITLE);
+        assert_eq!(description, "Razer DeathAdder V3 Pro: 42%");
+    }
+
+    #[test]
+    fn menu_layout() {
+        let Some(tray) = start() else {
+            return;
+        };
+        assert_eq!(
+            tray.property::<u32>(MENU_PATH, MENU_INTERFACE, "Version"),
+            3
+        );
+
+        let label = tray.test.monitor.devices()[&PID].label();
+        let snooze = snooze_text(&tray.test.monitor.config(), false);
+        let (revision, items) = tray.menu();
+        let labels: Vec<&str> = items.iter().map(|(_, label)| label.as_str()).collect();
+        assert_eq!(revision, 1);
+        assert_eq!(
+            labels,
+            [
+                label.as_str(),
+                "  Refresh",
+                "  Details",
+                "  Rename…",
+                "  Settings…",
+                "---",
+                "Refresh Now",
+                "Reload Settings",
+                snooze.as_str(),
+                "Exit",
+            ]
+        );
+    }
+
+    #[test]
+    fn clicks_become_menu_actions() {
+        let Some(tray) = start() else {
+            return;
+        };
+        let (_, items) = tray.menu();
+        let id = |label: &str| items.iter().find(|(_, text)| text == label).unwrap().0;
+
+        let () = tray.call(
+            MENU_PATH,
+            MENU_INTERFACE,
+            "Event",
+            &(id("Refresh Now"), "clicked", Value::from(0), 0u32),
+        );
+        assert!(matches!(
+            tray.event(),
+            TrayEvent::Menu(MenuAction::RefreshAll)
+        ));
+
+        let errors: Vec<i32> = tray.call(
+            MENU_PATH,
+            MENU_INTERFACE,
+            "EventGroup",
+            &vec![
+                (id("  Details"), "clicked", Value::from(0), 0u32),
+                (999, "clicked", Value::from(0), 0u32),
+            ],
+        );
+        assert_eq!(errors, [999]);
+        assert!(matches!(
+            tray.event(),
+            TrayEvent::Menu(MenuAction::Details(PID))
+        ));
+    }
+
+    #[test]
+    fn changes_are_signalled() {
+        let Some(mut tray) = start() else {
+            return;
+        };
+        let mut signals = tray.signals();
+
+        // Nothing changed
+        tray.tray.update(&tray.test.monitor, false).unwrap();
+
+        tray.test.backend.set_level(PID, 41, false);
+        tray.test.monitor.update(&[PID]);
+        tray.tray.update(&tray.test.monitor, false).unwrap();
+
+        let mut next = || {
+            let message = signals.next().unwrap().unwrap();
+            let member = message.header().member().unwrap().to_string();
+            (member, message)
+        };
+        assert_eq!(next().0, "NewIcon");
+        assert_eq!(next().0, "NewToolTip");
+        let (member, message) = next();
+        assert_eq!(member, "LayoutUpdated");
+        assert_eq!(message.body().deserialize::<(u32, i32)>().unwrap(), (2, 0));
+
+        assert_eq!(tray.menu().0, 2);
+        let (_, _, _, description): (String, Vec<Pixmap>, String, String) =
+            tray.property(ITEM_PATH, ITEM_INTERFACE, "ToolTip");
+        assert_eq!(description, "Razer DeathAdder V3 Pro: 41%");
+    }
+}
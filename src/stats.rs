@@ -0,0 +1,299 @@
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::estimate::split_sessions;
+use crate::history::HistoryRecord;
+
+/// Discharge span used to compare runtime over time.
+const RUNTIME_SPAN_PERCENT: f64 = 100.0 - 15.0;
+
+#[derive(Debug, Serialize)]
+pub struct DeviceStats {
+    pub pid: u32,
+    pub name: String,
+    pub readings: usize,
+    /// Time spent discharging while the device was in use.
+    pub hours_of_use: f64,
+    /// Average percentage lost per hour of use.
+    pub discharge_rate: Option<f64>,
+    /// Total percentage discharged divided by 100.
+    pub full_cycles: f64,
+    pub charges: usize,
+    pub average_charge_hours: Option<f64>,
+    pub monthly: Vec<MonthlyStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonthlyStats {
+    pub month: String,
+    pub hours_of_use: f64,
+    pub discharge_rate: Option<f64>,
+    /// Hours it takes to go from 100% to 15% at this month's discharge rate.
+    pub runtime_hours: Option<f64>,
+}
+
+#[derive(Default)]
+struct Discharge {
+    hours: f64,
+    percent: f64,
+}
+
+impl Discharge {
+    fn rate(&self) -> Option<f64> {
+        (self.hours > 0.0 && self.percent > 0.0).then(|| self.percent / self.hours)
+    }
+}
+
+/// Computes statistics for every device in the given chronological records.
+pub fn compute(records: &[HistoryRecord]) -> Vec<DeviceStats> {
+    let mut by_device: BTreeMap<u32, Vec<HistoryRecord>> = BTreeMap::new();
+    for record in records {
+        by_device
+            .entry(record.pid)
+            .or_default()
+            .push(record.clone());
+    }
+
+    by_device
+        .into_iter()
+        .map(|(pid, records)| device_stats(pid, &records))
+        .collect()
+}
+
+fn device_stats(pid: u32, records: &[HistoryRecord]) -> DeviceStats {
+    let mut total = Discharge::default();
+    let mut monthly: BTreeMap<String, Discharge> = BTreeMap::new();
+    let mut charge_hours = Vec::new();
+
+    for session in split_sessions(records) {
+        let (Some(first), Some(last)) = (session.records.first(), session.records.last()) else {
+            continue;
+        };
+        let hours = session.duration().as_secs_f64() / 3600.0;
+
+        if session.is_charging {
+            if hours > 0.0 {
+                charge_hours.push(hours);
+            }
+            continue;
+        }
+
+        let percent = first.raw.saturating_sub(last.raw) as f64 / 255.0 * 100.0;
+        total.hours += hours;
+        total.percent += percent;
+
+        let month = monthly.entry(format_month(first.timestamp)).or_default();
+        month.hours += hours;
+        month.percent += percent;
+    }
+
+    DeviceStats {
+        pid,
+        name: records
+            .last()
+            .map_or_else(String::new, |record| record.name.clone()),
+        readings: records.len(),
+        hours_of_use: total.hours,
+        discharge_rate: total.rate(),
+        full_cycles: total.percent / 100.0,
+        charges: charge_hours.len(),
+        average_charge_hours: (!charge_hours.is_empty())
+            .then(|| charge_hours.iter().sum::<f64>() / charge_hours.len() as f64),
+        monthly: monthly
+            .into_iter()
+            .map(|(month, discharge)| MonthlyStats {
+                month,
+                hours_of_use: discharge.hours,
+                discharge_rate: discharge.rate(),
+                runtime_hours: discharge.rate().map(|rate| RUNTIME_SPAN_PERCENT / rate),
+            })
+            .collect(),
+    }
+}
+
+fn format_month(timestamp: u64) -> String {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map_or_else(String::new, |time| time.format("%Y-%m").to_string())
+}
+
+pub fn format_text(stats: &DeviceStats) -> String {
+    let optional = |value: Option<f64>, unit: &str| {
+        value.map_or_else(|| "n/a".to_owned(), |value| format!("{:.1}{}", value, unit))
+    };
+
+    let mut text = String::new();
+    let _ = writeln!(text, "{} (0x{:04X})", stats.name, stats.pid);
+    let _ = writeln!(text, "  Readings:        {}", stats.readings);
+    let _ = writeln!(text, "  Hours of use:    {:.1} h", stats.hours_of_use);
+    let _ = writeln!(
+        text,
+        "  Discharge rate:  {}",
+        optional(stats.discharge_rate, " %/h")
+    );
+    let _ = writeln!(text, "  Full cycles:     {:.1}", stats.full_cycles);
+    let _ = writeln!(
+        text,
+        "  Charges:         {} (average {})",
+        stats.charges,
+        optional(stats.average_charge_hours, " h")
+    );
+
+    if !stats.monthly.is_empty() {
+        let _ = writeln!(text, "  Runtime 100% → 15% by month:");
+        for month in &stats.monthly {
+            let _ = writeln!(
+                text,
+                "    {}  {:>8}  ({:.1} h of use)",
+                month.month,
+                optional(month.runtime_hours, " h"),
+                month.hours_of_use
+            );
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::ConnectionType;
+
+    // Mid-month, so the month is the same in every time zone
+    const JUNE: u64 = 1_749_988_800; // 2025-06-15 12:00 UTC
+    const JULY: u64 = 1_752_580_800; // 2025-07-15 12:00 UTC
+    const STEP: u64 = 600;
+
+    fn record(pid: u32, timestamp: u64, raw: u8, is_charging: bool) -> HistoryRecord {
+        HistoryRecord {
+            timestamp,
+            pid,
+            name: format!("Mouse {:04X}", pid),
+            level: (f64::from(raw) / 2.55).round() as i32,
+            raw,
+            is_charging,
+            connection: ConnectionType::Wireless,
+        }
+    }
+
+    /// `count` readings every 10 minutes, going evenly from `from` to `to`.
+    fn session(
+        pid: u32,
+        start: u64,
+        count: u64,
+        (from, to): (u8, u8),
+        is_charging: bool,
+    ) -> Vec<HistoryRecord> {
+        (0..count)
+            .map(|i| {
+                let raw = i64::from(from)
+                    + (i64::from(to) - i64::from(from)) * i as i64 / (count - 1) as i64;
+                record(pid, start + i * STEP, raw as u8, is_charging)
+            })
+            .collect()
+    }
+
+    /// Two hours losing 20% in June, half an hour of charging, then an hour
+    /// losing 13.3% in July. A second device only has a single reading.
+    fn records() -> Vec<HistoryRecord> {
+        let mut records = session(0x00B7, JUNE, 13, (255, 204), false);
+        records.extend(session(0x00B7, JUNE + 13 * STEP, 4, (204, 255), true));
+        records.push(record(0x00B8, JULY - STEP, 100, true));
+        records.extend(session(0x00B7, JULY, 7, (255, 221), false));
+        records
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn stats_are_per_device() {
+        let stats = compute(&records());
+
+        let devices: Vec<(u32, &str, usize)> = stats
+            .iter()
+            .map(|stats| (stats.pid, stats.name.as_str(), stats.readings))
+            .collect();
+        assert_eq!(
+            devices,
+            [(0x00B7, "Mouse 00B7", 24), (0x00B8, "Mouse 00B8", 1)]
+        );
+        assert!(compute(&[]).is_empty());
+    }
+
+    #[test]
+    fn device_stats_add_up_sessions() {
+        let records: Vec<HistoryRecord> = records()
+            .into_iter()
+            .filter(|record| record.pid == 0x00B7)
+            .collect();
+        let stats = device_stats(0x00B7, &records);
+
+        assert_close(stats.hours_of_use, 3.0);
+        assert_close(stats.discharge_rate.unwrap(), (20.0 + 40.0 / 3.0) / 3.0);
+        assert_close(stats.full_cycles, (20.0 + 40.0 / 3.0) / 100.0);
+        assert_eq!(stats.charges, 1);
+        assert_close(stats.average_charge_hours.unwrap(), 0.5);
+
+        let months: Vec<&str> = stats
+            .monthly
+            .iter()
+            .map(|month| month.month.as_str())
+            .collect();
+        assert_eq!(months, ["2025-06", "2025-07"]);
+        let (june, july) = (&stats.monthly[0], &stats.monthly[1]);
+        assert_close(june.hours_of_use, 2.0);
+        assert_close(june.discharge_rate.unwrap(), 10.0);
+        assert_close(june.runtime_hours.unwrap(), 8.5);
+        assert_close(july.hours_of_use, 1.0);
+        assert_close(july.runtime_hours.unwrap(), 85.0 * 3.0 / 40.0);
+    }
+
+    #[test]
+    fn single_readings_have_no_rates() {
+        let stats = device_stats(0x00B8, &[record(0x00B8, JULY, 100, true)]);
+
+        assert_eq!(stats.hours_of_use, 0.0);
+        assert_eq!(stats.discharge_rate, None);
+        assert_eq!(stats.charges, 0);
+        assert_eq!(stats.average_charge_hours, None);
+        assert!(stats.monthly.is_empty());
+    }
+
+    #[test]
+    fn text_lists_totals_and_months() {
+        let stats = compute(&records());
+
+        assert_eq!(
+            format_text(&stats[0]),
+            "Mouse 00B7 (0x00B7)\n\
+             \x20 Readings:        24\n\
+             \x20 Hours of use:    3.0 h\n\
+             \x20 Discharge rate:  11.1 %/h\n\
+             \x20 Full cycles:     0.3\n\
+             \x20 Charges:         1 (average 0.5 h)\n\
+             \x20 Runtime 100% → 15% by month:\n\
+             \x20   2025-06     8.5 h  (2.0 h of use)\n\
+             \x20   2025-07     6.4 h  (1.0 h of use)\n"
+        );
+        assert_eq!(
+            format_text(&stats[1]),
+            "Mouse 00B8 (0x00B8)\n\
+             \x20 Readings:        1\n\
+             \x20 Hours of use:    0.0 h\n\
+             \x20 Discharge rate:  n/a\n\
+             \x20 Full cycles:     0.0\n\
+             \x20 Charges:         0 (average n/a)\n"
+        );
+    }
+}
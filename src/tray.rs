@@ -1,209 +1,297 @@
 use std::{
-    collections::{HashMap, HashSet},
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    rc::Rc,
     sync::Arc,
     thread,
-    time::Duration,
 };
 
-use crate::{console::DebugConsole, manager::DeviceManager};
+use crate::{
+    bus::MonitorEvent,
+    console::{self, DebugConsole},
+    http,
+    icon::{render_icon, IconConfig, IconState},
+    ipc::{self, Request, Response},
+    menu::{
+        self, menu_entries, open_settings, rename_device, snooze_text, sorted_devices, tooltip,
+        MenuAction, MenuEntry,
+    },
+    monitor::{BatteryMonitor, MemoryDevice},
+};
 use log::{error, info, trace};
 use parking_lot::Mutex;
 use tao::event_loop::{EventLoopBuilder, EventLoopProxy};
 use tray_icon::{
-    menu::{IsMenuItem, Menu, MenuEvent, MenuItem},
+    menu::{IsMenuItem, Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem, Submenu},
     TrayIcon, TrayIconBuilder,
 };
 
-const BATTERY_UPDATE_INTERVAL: Duration = Duration::from_secs(300); // 5 min
-const DEVICE_FETCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Tray icon size in pixels at 100% display scale.
+const TRAY_ICON_SIZE: f64 = 16.0;
 
-#[derive(Debug)]
-pub struct MemoryDevice {
-    pub name: String,
-    #[allow(unused)]
-    pub pid: u32,
-    pub battery_level: i32,
-    pub old_battery_level: i32,
-    pub is_charging: bool,
-}
-
-impl MemoryDevice {
-    fn new(name: String, pid: u32) -> Self {
-        Self {
-            name,
-            pid,
-            battery_level: -1,
-            old_battery_level: 50,
-            is_charging: false,
-        }
+impl MenuAction {
+    fn item(self, text: &str) -> MenuItem {
+        MenuItem::with_id(MenuId::new(self.id()), text, true, None)
     }
 }
 
 pub struct TrayInner {
-    tray_icon: Arc<Mutex<Option<TrayIcon>>>,
-    menu_items: Arc<Mutex<Vec<MenuItem>>>,
-    debug_console: Arc<DebugConsole>,
+    tray_icon: Rc<RefCell<Option<TrayIcon>>>,
+    debug_console: Rc<DebugConsole>,
 }
 
 impl TrayInner {
-    fn new(debug_console: Arc<DebugConsole>) -> Self {
+    fn new(debug_console: Rc<DebugConsole>) -> Self {
         Self {
-            tray_icon: Arc::new(Mutex::new(None)),
-            menu_items: Arc::new(Mutex::new(Vec::new())),
+            tray_icon: Rc::new(RefCell::new(None)),
             debug_console,
         }
     }
 
-    fn create_menu(&self) -> Menu {
+    fn create_menu(devices: &[&MemoryDevice], console_visible: bool, snooze_text: &str) -> Menu {
         let tray_menu = Menu::new();
-
-        let show_console_item = MenuItem::new("Show Log Window", true, None);
-        let quit_item = MenuItem::new("Exit", true, None);
-
-        let mut menu_items = self.menu_items.lock();
-        menu_items.push(show_console_item);
-        menu_items.push(quit_item);
-
-        let item_refs: Vec<&dyn IsMenuItem> = menu_items
-            .iter()
-            .map(|item| item as &dyn IsMenuItem)
-            .collect();
-
-        tray_menu
-            .append_items(&item_refs)
-            .expect("Failed to append menu items");
+        for entry in menu_entries(devices, Some(console_visible), snooze_text) {
+            let _ = tray_menu.append(Self::menu_item(entry).as_ref());
+        }
         tray_menu
     }
 
+    fn menu_item(entry: MenuEntry) -> Box<dyn IsMenuItem> {
+        match entry {
+            MenuEntry::Item {
+                text,
+                action: Some(action),
+            } => Box::new(action.item(&text)),
+            MenuEntry::Item { text, action: None } => Box::new(MenuItem::new(text, false, None)),
+            MenuEntry::Submenu { text, entries } => {
+                let submenu = Submenu::new(text, true);
+                for entry in entries {
+                    let _ = submenu.append(Self::menu_item(entry).as_ref());
+                }
+                Box::new(submenu)
+            }
+            MenuEntry::Separator => Box::new(PredefinedMenuItem::separator()),
+        }
+    }
+
     fn build_tray(
-        tray_icon: &Arc<Mutex<Option<TrayIcon>>>,
+        tray_icon: &Rc<RefCell<Option<TrayIcon>>>,
         tray_menu: &Menu,
         icon: tray_icon::Icon,
     ) {
+        *tray_icon.borrow_mut() = Self::create_tray(tray_menu, "Service is running", icon);
+    }
+
+    fn create_tray(tray_menu: &Menu, tooltip: &str, icon: tray_icon::Icon) -> Option<TrayIcon> {
         let tray_builder = TrayIconBuilder::new()
             .with_menu(Box::new(tray_menu.clone()))
-            .with_tooltip("Service is running")
+            .with_tooltip(tooltip)
             .with_icon(icon)
             .build();
 
         match tray_builder {
-            Ok(tray) => *tray_icon.lock() = Some(tray),
-            Err(err) => error!("Failed to create tray icon: {}", err),
+            Ok(tray) => Some(tray),
+            Err(err) => {
+                error!("Failed to create tray icon: {}", err);
+                None
+            }
         }
     }
 }
 
+/// The tray icon of a single device in per-device mode.
+struct DeviceIcon {
+    tray_icon: TrayIcon,
+    state: IconState,
+}
+
 pub struct TrayApp {
-    device_manager: Arc<Mutex<DeviceManager>>,
-    devices: Arc<Mutex<HashMap<u32, MemoryDevice>>>,
+    monitor: Arc<BatteryMonitor>,
     tray_inner: TrayInner,
 }
 
 #[derive(Debug)]
 enum TrayEvent {
-    DeviceUpdate(Vec<u32>),
+    Monitor(MonitorEvent),
+    ConfigReloaded,
+    /// Show the log window, which is the closest thing to a main window.
+    Activate,
     MenuEvent(MenuEvent),
 }
 
 impl TrayApp {
-    pub fn new(debug_console: DebugConsole) -> Self {
+    pub fn new(monitor: Arc<BatteryMonitor>, debug_console: DebugConsole) -> Self {
         Self {
-            device_manager: Arc::new(Mutex::new(DeviceManager::new())),
-            devices: Arc::new(Mutex::new(HashMap::new())),
-            tray_inner: TrayInner::new(Arc::new(debug_console)),
+            monitor,
+            tray_inner: TrayInner::new(Rc::new(debug_console)),
         }
     }
 
-    pub fn run(&self) {
-        let icon = Self::create_icon();
+    pub fn run(&self, listener: Option<ipc::Listener>) {
         let event_loop = EventLoopBuilder::with_user_event().build();
-        let tray_menu = self.tray_inner.create_menu();
+        let config = self.monitor.config();
+        let icon_size = config.icon.size.unwrap_or_else(|| {
+            let scale_factor = event_loop
+                .primary_monitor()
+                .map_or(1.0, |monitor| monitor.scale_factor());
+            (TRAY_ICON_SIZE * scale_factor).round() as u32
+        });
+        trace!("Tray icon size: {} px", icon_size);
+        let icon = Self::create_icon(IconState::Disconnected, &config.icon, icon_size);
+        let tray_menu = TrayInner::create_menu(
+            &sorted_devices(&self.monitor.devices()),
+            false,
+            &snooze_text(&config, self.monitor.is_snoozed()),
+        );
 
         let proxy = event_loop.create_proxy();
 
-        self.spawn_device_fetch_thread(proxy.clone());
-        self.spawn_battery_check_thread(proxy.clone());
+        self.spawn_event_thread(proxy.clone());
+        self.monitor.start_polling();
+        if let Some(listener) = listener {
+            self.spawn_ipc_server(listener, proxy.clone());
+        }
+        http::start(&self.monitor);
 
-        self.run_event_loop(event_loop, icon, tray_menu, proxy);
+        self.run_event_loop(event_loop, icon, icon_size, tray_menu, proxy);
     }
 
-    fn create_icon() -> tray_icon::Icon {
-        let icon = include_bytes!("../assets/mouse_white.png");
-        let image = image::load_from_memory(icon)
-            .expect("Failed to open icon")
-            .into_rgba8();
+    fn create_icon(state: IconState, config: &IconConfig, size: u32) -> tray_icon::Icon {
+        let image = render_icon(state, config, size);
         let (width, height) = image.dimensions();
         let rgba = image.into_raw();
 
         tray_icon::Icon::from_rgba(rgba, width, height).expect("Failed to create icon")
     }
 
-    fn spawn_device_fetch_thread(&self, proxy: EventLoopProxy<TrayEvent>) {
-        let devices = Arc::clone(&self.devices);
-        let device_manager = Arc::clone(&self.device_manager);
+    /// Forwards monitor events to the event loop, which only redraws.
+    fn spawn_event_thread(&self, proxy: EventLoopProxy<TrayEvent>) {
+        let events = self.monitor.bus().subscribe();
 
         thread::spawn(move || {
-            let mut last_devices = HashSet::new();
-            loop {
-                let (removed_devices, connected_devices) = {
-                    let mut manager = device_manager.lock();
-                    manager.fetch_devices()
-                };
-
-                let mut devices = devices.lock();
-                for id in removed_devices {
-                    if let Some(device) = devices.remove(&id) {
-                        info!("Device removed: {}", device.name);
-                    }
-                }
-
-                for &id in &connected_devices {
-                    if !devices.contains_key(&id) {
-                        if let Some(name) = device_manager.lock().get_device_name(id) {
-                            devices.insert(id, MemoryDevice::new(name.clone(), id));
-                            info!("New device: {}", name);
-                        } else {
-                            error!("Failed to get device name for id: {}", id);
-                        }
-                    }
-                }
-
-                let current_devices: HashSet<_> = connected_devices.iter().cloned().collect();
-                if current_devices != last_devices {
-                    let _ = proxy.send_event(TrayEvent::DeviceUpdate(connected_devices));
-                    last_devices = current_devices;
+            for event in events {
+                if proxy.send_event(TrayEvent::Monitor(event)).is_err() {
+                    return;
                 }
-
-                thread::sleep(DEVICE_FETCH_INTERVAL);
             }
         });
     }
 
-    fn spawn_battery_check_thread(&self, proxy: EventLoopProxy<TrayEvent>) {
-        let devices = Arc::clone(&self.devices);
-
-        thread::spawn(move || loop {
-            let device_ids: Vec<u32> = devices.lock().keys().cloned().collect();
-            let _ = proxy.send_event(TrayEvent::DeviceUpdate(device_ids));
-            thread::sleep(BATTERY_UPDATE_INTERVAL);
+    fn spawn_ipc_server(&self, listener: ipc::Listener, proxy: EventLoopProxy<TrayEvent>) {
+        let monitor = Arc::clone(&self.monitor);
+        let proxy = Mutex::new(proxy);
+
+        listener.serve(move |request| match request {
+            Request::Status => Response::Status {
+                devices: monitor.status(),
+            },
+            Request::Refresh => {
+                monitor.request_poll();
+                Response::Ok
+            }
+            Request::History { filter } => match monitor.history(&filter) {
+                Ok(records) => Response::History { records },
+                Err(err) => Response::error(err),
+            },
+            Request::ReloadConfig => match Self::reload_config(&monitor, &proxy.lock()) {
+                Ok(()) => Response::Ok,
+                Err(err) => Response::error(err),
+            },
+            Request::Activate => {
+                info!("Another instance was started");
+                let _ = proxy.lock().send_event(TrayEvent::Activate);
+                Response::Ok
+            }
+            Request::Watch => Response::Events(monitor.events().subscribe()),
         });
     }
 
+    /// Reloads the configuration and lets the event loop apply it.
+    fn reload_config(
+        monitor: &BatteryMonitor,
+        proxy: &EventLoopProxy<TrayEvent>,
+    ) -> Result<(), String> {
+        monitor.reload_config()?;
+        let _ = proxy.send_event(TrayEvent::ConfigReloaded);
+        Ok(())
+    }
+
     fn run_event_loop(
         &self,
         event_loop: tao::event_loop::EventLoop<TrayEvent>,
         icon: tray_icon::Icon,
+        icon_size: u32,
         tray_menu: Menu,
         proxy: EventLoopProxy<TrayEvent>,
     ) {
-        let devices = Arc::clone(&self.devices);
-        let device_manager = Arc::clone(&self.device_manager);
-        let tray_icon = Arc::clone(&self.tray_inner.tray_icon);
-        let debug_console = Arc::clone(&self.tray_inner.debug_console);
-        let menu_items = Arc::clone(&self.tray_inner.menu_items);
+        let monitor = Arc::clone(&self.monitor);
+        let tray_icon = Rc::clone(&self.tray_inner.tray_icon);
+        let debug_console = Rc::clone(&self.tray_inner.debug_console);
 
         let menu_channel = MenuEvent::receiver();
 
+        let mut update_tray = {
+            let monitor = Arc::clone(&monitor);
+            let tray_icon = Rc::clone(&tray_icon);
+            let debug_console = Rc::clone(&debug_console);
+            let mut icon_state = Some(IconState::Disconnected);
+            let mut device_icons = HashMap::new();
+
+            // Icons are re-rendered on changes only, unless `rerender` is set
+            move |rerender: bool| {
+                if rerender {
+                    icon_state = None;
+                    device_icons.clear();
+                }
+
+                let config = monitor.config();
+                let devices = monitor.devices();
+                let devices = sorted_devices(&devices);
+                let console_visible = debug_console.is_visible();
+                let snooze_text = snooze_text(&config, monitor.is_snoozed());
+
+                if config.icon.per_device {
+                    Self::update_device_icons(
+                        &mut device_icons,
+                        &devices,
+                        console_visible,
+                        &snooze_text,
+                        &config.icon,
+                        icon_size,
+                    );
+
+                    // The main icon only keeps the app reachable without devices
+                    if let Some(tray_icon) = tray_icon.borrow().as_ref() {
+                        let _ = tray_icon.set_visible(devices.is_empty());
+                    }
+                    if !devices.is_empty() {
+                        return;
+                    }
+                }
+
+                let tray_icon = tray_icon.borrow();
+                let Some(tray_icon) = tray_icon.as_ref() else {
+                    return;
+                };
+
+                let state = menu::icon_state(&devices);
+                if icon_state != Some(state) {
+                    trace!("Tray icon state: {:?}", state);
+                    icon_state = Some(state);
+                    let icon = Self::create_icon(state, &config.icon, icon_size);
+                    if let Err(err) = tray_icon.set_icon(Some(icon)) {
+                        error!("Failed to update tray icon: {}", err);
+                    }
+                }
+
+                let menu = TrayInner::create_menu(&devices, console_visible, &snooze_text);
+                tray_icon.set_menu(Some(Box::new(menu)));
+                if let Err(err) = tray_icon.set_tooltip(Some(tooltip(&devices))) {
+                    error!("Failed to update tooltip: {}", err);
+                }
+            }
+        };
+
         event_loop.run(move |event, _, control_flow| {
             *control_flow = tao::event_loop::ControlFlow::Wait;
 
@@ -211,25 +299,52 @@ impl TrayApp {
                 tao::event::Event::NewEvents(tao::event::StartCause::Init) => {
                     TrayInner::build_tray(&tray_icon, &tray_menu, icon.clone());
                 }
-                tao::event::Event::UserEvent(TrayEvent::DeviceUpdate(device_ids)) => {
-                    Self::update(&devices, &device_manager, &device_ids, &tray_icon);
+                tao::event::Event::UserEvent(TrayEvent::Monitor(MonitorEvent::Stopped)) => {
+                    *control_flow = tao::event_loop::ControlFlow::Exit;
+                }
+                tao::event::Event::UserEvent(TrayEvent::Monitor(event))
+                    if event.changes_state() =>
+                {
+                    update_tray(false);
+                }
+                tao::event::Event::UserEvent(TrayEvent::Activate) => {
+                    debug_console.show();
+                    update_tray(false);
+                }
+                tao::event::Event::UserEvent(TrayEvent::ConfigReloaded) => {
+                    update_tray(true);
                 }
                 tao::event::Event::UserEvent(TrayEvent::MenuEvent(event)) => {
-                    let menu_items = menu_items.lock();
-
-                    if event.id == menu_items[0].id() {
-                        debug_console.toggle_visibility();
-                        let visible = debug_console.is_visible();
-                        menu_items[0].set_text(if visible {
-                            "Hide Log Window"
-                        } else {
-                            "Show Log Window"
-                        });
-                        trace!("{} log window", if visible { "showing" } else { "hiding" });
-                    }
-
-                    if event.id == menu_items[1].id() {
-                        *control_flow = tao::event_loop::ControlFlow::Exit;
+                    match MenuAction::from_id(event.id.as_ref()) {
+                        Some(MenuAction::RefreshAll) => monitor.request_poll(),
+                        Some(MenuAction::ReloadConfig) => {
+                            if let Err(err) = Self::reload_config(&monitor, &proxy) {
+                                error!("Failed to reload config: {}", err);
+                            }
+                        }
+                        Some(MenuAction::ToggleConsole) => {
+                            debug_console.toggle_visibility();
+                            let visible = debug_console.is_visible();
+                            trace!("{} log window", if visible { "showing" } else { "hiding" });
+                            update_tray(false);
+                        }
+                        Some(MenuAction::ToggleSnooze) => {
+                            monitor.toggle_snooze();
+                            update_tray(false);
+                        }
+                        // Exits once the monitor reports it stopped
+                        Some(MenuAction::Exit) => monitor.shutdown(),
+                        Some(MenuAction::Refresh(pid)) => monitor.request_update(pid),
+                        Some(MenuAction::Details(pid)) => {
+                            if let Some(device) = monitor.devices().get(&pid) {
+                                let (title, details) = (device.name.clone(), device.details());
+                                info!("{}", details.replace('\n', ", "));
+                                thread::spawn(move || console::show_message(&title, &details));
+                            }
+                        }
+                        Some(MenuAction::Rename(pid)) => rename_device(&monitor, pid),
+                        Some(MenuAction::Settings) => open_settings(),
+                        None => (),
                     }
                 }
                 _ => (),
@@ -241,57 +356,52 @@ impl TrayApp {
         });
     }
 
-    fn update(
-        devices: &Arc<Mutex<HashMap<u32, MemoryDevice>>>,
-        manager: &Arc<Mutex<DeviceManager>>,
-        device_ids: &[u32],
-        tray_icon: &Arc<Mutex<Option<TrayIcon>>>,
+    /// Creates, updates and removes the icons of per-device mode so there is
+    /// exactly one for every connected device.
+    fn update_device_icons(
+        device_icons: &mut HashMap<u32, DeviceIcon>,
+        devices: &[&MemoryDevice],
+        console_visible: bool,
+        snooze_text: &str,
+        config: &IconConfig,
+        icon_size: u32,
     ) {
-        let mut devices = devices.lock();
-        let manager = manager.lock();
-
-        for &id in device_ids {
-            if let Some(device) = devices.get_mut(&id) {
-                if let (Some(battery_level), Some(is_charging)) = (
-                    manager.get_device_battery_level(id),
-                    manager.is_device_charging(id),
-                ) {
-                    info!("{}  battery level: {}%", device.name, battery_level);
-                    info!("{}  charging status: {}", device.name, is_charging);
-
-                    device.old_battery_level = device.battery_level;
-                    device.battery_level = battery_level;
-                    device.is_charging = is_charging;
-
-                    Self::check_notify(device);
-
-                    if let Some(tray_icon) = tray_icon.lock().as_mut() {
-                        let _ = tray_icon
-                            .set_tooltip(Some(format!("{}: {}%", device.name, battery_level)));
+        device_icons.retain(|pid, _| {
+            let connected = devices.iter().any(|device| device.pid == *pid);
+            if !connected {
+                trace!("Removing tray icon of 0x{:04X}", pid);
+            }
+            connected
+        });
+
+        for &device in devices {
+            let state = device.icon_state();
+            let menu = TrayInner::create_menu(&[device], console_visible, snooze_text);
+            let tooltip = tooltip(&[device]);
+
+            match device_icons.entry(device.pid) {
+                Entry::Occupied(entry) => {
+                    let device_icon = entry.into_mut();
+                    if device_icon.state != state {
+                        device_icon.state = state;
+                        let icon = Self::create_icon(state, config, icon_size);
+                        if let Err(err) = device_icon.tray_icon.set_icon(Some(icon)) {
+                            error!("Failed to update tray icon: {}", err);
+                        }
+                    }
+                    device_icon.tray_icon.set_menu(Some(Box::new(menu)));
+                    if let Err(err) = device_icon.tray_icon.set_tooltip(Some(tooltip)) {
+                        error!("Failed to update tooltip: {}", err);
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    trace!("Adding tray icon of {}", device.name);
+                    let icon = Self::create_icon(state, config, icon_size);
+                    if let Some(tray_icon) = TrayInner::create_tray(&menu, &tooltip, icon) {
+                        entry.insert(DeviceIcon { tray_icon, state });
                     }
                 }
             }
         }
     }
-
-    fn check_notify(device: &MemoryDevice) {
-        if device.battery_level == -1 {
-            return;
-        }
-
-        if !device.is_charging
-            && (device.battery_level <= 5
-                || (device.old_battery_level > 15 && device.battery_level <= 15))
-        {
-            info!("{}: Battery low ({}%)", device.name, device.battery_level);
-        } else if device.old_battery_level <= 99
-            && device.battery_level == 100
-            && device.is_charging
-        {
-            info!(
-                "{}: Battery fully charged ({}%)",
-                device.name, device.battery_level
-            );
-        }
-    }
 }